@@ -0,0 +1,99 @@
+// Throughput of answering fetches for a whole partition, the records going
+// to a local socket that a thread drains:
+//
+//   decode    reading each record and encoding it again, as fetches used to
+//   copy      the raw bytes of the segment files through a user space buffer
+//   sendfile  the raw bytes handed to the kernel to send straight from the files
+//
+// Run with `cargo bench --bench fetch`.
+use std::env;
+use std::fs;
+use std::io::{self, BufWriter, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use latka::partition::{Config, Partition};
+use latka::record::Record;
+use latka::segment::Offset;
+use latka::transfer::{self, FileSlice};
+
+
+const RECORDS: u64 = 200_000;
+const RECORD_BYTES: usize = 1024;
+const SEGMENT_BYTES: u64 = 64 << 20;
+const MAX_BYTES: usize = 1 << 20;
+const ROUNDS: u32 = 5;
+
+
+fn main() -> io::Result<()> {
+    let dir = env::temp_dir().join("latka-bench-fetch");
+    let _ = fs::remove_dir_all(&dir);
+    let config = Config { segment_bytes: SEGMENT_BYTES, ..Config::default() };
+    let mut partition = Partition::with_config(dir.to_string_lossy().into_owned(), 0, config)?;
+    partition.open_active()?;
+    for _ in 0..RECORDS {
+        partition.append(&mut record())?;
+    }
+    partition.sync()?;
+    let bytes = RECORDS * record().encoded_len() as u64;
+    println!("{} records, {} MB, best of {} rounds", RECORDS, bytes >> 20, ROUNDS);
+
+    bench("decode", &mut partition, bytes, |partition, offset, socket| {
+        let records = partition.read(offset, RECORDS, MAX_BYTES)?;
+        let mut writer = BufWriter::new(socket);
+        for record in &records {
+            record.write_to(&mut writer)?;
+        }
+        writer.flush()?;
+        Ok(records.len() as u64)
+    })?;
+    bench("copy", &mut partition, bytes, |partition, offset, mut socket| {
+        let slices = partition.slices(offset, RECORDS, MAX_BYTES)?;
+        transfer::copy_to(&slices, &mut socket)?;
+        Ok(count(&slices))
+    })?;
+    bench("sendfile", &mut partition, bytes, |partition, offset, socket| {
+        let slices = partition.slices(offset, RECORDS, MAX_BYTES)?;
+        transfer::send_to(&slices, socket)?;
+        Ok(count(&slices))
+    })?;
+
+    fs::remove_dir_all(&dir)
+}
+
+// Time sending every record with `fetch`, which answers a fetch from an
+// offset and returns how many records it sent.
+fn bench<F>(name: &str, partition: &mut Partition, bytes: u64, fetch: F) -> io::Result<()>
+where
+    F: Fn(&mut Partition, Offset, &TcpStream) -> io::Result<u64>,
+{
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let socket = TcpStream::connect(listener.local_addr()?)?;
+    let (mut drain, _) = listener.accept()?;
+    let drained = thread::spawn(move || io::copy(&mut drain, &mut io::sink()));
+
+    let mut best = Duration::MAX;
+    for _ in 0..ROUNDS {
+        let start = Instant::now();
+        let mut offset = partition.log_start_offset();
+        while offset < RECORDS {
+            offset += fetch(partition, offset, &socket)?;
+        }
+        best = best.min(start.elapsed());
+    }
+    drop(socket);
+    assert_eq!(drained.join().unwrap()?, bytes * ROUNDS as u64, "{} sent it all", name);
+    let megabytes = bytes as f64 / (1 << 20) as f64;
+    println!("{:>10}: {:>8.1} MB/s", name, megabytes / best.as_secs_f64());
+    Ok(())
+}
+
+fn record() -> Record {
+    Record::new(vec![b'w'; RECORD_BYTES])
+}
+
+// every record of the benchmark is the same size
+fn count(slices: &[FileSlice]) -> u64 {
+    transfer::len(slices) / record().encoded_len() as u64
+}
@@ -0,0 +1,152 @@
+// Assignors split the partitions of the topics a consumer group subscribes
+// to among its members. Every member lists the assignors it knows of and
+// the group coordinator uses the leader's favourite that all of them know.
+use std::collections::{BTreeMap, HashMap};
+
+
+/// Partitions assigned to each member, as (topic, partition).
+pub type Assignment = HashMap<String, Vec<(String, u32)>>;
+
+pub trait Assignor {
+    fn name(&self) -> &'static str;
+
+    /// Assign the partitions of `partitions`, the partition count of each
+    /// topic, to `members`, the topics each member subscribes to.
+    fn assign(&self, members: &BTreeMap<String, Vec<String>>, partitions: &HashMap<String, u32>) -> Assignment;
+}
+
+/// The assignor called `name`, if there is one.
+pub fn assignor(name: &str) -> Option<Box<dyn Assignor>> {
+    match name {
+        "range" => Some(Box::new(RangeAssignor)),
+        "roundrobin" => Some(Box::new(RoundRobinAssignor)),
+        _ => None,
+    }
+}
+
+
+/// Gives each subscriber of a topic a contiguous range of its partitions,
+/// the first members in order getting one more when they don't divide evenly.
+#[derive(Debug, Default)]
+pub struct RangeAssignor;
+
+impl Assignor for RangeAssignor {
+    fn name(&self) -> &'static str {
+        "range"
+    }
+
+    fn assign(&self, members: &BTreeMap<String, Vec<String>>, partitions: &HashMap<String, u32>) -> Assignment {
+        let mut assignment: Assignment = members.keys().map(|member| (member.clone(), vec![])).collect();
+        let mut topics: Vec<&String> = partitions.keys().collect();
+        topics.sort();
+        for topic in topics {
+            let subscribers: Vec<&String> = members.iter()
+                .filter(|(_, topics)| topics.contains(topic))
+                .map(|(member, _)| member)
+                .collect();
+            if subscribers.is_empty() {
+                continue
+            }
+            let count = partitions[topic];
+            let (per_member, extra) = (count / subscribers.len() as u32, count % subscribers.len() as u32);
+            let mut next = 0;
+            for (i, member) in subscribers.into_iter().enumerate() {
+                let len = per_member + if (i as u32) < extra { 1 } else { 0 };
+                let owned = assignment.get_mut(member).unwrap();
+                owned.extend((next..next + len).map(|partition| (topic.clone(), partition)));
+                next += len;
+            }
+        }
+        assignment
+    }
+}
+
+
+/// Deals out the partitions of all topics one by one to the members in
+/// turn, skipping members that don't subscribe to the partition's topic.
+#[derive(Debug, Default)]
+pub struct RoundRobinAssignor;
+
+impl Assignor for RoundRobinAssignor {
+    fn name(&self) -> &'static str {
+        "roundrobin"
+    }
+
+    fn assign(&self, members: &BTreeMap<String, Vec<String>>, partitions: &HashMap<String, u32>) -> Assignment {
+        let mut assignment: Assignment = members.keys().map(|member| (member.clone(), vec![])).collect();
+        let mut topics: Vec<&String> = partitions.keys().collect();
+        topics.sort();
+        let mut turn = members.iter().cycle();
+        for topic in topics {
+            if !members.values().any(|topics| topics.contains(topic)) {
+                continue
+            }
+            for partition in 0..partitions[topic] {
+                let member = loop {
+                    let (member, topics) = turn.next().unwrap();
+                    if topics.contains(topic) {
+                        break member;
+                    }
+                };
+                assignment.get_mut(member).unwrap().push((topic.clone(), partition));
+            }
+        }
+        assignment
+    }
+}
+
+
+#[cfg(test)]
+extern crate speculate;
+
+#[cfg(test)]
+mod tests {
+    use speculate::speculate;
+    use super::*;
+
+    speculate! {
+        fn members(subscriptions: &[(&str, &[&str])]) -> BTreeMap<String, Vec<String>> {
+            subscriptions.iter().map(|(member, topics)| {
+                (member.to_string(), topics.iter().map(|topic| topic.to_string()).collect())
+            }).collect()
+        }
+
+        fn partitions(counts: &[(&str, u32)]) -> HashMap<String, u32> {
+            counts.iter().map(|(topic, count)| (topic.to_string(), *count)).collect()
+        }
+
+        fn owned(assignment: &Assignment, member: &str) -> Vec<(String, u32)> {
+            assignment[member].clone()
+        }
+
+        fn tp(topic: &str, partition: u32) -> (String, u32) {
+            (topic.to_string(), partition)
+        }
+
+        test "range gives the first members the extra partitions" {
+            let members = members(&[("a", &["t"]), ("b", &["t"]), ("c", &["t"])]);
+            let assignment = RangeAssignor.assign(&members, &partitions(&[("t", 5)]));
+            assert_eq!(owned(&assignment, "a"), vec![tp("t", 0), tp("t", 1)]);
+            assert_eq!(owned(&assignment, "b"), vec![tp("t", 2), tp("t", 3)]);
+            assert_eq!(owned(&assignment, "c"), vec![tp("t", 4)]);
+        }
+
+        test "round robin deals partitions across topics" {
+            let members = members(&[("a", &["t", "u"]), ("b", &["t", "u"])]);
+            let assignment = RoundRobinAssignor.assign(&members, &partitions(&[("t", 3), ("u", 1)]));
+            assert_eq!(owned(&assignment, "a"), vec![tp("t", 0), tp("t", 2)]);
+            assert_eq!(owned(&assignment, "b"), vec![tp("t", 1), tp("u", 0)]);
+        }
+
+        test "members only get topics they subscribe to" {
+            let members = members(&[("a", &["t"]), ("b", &["u"]), ("c", &[])]);
+            let counts = partitions(&[("t", 2), ("u", 2), ("v", 1)]);
+            for assignor in &[assignor("range").unwrap(), assignor("roundrobin").unwrap()] {
+                let assignment = assignor.assign(&members, &counts);
+                assert_eq!(owned(&assignment, "a"), vec![tp("t", 0), tp("t", 1)], "{}", assignor.name());
+                assert_eq!(owned(&assignment, "b"), vec![tp("u", 0), tp("u", 1)], "{}", assignor.name());
+                assert!(owned(&assignment, "c").is_empty(), "{}", assignor.name());
+            }
+        }
+    }
+}
@@ -7,227 +7,802 @@
 #![allow(unused_variables)]
 //#![feature(bufreader_buffer)]
 use std::{io, fs, thread, env};
+use std::collections::{HashMap, HashSet};
 use std::fs::{OpenOptions, File};
 use std::io::{Seek, SeekFrom, BufReader, BufWriter,Write, Read, BufRead, Error};
-use std::io::ErrorKind::ConnectionReset;
+use std::io::ErrorKind;
+use std::io::ErrorKind::{ConnectionReset, UnexpectedEof};
 use std::net::{TcpListener, TcpStream};
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
 
 use bufstream::BufStream;
-use byteorder::{ReadBytesExt, NetworkEndian};
+use byteorder::{ReadBytesExt, WriteBytesExt, NetworkEndian};
 use getopts::Options;
 
+use latka::compression::Compression;
+use latka::group::Coordinator;
+use latka::offsets::{OffsetStore, OFFSETS_TOPIC};
+use latka::protocol::{self, Acks, ApiKey, ApiVersion, ErrorCode, IsolationLevel, Request, Response, ResponseError};
+use latka::record::Record;
+use latka::partition::{Config, CleanupPolicy};
+use latka::transfer::{self, FileSlice};
+
 static USAGE: &str = "
 broker message queue
 
 Usage:
   broker
-  broker [--topic=dirname] [--port=number] [--create]
-  broker [-t dirname] [-p number] [-c]
+  broker [--data-dir=dirname] [--topic=name]... [--port=number]
+         [--create] [--partitions=number]
+         [--flush-messages=number] [--flush-ms=number]
+         [--segment-bytes=number] [--segment-ms=number]
+         [--retention-bytes=number] [--retention-ms=number]
+         [--cleanup-policy=delete|compact] [--delete-retention-ms=number]
+         [--compression-type=producer|uncompressed|gzip|snappy|lz4|zstd]
+  broker [-d dirname] [-t name]... [-p number] [-c] [-n number]
 
 Options:
-  -h --help     Show this screen.
-  -t --topic    Specify which topic [default topic]
-  -p --port     Serve on port [default 7070]
-  -c --create   Create topic if it doesn't exist
-";
+  -h --help          Show this screen.
+  -d --data-dir      Serve every topic found in this directory [default .]
+  -t --topic         A topic to create or remove, may be repeated
+  -p --port          Serve on port [default 7070]
+  -c --create        Create the topics if they don't exist
+  -r --remove        Remove and recreate the topics
+  -n --partitions    Partitions of the topics, adding any missing [default 1]
+  --flush-messages   Flush to disk after this many messages [default 1]
+  --flush-ms         Also flush pending messages this often (milliseconds)
+  --segment-bytes    Roll to a new segment past this size [default 1073741824]
+  --segment-ms       Roll to a new segment once the active one is this old
+  --retention-bytes  Delete the oldest segments while the topic is bigger
+  --retention-ms     Delete segments whose newest message is this old
+  --retention-check-ms
+                     How often retention is enforced [default 300000]
+  --cleanup-policy   delete: old segments go by retention [default]
+                     compact: keep only the newest message of each key
+  --delete-retention-ms
+                     How long compaction keeps tombstones [default 86400000]
+  --cleaner-backoff-ms
+                     How often compaction runs [default 15000]
+  --compression-type Store each batch compressed with this codec,
+                     producer keeps the codec it was sent with [default]
 
+Messages are only exposed to consumers once they are flushed.
+A directory of the data directory is a topic once it holds a partition.
+Consumer groups commit their offsets to the internal, compacted topic
+__consumer_offsets, which consumers can't read.
+";
 
-const SEGMENT_SIZE: u64 = 32;
-const CONSUMER_MESSAGE_PREFIX: u8 = 42;
-const PRODUCER_MESSAGE_PREFIX: u8 = 78;
 
 type Offset = u64;
 
+// where the broker keeps the next producer id, in the data directory
+const PRODUCER_ID_FILE: &str = ".producer_id";
+// where the transaction coordinator keeps its transactional ids
+const TRANSACTIONS_FILE: &str = ".transactions";
+// how often the group coordinator looks for members that went silent
+const SESSION_CHECK_MS: u64 = 1000;
+
 
 // segment_offset: LinkedList<Offset>,
 // let mut offsets: LinkedList<Offset> = LinkedList::new();
 // offsets.extend(sorted_segments);
 
 struct Partition {
-    largest_offset: Mutex<Offset>,
-    latest_segment: Mutex<Offset>,
-    segments_count: Mutex<usize>,
-    topic: String,
+    log: Mutex<latka::partition::Partition>,
+    // offset after the last record synced to disk, consumers read up to here
+    high_watermark: Mutex<Offset>,
+    // notified whenever the high watermark moves, for fetches waiting on records
+    flushed: Condvar,
+    unflushed_messages: Mutex<u64>,
+    flush_messages: u64,
+    topic: String, // directory of the topic
     partition: u32,
 }
 
 impl Partition {
-    fn new(topic: String, part: u32) -> io::Result<Partition> {
-        let sorted_segments = crawl_sorted_segments(&topic)?;
-        let (largest_base_offset, count) = match sorted_segments.last() {
-            None => (0, sorted_segments.len()),
-            Some(seg) => (*seg, sorted_segments.len()),
-        };
-        let last_segment_name = format!("{}/{}/{:0>20}.log", topic, part, largest_base_offset);
-        let count = if count == 0 {
-            OpenOptions::new().write(true).create_new(true).open(&last_segment_name)?;
-            1
-        } else {
-            count
-        };
-        let size_of_last_file: Offset = fs::metadata(last_segment_name)?.len();
+    fn new(topic: String, part: u32, flush_messages: u64, config: Config) -> io::Result<Partition> {
+        let mut log = latka::partition::Partition::with_config(topic.clone(), part, config)?;
+        // a crash may have left a torn record at the end of the active segment
+        if let Some(truncation) = log.open_active()? {
+            println!(
+                "RECOVERY: dropped {} bytes at position {} of the active segment of {}: {}",
+                truncation.bytes, truncation.position, log.path(), truncation.reason
+            );
+        }
+        log.sync()?;
         Ok(Partition {
             partition: part,
-            topic: topic,
-            segments_count: Mutex::new(count),
-            largest_offset: Mutex::new(largest_base_offset + size_of_last_file),
-            latest_segment: Mutex::new(largest_base_offset),
+            topic,
+            high_watermark: Mutex::new(log.next_offset()),
+            flushed: Condvar::new(),
+            unflushed_messages: Mutex::new(0),
+            flush_messages,
+            log: Mutex::new(log),
         })
     }
 
-    fn log_filename(&self, base_offset: Offset) -> String {
-        format!("{}/{}/{:0>20}.log", self.topic, self.partition, base_offset)
+    // Sync the active segment and expose everything in it to consumers.
+    // Callers hold the log lock.
+    fn flush(&self, log: &mut latka::partition::Partition) -> io::Result<()> {
+        log.sync()?;
+        *self.unflushed_messages.lock().unwrap() = 0;
+        *self.high_watermark.lock().unwrap() = log.next_offset();
+        self.flushed.notify_all();
+        Ok(())
     }
 
-    fn open_consumer_segment_at_offset(&self, base_offset: Offset) -> io::Result<File> {
-        let log = OpenOptions::new().
-            write(false).
-            read(true).
-            open(self.log_filename(base_offset))?;
-
-        Ok(log)
+    // Wait until the high watermark moves past `seen`, returning false
+    // once `deadline` passed without that happening.
+    fn wait_for_flush(&self, seen: Offset, deadline: Instant) -> bool {
+        let mut high_watermark = self.high_watermark.lock().unwrap();
+        while *high_watermark == seen {
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            high_watermark = self.flushed.wait_timeout(high_watermark, deadline - now).unwrap().0;
+        }
+        true
     }
+}
 
-    fn open_latest_segment_for_appending(&self) -> io::Result<(File, Offset)>{
-        let base_offset: Offset = {
-            let n = self.latest_segment.lock().unwrap();
-            *n
-        };
-        let log = OpenOptions::new().
-            create(true).
-            append(true).
-            open(self.log_filename(base_offset))?;
 
-        Ok((log, base_offset))
+struct Topic {
+    name: String,
+    partitions: Vec<Arc<Partition>>,
+}
+
+impl Topic {
+    // Open every partition directory of the topic, creating the
+    // missing ones up to `partition_count`.
+    fn open(data_dir: &str, name: String, partition_count: u32, flush_messages: u64, config: &Config) -> io::Result<Topic> {
+        let path = format!("{}/{}", data_dir, name);
+        let existing = partition_dirs(&path)?.map(|part| part + 1).max().unwrap_or(0);
+        let mut partitions = vec![];
+        for part in 0..existing.max(partition_count) {
+            partitions.push(Arc::new(
+                Partition::new(path.clone(), part, flush_messages, config.clone())?
+            ));
+        }
+        Ok(Topic { name, partitions })
     }
 
+    fn partition(&self, part: u32) -> io::Result<&Arc<Partition>> {
+        match self.partitions.get(part as usize) {
+            Some(partition) => Ok(partition),
+            None => Err(Error::new(
+                ErrorKind::NotFound,
+                format!("topic {} has no partition {}", self.name, part)
+            )),
+        }
+    }
+}
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum TransactionStatus {
+    // no transaction, or one that only wrote records so far
+    Ongoing,
+    // decided, its markers are being written
+    PrepareCommit,
+    PrepareAbort,
 }
 
+impl TransactionStatus {
+    fn as_str(self) -> &'static str {
+        match self {
+            TransactionStatus::Ongoing => "ongoing",
+            TransactionStatus::PrepareCommit => "prepare-commit",
+            TransactionStatus::PrepareAbort => "prepare-abort",
+        }
+    }
 
-fn crawl_sorted_segments(topic: &String) -> io::Result<Vec<Offset>> {
-    let mut segments: Vec<Offset> = fs::read_dir(topic)?.map(
-        |entry| {
-            let path = entry.unwrap().path();
-            let stem = path.as_path().file_stem().unwrap();
-            let str_stem = stem.to_str().unwrap();
-            str_stem.parse::<Offset>().unwrap()
+    fn parse(s: &str) -> Option<TransactionStatus> {
+        match s {
+            "ongoing" => Some(TransactionStatus::Ongoing),
+            "prepare-commit" => Some(TransactionStatus::PrepareCommit),
+            "prepare-abort" => Some(TransactionStatus::PrepareAbort),
+            _ => None,
         }
-    ).collect();
-    segments.sort_unstable();
-    Ok(segments)
+    }
 }
 
+// What the coordinator knows of a transactional id.
+struct Transaction {
+    producer_id: i64,
+    producer_epoch: i16,
+    status: TransactionStatus,
+    // partitions written to since the last marker, (topic, partition)
+    partitions: HashSet<(String, u32)>,
+}
 
+struct Broker {
+    data_dir: String,
+    topics: HashMap<String, Arc<Topic>>,
+    // the next id handed to an idempotent producer
+    next_producer_id: Mutex<i64>,
+    // the transaction coordinator's state, by transactional id
+    transactions: Mutex<HashMap<String, Transaction>>,
+    groups: Coordinator,
+    offsets: OffsetStore,
+}
+
+impl Broker {
+    // Pick up where the broker in `data_dir` left off, finishing the
+    // transactions it left open.
+    fn open(data_dir: String, topics: HashMap<String, Arc<Topic>>) -> io::Result<Broker> {
+        let next_producer_id = match fs::read_to_string(format!("{}/{}", data_dir, PRODUCER_ID_FILE)) {
+            Ok(s) => s.trim().parse().map_err(|_| Error::new(ErrorKind::InvalidData, "bad next producer id"))?,
+            Err(ref e) if e.kind() == ErrorKind::NotFound => 0,
+            Err(e) => return Err(e),
+        };
+        let transactions = load_transactions(&data_dir)?;
+        let partition_counts = topics.iter().map(|(name, topic)| (name.clone(), topic.partitions.len() as u32)).collect();
+        let broker = Broker {
+            topics,
+            next_producer_id: Mutex::new(next_producer_id),
+            transactions: Mutex::new(transactions),
+            groups: Coordinator::new(partition_counts),
+            offsets: OffsetStore::open(&data_dir)?,
+            data_dir,
+        };
+        broker.recover_transactions()?;
+        Ok(broker)
+    }
+
+    fn topic(&self, name: &str) -> io::Result<&Arc<Topic>> {
+        self.topics.get(name).ok_or_else(
+            || Error::new(ErrorKind::NotFound, format!("no topic {}", name))
+        )
+    }
+
+    fn partition(&self, name: &str, part: u32) -> io::Result<&Arc<Partition>> {
+        self.topic(name)?.partition(part)
+    }
+
+    // Fetched records are left in the log files, to be sent after the
+    // response from there.
+    fn handle(&self, request: Request) -> io::Result<(Response, Vec<FileSlice>)> {
+        let response = match request {
+            Request::Produce { topic, partition, acks, records } => {
+                let partition = self.partition(&topic, partition)?;
+                if !records.iter().any(|record| record.is_transactional()) {
+                    produce(partition, &mut partition.log.lock().unwrap(), acks, records)
+                } else {
+                    // the log is locked before the transaction is let go, so
+                    // its markers can't be written ahead of the records, and
+                    // other transactions don't wait for the append
+                    let transactions = self.transactions.lock().unwrap();
+                    check_in_transaction(&transactions, &topic, partition.partition, &records[0])?;
+                    let mut log = partition.log.lock().unwrap();
+                    drop(transactions);
+                    produce(partition, &mut log, acks, records)
+                }
+            },
+            Request::Fetch { topic, partition, offset, max_bytes, isolation_level, max_wait_ms, min_bytes } => {
+                let deadline = Instant::now() + Duration::from_millis(max_wait_ms as u64);
+                return fetch(self.partition(&topic, partition)?, offset, max_bytes, isolation_level, min_bytes, deadline);
+            },
+            Request::ListOffsets { topic, partition, timestamp } => {
+                list_offsets(self.partition(&topic, partition)?, timestamp)
+            },
+            Request::Metadata { topic } => Ok(Response::Metadata {
+                partitions: self.topic(&topic)?.partitions.len() as u32,
+            }),
+            Request::JoinGroup { group_id, member_id, session_timeout_ms, topics, assignors } => {
+                let session_timeout = Duration::from_millis(session_timeout_ms as u64);
+                let joined = self.groups.join(&group_id, &member_id, session_timeout, topics, assignors)?;
+                println!(
+                    "GROUP: {} joined {} in generation {} with {} members",
+                    joined.member_id, group_id, joined.generation_id, joined.members.len()
+                );
+                Ok(Response::JoinGroup {
+                    generation_id: joined.generation_id,
+                    member_id: joined.member_id,
+                    leader_id: joined.leader_id,
+                    assignor: joined.assignor,
+                    members: joined.members,
+                })
+            },
+            Request::SyncGroup { group_id, generation_id, member_id } => Ok(Response::SyncGroup {
+                partitions: self.groups.sync(&group_id, generation_id, &member_id)?,
+            }),
+            Request::Heartbeat { group_id, generation_id, member_id } => {
+                self.groups.heartbeat(&group_id, generation_id, &member_id)?;
+                Ok(Response::Heartbeat)
+            },
+            Request::LeaveGroup { group_id, member_id } => {
+                self.groups.leave(&group_id, &member_id)?;
+                println!("GROUP: {} left {}", member_id, group_id);
+                Ok(Response::LeaveGroup)
+            },
+            Request::OffsetCommit { group_id, generation_id, member_id, offsets } => {
+                // a commit from outside the group names no member
+                if generation_id >= 0 || !member_id.is_empty() {
+                    self.groups.check_commit(&group_id, generation_id, &member_id)?;
+                }
+                for (topic, partition, _) in &offsets {
+                    self.partition(topic, *partition)?;
+                }
+                self.offsets.commit(&group_id, &offsets)?;
+                Ok(Response::OffsetCommit)
+            },
+            Request::OffsetFetch { group_id, partitions } => Ok(Response::OffsetFetch {
+                offsets: partitions.into_iter().map(|(topic, partition)| {
+                    let offset = self.offsets.fetch(&group_id, &topic, partition);
+                    (topic, partition, offset)
+                }).collect(),
+            }),
+            Request::ApiVersions => Ok(Response::ApiVersions { versions: ApiVersion::supported() }),
+            Request::InitProducerId { transactional_id: None } => Ok(Response::InitProducerId {
+                producer_id: self.new_producer_id()?,
+                producer_epoch: 0,
+            }),
+            Request::InitProducerId { transactional_id: Some(transactional_id) } => {
+                self.init_transactional_producer(transactional_id)
+            },
+            Request::AddPartitionsToTxn { transactional_id, producer_id, producer_epoch, partitions } => {
+                let mut transactions = self.transactions.lock().unwrap();
+                let transaction = current_transaction(
+                    &mut transactions, &transactional_id, producer_id, producer_epoch
+                )?;
+                for (topic, partition) in &partitions {
+                    self.partition(topic, *partition)?;
+                }
+                transaction.partitions.extend(partitions);
+                Ok(Response::AddPartitionsToTxn)
+            },
+            Request::EndTxn { transactional_id, producer_id, producer_epoch, commit } => {
+                let mut transactions = self.transactions.lock().unwrap();
+                current_transaction(&mut transactions, &transactional_id, producer_id, producer_epoch)?;
+                self.end_transaction(&mut transactions, &transactional_id, commit)?;
+                Ok(Response::EndTxn)
+            },
+        };
+        response.map(|response| (response, vec![]))
+    }
 
-fn handle_producer(stream: TcpStream, partition: Arc<Partition>) -> Result<(), Error> {
-    let mut reader = BufReader::new(stream);
+    fn new_producer_id(&self) -> io::Result<i64> {
+        let mut next_producer_id = self.next_producer_id.lock().unwrap();
+        let producer_id = *next_producer_id;
+        // synced before it is handed out so a restarted broker never reuses it
+        let mut file = File::create(format!("{}/{}", self.data_dir, PRODUCER_ID_FILE))?;
+        write!(file, "{}", producer_id + 1)?;
+        file.sync_all()?;
+        *next_producer_id += 1;
+        Ok(producer_id)
+    }
 
-    'outer: loop {
-        let (segment_file, curr_seg_base_offset) = partition.open_latest_segment_for_appending()?;
+    // A transactional id keeps its producer id, every new producer using it
+    // gets the next epoch, which fences off the previous one, and whatever
+    // transaction the previous one left open is aborted.
+    fn init_transactional_producer(&self, transactional_id: String) -> io::Result<Response> {
+        let mut transactions = self.transactions.lock().unwrap();
+        if transactions.contains_key(&transactional_id) {
+            let commit = transactions[&transactional_id].status == TransactionStatus::PrepareCommit;
+            self.end_transaction(&mut transactions, &transactional_id, commit)?;
+            self.fence(transactions.get_mut(&transactional_id).unwrap())?;
+        } else {
+            transactions.insert(transactional_id.clone(), Transaction {
+                producer_id: self.new_producer_id()?,
+                producer_epoch: 0,
+                status: TransactionStatus::Ongoing,
+                partitions: HashSet::new(),
+            });
+        }
+        self.save_transactions(&transactions)?;
+        let transaction = &transactions[&transactional_id];
+        Ok(Response::InitProducerId {
+            producer_id: transaction.producer_id,
+            producer_epoch: transaction.producer_epoch,
+        })
+    }
 
-        let mut segment = BufWriter::new(segment_file);
+    // Move the transactional id on to the next epoch, which the producer
+    // using it so far can't write or end transactions with.
+    fn fence(&self, transaction: &mut Transaction) -> io::Result<()> {
+        if transaction.producer_epoch == i16::MAX {
+            transaction.producer_id = self.new_producer_id()?;
+            transaction.producer_epoch = 0;
+        } else {
+            transaction.producer_epoch += 1;
+        }
+        Ok(())
+    }
 
+    // The decision is saved before any marker is written, so a broker
+    // that crashes halfway through finishes the same way on restart.
+    fn end_transaction(
+        &self, transactions: &mut HashMap<String, Transaction>, transactional_id: &str, commit: bool
+    ) -> io::Result<()> {
+        let transaction = transactions.get_mut(transactional_id).unwrap();
+        let status = if commit { TransactionStatus::PrepareCommit } else { TransactionStatus::PrepareAbort };
+        if transaction.status != TransactionStatus::Ongoing && transaction.status != status {
+            return Err(ResponseError::new(
+                ErrorCode::InvalidTxnState,
+                format!("transaction of {} is already in {}", transactional_id, transaction.status.as_str())
+            ).into());
+        }
+        transaction.status = status;
+        let (producer_id, producer_epoch) = (transaction.producer_id, transaction.producer_epoch);
+        let partitions: Vec<_> = transaction.partitions.iter().cloned().collect();
+        self.save_transactions(transactions)?;
 
-        let mut buffer = String::new();
-        'inner: loop {
-            let n = reader.read_line(&mut buffer)?;
-            if n == 0 {
-                break 'outer;
+        for (topic, partition) in partitions {
+            let partition = self.partition(&topic, partition)?;
+            let mut log = partition.log.lock().unwrap();
+            // a retried end finds some markers already written
+            if log.ongoing_transactions().iter().any(|&(id, _)| id == producer_id) {
+                log.end_transaction(producer_id, producer_epoch, commit)?;
+                partition.flush(&mut log)?;
             }
-            write!(segment, "{}", buffer)?; // does write error if not all bytes are written?
-            buffer.clear();
-
-            // update segment if it is "filled"
-            let mut off = partition.largest_offset.lock().unwrap();
-            *off += n as u64;
-            let mut last_seg = partition.latest_segment.lock().unwrap();
-            if *last_seg > curr_seg_base_offset {
-                // Data consistency guarantees hold as long as you are producing
-                // to one partition and consuming from one partition.
-                // All guarantees are off if you are reading from the same
-                // partition using two consumers or writing to the same partition
-                // using two producers~ https://sookocheff.com/post/kafka/kafka-in-a-nutshell/
-                continue 'outer
+        }
+
+        let transaction = transactions.get_mut(transactional_id).unwrap();
+        transaction.status = TransactionStatus::Ongoing;
+        transaction.partitions.clear();
+        self.save_transactions(transactions)
+    }
+
+    fn save_transactions(&self, transactions: &HashMap<String, Transaction>) -> io::Result<()> {
+        let path = format!("{}/{}", self.data_dir, TRANSACTIONS_FILE);
+        let mut file = BufWriter::new(File::create(format!("{}.tmp", path))?);
+        for (transactional_id, transaction) in transactions {
+            writeln!(
+                file, "{} {} {} {}", transaction.producer_id, transaction.producer_epoch,
+                transaction.status.as_str(), transactional_id
+            )?;
+        }
+        file.into_inner()?.sync_all()?;
+        fs::rename(format!("{}.tmp", path), path)
+    }
+
+    // Finish the transactions a crash interrupted: the ones the coordinator
+    // decided on go its way, every other open one is aborted. The producer
+    // of an aborted one may still be around and not know, so it is fenced
+    // off rather than left to commit a transaction that's gone.
+    fn recover_transactions(&self) -> io::Result<()> {
+        let mut transactions = self.transactions.lock().unwrap();
+        let decided: HashMap<i64, TransactionStatus> = transactions.values().map(|transaction| (
+            transaction.producer_id, transaction.status
+        )).collect();
+        let mut fenced = HashSet::new();
+        for partition in self.topics.values().flat_map(|topic| &topic.partitions) {
+            let mut log = partition.log.lock().unwrap();
+            for (producer_id, producer_epoch) in log.ongoing_transactions() {
+                let status = decided.get(&producer_id).copied().unwrap_or(TransactionStatus::Ongoing);
+                let commit = status == TransactionStatus::PrepareCommit;
+                if status == TransactionStatus::Ongoing {
+                    fenced.insert(producer_id);
+                }
+                log.end_transaction(producer_id, producer_epoch, commit)?;
+                println!(
+                    "RECOVERY: {} the transaction of producer {} in {}",
+                    if commit { "committed" } else { "aborted" }, producer_id, log.path()
+                );
             }
-            let mut segment_count = partition.segments_count.lock().unwrap();
-            if (*segment_count as u64 * SEGMENT_SIZE) < *off {
-                *segment_count += 1;
-                *last_seg = *off;
-                continue 'outer;
+            partition.flush(&mut log)?;
+        }
+        for transaction in transactions.values_mut() {
+            if fenced.contains(&transaction.producer_id) {
+                self.fence(transaction)?;
             }
+            transaction.status = TransactionStatus::Ongoing;
         }
+        self.save_transactions(&transactions)
+    }
+}
+
+// The transaction of `transactional_id`, if `producer_id` at
+// `producer_epoch` is the producer currently using it.
+fn current_transaction<'a>(
+    transactions: &'a mut HashMap<String, Transaction>, transactional_id: &str,
+    producer_id: i64, producer_epoch: i16
+) -> io::Result<&'a mut Transaction> {
+    let transaction = match transactions.get_mut(transactional_id) {
+        Some(transaction) if transaction.producer_id == producer_id => transaction,
+        _ => return Err(ResponseError::new(
+            ErrorCode::InvalidProducerIdMapping,
+            format!("producer {} doesn't own transactional id {}", producer_id, transactional_id)
+        ).into()),
+    };
+    if producer_epoch != transaction.producer_epoch {
+        return Err(ResponseError::new(
+            ErrorCode::InvalidProducerEpoch,
+            format!("producer {} epoch {} is not the current {}", producer_id, producer_epoch, transaction.producer_epoch)
+        ).into());
+    }
+    Ok(transaction)
+}
+
+// Transactional records may only go to partitions added to the
+// transaction of their producer.
+fn check_in_transaction(
+    transactions: &HashMap<String, Transaction>, topic: &str, partition: u32, record: &Record
+) -> io::Result<()> {
+    let transaction = transactions.values().find(|t| t.producer_id == record.producer_id).ok_or_else(|| {
+        Error::from(ResponseError::new(
+            ErrorCode::InvalidProducerIdMapping,
+            format!("producer {} has no transactional id", record.producer_id)
+        ))
+    })?;
+    if record.producer_epoch != transaction.producer_epoch {
+        return Err(ResponseError::new(
+            ErrorCode::InvalidProducerEpoch,
+            format!("producer {} epoch {} is not the current {}", record.producer_id, record.producer_epoch, transaction.producer_epoch)
+        ).into());
+    }
+    if !transaction.partitions.contains(&(topic.to_string(), partition)) {
+        return Err(ResponseError::new(
+            ErrorCode::InvalidTxnState,
+            format!("{}-{} was not added to the transaction of producer {}", topic, partition, record.producer_id)
+        ).into());
     }
     Ok(())
 }
 
-fn handle_consumer(tcp_stream: TcpStream, partition: Arc<Partition>) ->  Result<Offset, Error> {
+fn load_transactions(data_dir: &str) -> io::Result<HashMap<String, Transaction>> {
+    let contents = match fs::read_to_string(format!("{}/{}", data_dir, TRANSACTIONS_FILE)) {
+        Ok(contents) => contents,
+        Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(HashMap::new()),
+        Err(e) => return Err(e),
+    };
+    let mut transactions = HashMap::new();
+    for line in contents.lines() {
+        let mut fields = line.splitn(4, ' ');
+        let mut field = || fields.next().ok_or_else(
+            || Error::new(ErrorKind::InvalidData, format!("truncated transaction {:?}", line))
+        );
+        let producer_id = field()?.parse().map_err(|_| Error::new(ErrorKind::InvalidData, "bad producer id"))?;
+        let producer_epoch = field()?.parse().map_err(|_| Error::new(ErrorKind::InvalidData, "bad epoch"))?;
+        let status = TransactionStatus::parse(field()?).ok_or_else(
+            || Error::new(ErrorKind::InvalidData, "bad transaction status")
+        )?;
+        transactions.insert(field()?.to_string(), Transaction {
+            producer_id, producer_epoch, status, partitions: HashSet::new(),
+        });
+    }
+    Ok(transactions)
+}
+
+fn partition_dirs(path: &str) -> io::Result<impl Iterator<Item = u32>> {
+    Ok(fs::read_dir(path)?.filter_map(|entry| {
+        entry.ok()?.file_name().to_str()?.parse::<u32>().ok()
+    }))
+}
+
+// Topics are the directories of the data directory with at least one
+// partition directory, anything else living there is left alone.
+fn discover_topics(data_dir: &str) -> io::Result<Vec<String>> {
+    let mut topics = vec![];
+    for entry in fs::read_dir(data_dir)? {
+        let entry = entry?;
+        if !entry.file_type()?.is_dir() {
+            continue
+        }
+        let name = match entry.file_name().into_string() {
+            Ok(name) if name != OFFSETS_TOPIC => name,
+            _ => continue,
+        };
+        if partition_dirs(&format!("{}/{}", data_dir, name))?.next().is_some() {
+            topics.push(name);
+        }
+    }
+    Ok(topics)
+}
+
+fn valid_topic_name(name: &str) -> bool {
+    !name.is_empty() && name != "." && name != ".." && !name.contains('/') && name != OFFSETS_TOPIC
+}
+
+// Answer the requests of one client until it hangs up.
+fn handle_connection(tcp_stream: TcpStream, broker: Arc<Broker>) -> io::Result<()> {
+    // responses can go out in several writes, records sent after the rest
+    tcp_stream.set_nodelay(true)?;
     let mut stream = BufStream::new(tcp_stream);
-    let mut offset: Offset = stream.read_u64::<NetworkEndian>()?;
-    println!("Feeding Consumer at Offset: {:?}", offset);
-    'infinite: loop{
-        // flush remaining messages before sending heartbeat
-        stream.flush()?;
-        // keep alive NULLBYTE
-        // This heartbeat message informs the broker
-        // when the connection is dropped if the consumer
-        // is waiting for more messages.
-        writeln!(stream, "\0")?;
-
-        let segment_offsets = crawl_sorted_segments(&partition.topic)?;
-        let mut peekable_segments = segment_offsets.iter().peekable();
-
-        'outer: loop {
-            let seg_base_offset = match peekable_segments.next() {
-                Some(o) => *o,
-                None => break 'outer,
-            };
-            if offset < seg_base_offset {break 'outer} // peekable segments are sorted small->big
+    while let Some((header, request)) = protocol::read_request(&mut stream)? {
+        let respond = match &request {
+            Ok(request) => request.expects_response(),
+            // the client doesn't read an answer to a produce with acks 0,
+            // and one that doesn't decode may have been one, so there's no
+            // telling what the client expects next
+            Err(e) if header.api_key == ApiKey::Produce as i16 => {
+                println!(
+                    "ERROR: undecodable produce from {:?}, hanging up: {}",
+                    header.client_id.as_deref().unwrap_or(""), e
+                );
+                return Ok(());
+            },
+            Err(_) => true,
+        };
+        let response = request.and_then(|request| broker.handle(request)).map_err(|e| {
+            println!(
+                "ERROR: api key {} from {:?}: {}",
+                header.api_key, header.client_id.as_deref().unwrap_or(""), e
+            );
+            ResponseError::for_error(&e)
+        });
+        if !respond {
+            continue
+        }
+        match response {
+            Ok((response, ref records)) if !records.is_empty() => {
+                protocol::write_fetch_response_header(
+                    &mut stream, header.api_version, header.correlation_id, &response, transfer::len(records)
+                )?;
+                stream.flush()?;
+                transfer::send_to(records, stream.get_ref())?;
+            },
+            response => {
+                let response = response.map(|(response, _)| response);
+                protocol::write_response(&mut stream, header.api_version, header.correlation_id, &response)?;
+                stream.flush()?;
+            },
+        }
+    }
+    Ok(())
+}
 
-            if let Some(n) = peekable_segments.peek() {
-                if offset >= **n {
-                    continue 'outer  // already consumed this segment
-                }
-            }
+// Callers hold the log lock.
+fn produce(partition: &Partition, log: &mut latka::partition::Partition, acks: Acks, records: Vec<Record>) -> io::Result<Response> {
+    // the broker, not the producer, decides which offset a record gets
+    let next_offset = log.next_offset();
+    let base_offset = log.append_batch(records)?;
+    // nothing is appended for a batch a producer resent
+    let appended = log.next_offset() - next_offset;
 
-            let mut reader: BufReader<File> = {
-                let file = partition.open_consumer_segment_at_offset(seg_base_offset)?;
-                let mut reader = BufReader::new(file);
-                let relative_offset = offset - seg_base_offset;
-                let _ = reader.seek(SeekFrom::Start(relative_offset))?;
-                reader
-            };
+    let unflushed = {
+        let mut n = partition.unflushed_messages.lock().unwrap();
+        *n += appended;
+        *n
+    };
+    if appended > 0 && unflushed >= partition.flush_messages {
+        partition.flush(log)?;
+    }
+    if acks == Acks::All && *partition.unflushed_messages.lock().unwrap() > 0 {
+        partition.flush(log)?;
+    }
+    Ok(Response::Produce { base_offset: Some(base_offset) })
+}
 
-            // NOTE: the BufStream is flushed every segment
-            stream.flush()?;
-            let mut buffer = String::new();
-            'inner: loop {
-                let n = reader.read_line(&mut buffer)?;
-                if n == 0 {
-                    continue 'outer;
-                }
-                // TODO: use syscall `sendfile` to copy directly from file to socket
-                match write!(stream, "{}", buffer){
-                    Ok(_) => {;},
-                    Err(_) => break 'infinite,
-                };
-                buffer.clear();
-                offset += n as Offset;
-            }
+// Consumers only ever see flushed records, so fetches stop at the high
+// watermark, and read committed ones also stop at the first record of an
+// undecided transaction.
+//
+// A fetch finding less than `min_bytes` waits for more to be flushed, up to
+// `deadline`, so an idle consumer costs nothing until there is news.
+//
+// The records are never decoded: the response goes out without them and
+// they follow straight from the segment files.
+fn fetch(
+    partition: &Partition, offset: Offset, max_bytes: u32, isolation_level: IsolationLevel,
+    min_bytes: u32, deadline: Instant
+) -> io::Result<(Response, Vec<FileSlice>)> {
+    loop {
+        let (response, records) = fetch_now(partition, offset, max_bytes, isolation_level)?;
+        let enough = transfer::len(&records) >= min_bytes as u64;
+        match response {
+            Response::Fetch { high_watermark, .. } if !enough && partition.wait_for_flush(high_watermark, deadline) => {},
+            _ => return Ok((response, records)),
         }
     }
-    Ok(offset)
+}
+
+fn fetch_now(
+    partition: &Partition, offset: Offset, max_bytes: u32, isolation_level: IsolationLevel
+) -> io::Result<(Response, Vec<FileSlice>)> {
+    let high_watermark: Offset = *partition.high_watermark.lock().unwrap();
+    // reading through the log's own segments keeps retention and compaction
+    // from changing them underneath
+    let mut log = partition.log.lock().unwrap();
+    let log_start_offset = log.log_start_offset();
+    let last_stable_offset = log.last_stable_offset().min(high_watermark);
+    if offset < log_start_offset || offset > high_watermark {
+        return Err(ResponseError::new(
+            ErrorCode::OffsetOutOfRange,
+            format!("offset {} out of range {}..{}", offset, log_start_offset, high_watermark)
+        ).into());
+    }
+    let (end, aborted) = match isolation_level {
+        IsolationLevel::ReadUncommitted => (high_watermark, vec![]),
+        IsolationLevel::ReadCommitted => (last_stable_offset, log.aborted_transactions(offset, last_stable_offset)),
+    };
+    let records = log.slices(offset, end, max_bytes as usize)?;
+    let response = Response::Fetch { high_watermark, last_stable_offset, log_start_offset, aborted, records: vec![] };
+    Ok((response, records))
+}
+
+fn list_offsets(partition: &Partition, timestamp: i64) -> io::Result<Response> {
+    let found = partition.log.lock().unwrap().offset_for_timestamp(timestamp)?;
+    let high_watermark = *partition.high_watermark.lock().unwrap();
+    // nothing that recent yet, or nothing flushed yet, so start with the
+    // next record consumers get to see
+    let offset = found.unwrap_or(high_watermark).min(high_watermark);
+    Ok(Response::ListOffsets { offset })
 }
 
 
+fn flush_periodically(partition: Arc<Partition>, interval: Duration) {
+    loop {
+        thread::sleep(interval);
+        let mut log = partition.log.lock().unwrap();
+        if *partition.unflushed_messages.lock().unwrap() == 0 {
+            continue
+        }
+        if let Err(e) = partition.flush(&mut log) {
+            println!("ERROR FLUSH: {:?}", e);
+        }
+    }
+}
+
+fn enforce_retention_periodically(partition: Arc<Partition>, interval: Duration) {
+    loop {
+        thread::sleep(interval);
+        let mut log = partition.log.lock().unwrap();
+        match log.enforce_retention() {
+            Ok(ref deleted) if deleted.is_empty() => {},
+            Ok(deleted) => println!(
+                "RETENTION: deleted segments {:?} of {}, log now starts at offset {}",
+                deleted, log.path(), log.log_start_offset()
+            ),
+            Err(e) => println!("ERROR RETENTION: {:?}", e),
+        }
+    }
+}
+
+fn expire_members_periodically(broker: Arc<Broker>, interval: Duration) {
+    loop {
+        thread::sleep(interval);
+        for member_id in broker.groups.expire_members() {
+            println!("GROUP: session of {} timed out", member_id);
+        }
+    }
+}
+
+fn compact_offsets_periodically(broker: Arc<Broker>, interval: Duration) {
+    loop {
+        thread::sleep(interval);
+        match broker.offsets.compact() {
+            Ok(0) => {},
+            Ok(n) => println!("CLEANER: compacted away {} offset commits", n),
+            Err(e) => println!("ERROR CLEANER: {:?}", e),
+        }
+    }
+}
+
+// The cleaner compacts from its own view of the segment files, and only the
+// swap of the compacted segments holds up the partition.
+fn compact_periodically(partition: Arc<Partition>, cleaner: latka::partition::Partition, interval: Duration) {
+    loop {
+        thread::sleep(interval);
+        match cleaner.clean().and_then(|cleaned| partition.log.lock().unwrap().install(cleaned)) {
+            Ok(0) => {},
+            Ok(n) => println!("CLEANER: compacted away {} records of {}", n, cleaner.path()),
+            Err(e) => println!("ERROR CLEANER: {:?}", e),
+        }
+    }
+}
+
 fn main() -> Result<(), Error> {
     let mut opts = Options::new();
     opts.optopt("p", "port", "broker port", "port");
-    opts.optopt("t", "topic", "topic name", "topic");
-    opts.optflag("r", "remove", "remove topic (for recreating)");
-    opts.optflag("c", "create", "create topic");
+    opts.optopt("d", "data-dir", "directory holding the topics", "dirname");
+    opts.optmulti("t", "topic", "topic name", "topic");
+    opts.optflag("r", "remove", "remove topics (for recreating)");
+    opts.optflag("c", "create", "create topics");
+    opts.optopt("n", "partitions", "number of partitions", "number");
     opts.optflag("h", "help", "print usage");
+    opts.optopt("", "flush-messages", "flush after this many messages", "number");
+    opts.optopt("", "flush-ms", "flush pending messages this often", "milliseconds");
+    opts.optopt("", "segment-bytes", "maximum segment size", "bytes");
+    opts.optopt("", "segment-ms", "maximum segment age", "milliseconds");
+    opts.optopt("", "retention-bytes", "maximum topic size", "bytes");
+    opts.optopt("", "retention-ms", "maximum message age", "milliseconds");
+    opts.optopt("", "retention-check-ms", "retention check interval", "milliseconds");
+    opts.optopt("", "cleanup-policy", "delete or compact", "policy");
+    opts.optopt("", "delete-retention-ms", "tombstone retention", "milliseconds");
+    opts.optopt("", "cleaner-backoff-ms", "compaction interval", "milliseconds");
+    opts.optopt("", "compression-type", "codec batches are stored with", "codec");
     let args: Vec<_> = env::args().collect();
     let matches = match opts.parse(&args[1..]) {
         Ok(m) => m,
@@ -237,60 +812,224 @@ fn main() -> Result<(), Error> {
         println!("{}", USAGE);
         return Ok(())
     }
-    let topic = match matches.opt_str("t") {
+    let data_dir = match matches.opt_str("d") {
         Some(s) => s,
-        None => String::from("topic"),
+        None => String::from("."),
     };
-    if matches.opt_present("c") {
-        fs::create_dir(&topic)?;
-    };
-    if matches.opt_present("r") {
-        fs::remove_dir_all(&topic)?;
-        fs::create_dir(&topic)?;
+    let named_topics = matches.opt_strs("t");
+    for topic in &named_topics {
+        if !valid_topic_name(topic) {
+            panic!("Invalid topic name {:?}", topic);
+        }
+        let path = format!("{}/{}", data_dir, topic);
+        if matches.opt_present("c") {
+            fs::create_dir_all(&path)?;
+        };
+        if matches.opt_present("r") {
+            fs::remove_dir_all(&path)?;
+            fs::create_dir(&path)?;
+        };
+    }
+    let partition_count: u32 = match matches.opt_str("n") {
+        Some(s) => s.parse().expect("Couldn't parse partitions"),
+        None => 1,
     };
     let port: u16 = match matches.opt_str("p") {
         Some(s) => s.parse().expect("Couldn't parse Port"),
         None => 7070,
     };
+    let flush_messages: u64 = match matches.opt_str("flush-messages") {
+        Some(s) => s.parse().expect("Couldn't parse flush messages"),
+        None => 1,
+    };
+    let flush_ms: Option<u64> = matches.opt_str("flush-ms").map(
+        |s| s.parse().expect("Couldn't parse flush ms")
+    );
+    let mut config = Config::default();
+    if let Some(s) = matches.opt_str("segment-bytes") {
+        config.segment_bytes = s.parse().expect("Couldn't parse segment bytes");
+    }
+    config.segment_ms = matches.opt_str("segment-ms").map(
+        |s| s.parse().expect("Couldn't parse segment ms")
+    );
+    config.retention_bytes = matches.opt_str("retention-bytes").map(
+        |s| s.parse().expect("Couldn't parse retention bytes")
+    );
+    config.retention_ms = matches.opt_str("retention-ms").map(
+        |s| s.parse().expect("Couldn't parse retention ms")
+    );
+    let retention_check_ms: u64 = match matches.opt_str("retention-check-ms") {
+        Some(s) => s.parse().expect("Couldn't parse retention check ms"),
+        None => 300_000,
+    };
+    config.cleanup_policy = match matches.opt_str("cleanup-policy").as_deref() {
+        None | Some("delete") => CleanupPolicy::Delete,
+        Some("compact") => CleanupPolicy::Compact,
+        Some(other) => panic!("Unknown cleanup policy {}", other),
+    };
+    if let Some(s) = matches.opt_str("delete-retention-ms") {
+        config.delete_retention_ms = s.parse().expect("Couldn't parse delete retention ms");
+    }
+    config.compression = match matches.opt_str("compression-type").as_deref() {
+        None | Some("producer") => None,
+        Some(name) => Some(Compression::parse(name).unwrap_or_else(|| panic!("Unknown compression type {}", name))),
+    };
+    let cleaner_backoff_ms: u64 = match matches.opt_str("cleaner-backoff-ms") {
+        Some(s) => s.parse().expect("Couldn't parse cleaner backoff ms"),
+        None => 15_000,
+    };
+    let retention = config.cleanup_policy == CleanupPolicy::Delete
+        && (config.retention_bytes.is_some() || config.retention_ms.is_some());
+    let compaction = config.cleanup_policy == CleanupPolicy::Compact;
 
     println!("Broker listening on  127.0.0.1:{}", port);
     let listener = TcpListener::bind(("127.0.0.1", port))?;
 
 
 
-    let partition = Arc::new(
-        Partition::new(topic.clone(), 0)?
-    );
+    let mut topics = HashMap::new();
+    for name in discover_topics(&data_dir)?.into_iter().chain(named_topics.clone()) {
+        if topics.contains_key(&name) {
+            continue
+        }
+        // topics already on disk keep the partitions they have
+        let count = if named_topics.contains(&name) { partition_count } else { 1 };
+        let topic = Topic::open(&data_dir, name.clone(), count, flush_messages, &config)?;
+        println!("Serving topic {} with {} partitions", name, topic.partitions.len());
+        topics.insert(name, Arc::new(topic));
+    }
+    for partition in topics.values().flat_map(|topic| &topic.partitions) {
+        if let Some(flush_ms) = flush_ms {
+            let partition = Arc::clone(partition);
+            thread::spawn(move || flush_periodically(partition, Duration::from_millis(flush_ms)));
+        }
+        if retention {
+            let partition = Arc::clone(partition);
+            let interval = Duration::from_millis(retention_check_ms);
+            thread::spawn(move || enforce_retention_periodically(partition, interval));
+        }
+        if compaction {
+            let partition = Arc::clone(partition);
+            let cleaner = latka::partition::Partition::with_config(
+                partition.topic.clone(), partition.partition, config.clone()
+            )?;
+            let interval = Duration::from_millis(cleaner_backoff_ms);
+            thread::spawn(move || compact_periodically(partition, cleaner, interval));
+        }
+    }
+
+    let broker = Arc::new(Broker::open(data_dir, topics)?);
+    {
+        let broker = Arc::clone(&broker);
+        thread::spawn(move || expire_members_periodically(broker, Duration::from_millis(SESSION_CHECK_MS)));
+    }
+    {
+        let broker = Arc::clone(&broker);
+        thread::spawn(move || compact_offsets_periodically(broker, Duration::from_millis(cleaner_backoff_ms)));
+    }
 
     for incoming in listener.incoming() {
-        let mut stream = match incoming {
+        let stream = match incoming {
             Ok(inc) => inc,
             Err(_) => continue,
         };
-        let mut message_type = [0; 1];
-        let _ = stream.read(&mut message_type).unwrap();
-        match message_type[0] {
-            CONSUMER_MESSAGE_PREFIX => {
-                let partition = Arc::clone(&partition);
-                thread::spawn(|| {
-                    match handle_consumer(stream, partition) {
-                        Ok(n) => println!("SUCCESS: Consumer stopped consuming at offset {}", n),
-                        Err(ref e) if e.kind() == ConnectionReset => println!("Consumer dropped off"),
-                        Err(e) => println!("ERROR CON: {:?}", e),
-                    };
-                });
-            },
-            PRODUCER_MESSAGE_PREFIX => {
-                let partition = Arc::clone(&partition);
-                thread::spawn(move || {
-                    match handle_producer(stream, partition) {
-                        Ok(_) => println!("SUCCESS: Producer finished."),
-                        Err(e) => println!("ERROR PRO: {:?}", e),
-                    };
-                });
-            },
-            _ => println!("Unrecognizable Message Prefix {}", message_type[0]),
-        }
+        let broker = Arc::clone(&broker);
+        thread::spawn(move || {
+            match handle_connection(stream, broker) {
+                Ok(()) => {},
+                Err(ref e) if e.kind() == ConnectionReset => println!("Client dropped off"),
+                Err(e) => println!("ERROR CONNECTION: {:?}", e),
+            }
+        });
     };
-    return Ok(());
+    Ok(())
+}
+
+
+#[cfg(test)]
+extern crate speculate;
+
+#[cfg(test)]
+mod tests {
+    use speculate::speculate;
+    use latka::record;
+    use super::*;
+
+    speculate! {
+        after {
+            let _ = fs::remove_dir_all("tmp/");
+        }
+
+        // A broker serving the topic wombats from tmp/, as if just started.
+        fn start() -> Broker {
+            fs::create_dir_all("tmp/wombats").unwrap();
+            let topic = Topic::open("tmp", String::from("wombats"), 1, 1, &Config::default()).unwrap();
+            Broker::open(String::from("tmp"), HashMap::from([(String::from("wombats"), Arc::new(topic))])).unwrap()
+        }
+
+        test "produces that don't decode hang up the connection" {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let address = listener.local_addr().unwrap();
+            let broker = Arc::new(start());
+            let handler = thread::spawn(move || handle_connection(listener.accept().unwrap().0, broker));
+
+            let mut frame = vec![];
+            let request = Request::Produce {
+                topic: String::from("wombats"), partition: 0, acks: Acks::None, records: vec![Record::new(b"WOMBIEST".to_vec())],
+            };
+            protocol::write_request(&mut frame, 1, 1, "wombat", &request).unwrap();
+            // cut the records short, keeping the frame length right
+            frame.truncate(frame.len() - 4);
+            let len = frame.len() as i32 - 4;
+            frame[..4].copy_from_slice(&len.to_be_bytes());
+            let mut stream = TcpStream::connect(address).unwrap();
+            stream.write_all(&frame).unwrap();
+
+            handler.join().unwrap().unwrap();
+            let mut rest = vec![];
+            assert_eq!(stream.read_to_end(&mut rest).unwrap(), 0, "nothing was answered");
+        }
+
+        test "transactions a restart cut short are aborted and fenced off" {
+            let transactional_id = String::from("wombat");
+            let (producer_id, producer_epoch) = {
+                let broker = start();
+                let (producer_id, producer_epoch) = match broker.handle(Request::InitProducerId {
+                    transactional_id: Some(transactional_id.clone()),
+                }).unwrap().0 {
+                    Response::InitProducerId { producer_id, producer_epoch } => (producer_id, producer_epoch),
+                    other => panic!("unexpected response {:?}", other),
+                };
+                broker.handle(Request::AddPartitionsToTxn {
+                    transactional_id: transactional_id.clone(),
+                    producer_id,
+                    producer_epoch,
+                    partitions: vec![(String::from("wombats"), 0)],
+                }).unwrap();
+                let record = Record {
+                    producer_id,
+                    producer_epoch,
+                    attributes: record::TRANSACTIONAL,
+                    ..Record::new(b"WOMBIEST".to_vec())
+                };
+                broker.handle(Request::Produce {
+                    topic: String::from("wombats"), partition: 0, acks: Acks::All, records: vec![record],
+                }).unwrap();
+                (producer_id, producer_epoch)
+            };
+
+            let broker = start();
+            let error = broker.handle(Request::EndTxn {
+                transactional_id: transactional_id.clone(), producer_id, producer_epoch, commit: true,
+            }).unwrap_err();
+            assert_eq!(ErrorCode::for_error(&error), ErrorCode::InvalidProducerEpoch);
+            let log = broker.partition("wombats", 0).unwrap().log.lock().unwrap();
+            assert!(log.ongoing_transactions().is_empty());
+            assert_eq!(log.aborted_transactions(0, log.next_offset()).len(), 1);
+            drop(log);
+
+            let response = broker.handle(Request::InitProducerId { transactional_id: Some(transactional_id) }).unwrap().0;
+            assert!(matches!(response, Response::InitProducerId { producer_epoch: epoch, .. } if epoch == producer_epoch + 2));
+        }
+    }
 }
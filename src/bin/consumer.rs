@@ -1,52 +1,95 @@
-extern crate byteorder;
-
 use std::{env, io};
-use std::io::{BufRead, Write, Error, ErrorKind};
-use std::net::{TcpStream};
+use std::io::Write;
 
-use bufstream::BufStream;
-use byteorder::{WriteBytesExt, NetworkEndian};
+use chrono::DateTime;
 use getopts::Options;
 
+use latka::client::{Consumer, ConsumerConfig, ConsumerRecord, StartAt};
+use latka::protocol::IsolationLevel;
+
 
 static USAGE: &str = "
 Streaming message queue consumer to stdout
 
 Usage:
     consumer
-    consumer [--offset=number] [--port=number]
-    consumer [-o number] [-p number]
+    consumer [--offset=number] [--since=time] [--port=number]
+             [--topic=name] [--partition=number] [--read-committed]
+             [--group=name] [--assignor=range|roundrobin]
+             [--session-timeout-ms=number] [--reconnects=number]
+             [--reconnect-backoff-ms=number]
+    consumer [-o number] [-s time] [-p number] [-t name] [-P number]
 
 Options:
     -h --help     Show this screen.
     -p --port     Connect to broker on port [default 7070]
+    -t --topic    Consume this topic [default topic]
+    -P --partition
+                  Consume this partition of the topic [default 0]
     -o --offset   Start consuming at offset [default 0]
+    -s --since    Start consuming at the first message at or after
+                  an RFC3339 time or epoch milliseconds
+    --read-committed
+                  Only print committed messages of transactions, and
+                  nothing past a transaction that is still open
+    -g --group    Share the topic with the other consumers of this group,
+                  consuming the partitions the broker assigns instead of
+                  --partition, and labelling messages topic-partition@offset.
+                  Partitions resume where the group committed it got to,
+                  --offset and --since only apply to the others
+    --assignor    How the group splits the partitions, range gives each
+                  consumer a block of them, roundrobin deals them out
+                  one by one [default range]
+    --session-timeout-ms
+                  The group hands this consumer's partitions to the
+                  others once it went this long without a heartbeat
+                  [default 10000]
+    --reconnects  Connect again this many times in a row after losing
+                  the broker, carrying on from the last message printed
+                  [default unlimited]
+    --reconnect-backoff-ms
+                  Pause before connecting again, doubling up to a second
+                  with each failed attempt [default 100]
 ";
 
-const MESSAGE_PREFIX: u8 = 42;
-
-
-fn handshake(stream: &mut BufStream<TcpStream>, offset: u64) -> io::Result<()> {
-    stream.write(&[MESSAGE_PREFIX])?;
-
-    let mut big_endian_buffer = vec![];
-    big_endian_buffer.write_u64::<NetworkEndian>(offset)?;
+fn parse_since(since: &str) -> Option<i64> {
+    if let Ok(epoch_ms) = since.parse::<i64>() {
+        return Some(epoch_ms);
+    }
+    DateTime::parse_from_rfc3339(since).ok().map(|time| time.timestamp_millis())
+}
 
-    let num_written = stream.write(big_endian_buffer.as_slice())?;
-    if num_written != 8 {
-        return Err(Error::new(
-            ErrorKind::ConnectionAborted, "Can't communicate with Broker"
-        ));
+// Print a record as offset: value, or topic-partition@offset: value when
+// `labelled`, with the key before the value if it has one.
+fn print<W: Write>(writer: &mut W, record: ConsumerRecord, labelled: bool) -> io::Result<()> {
+    let value = match record.value {
+        Some(value) => String::from_utf8_lossy(&value).into_owned(),
+        None => String::from("(tombstone)"),
+    };
+    let position = match labelled {
+        true => format!("{}-{}@{}", record.topic, record.partition, record.offset),
+        false => record.offset.to_string(),
+    };
+    match record.key {
+        Some(key) => writeln!(writer, "{}: {} => {}", position, String::from_utf8_lossy(&key), value),
+        None => writeln!(writer, "{}: {}", position, value),
     }
-    Ok(())
 }
 
 
 fn main() -> io::Result<()>{
     let mut opts = Options::new();
-    opts.optopt("t", "topic", "the stream topic (not implemented)", "topic");
+    opts.optopt("t", "topic", "the stream topic", "topic");
     opts.optopt("o", "offset", "the offset to read stream from", "off");
+    opts.optopt("s", "since", "read stream from this time (RFC3339 or epoch ms)", "time");
     opts.optopt("p", "port", "broker host port (assume host is localhost)", "port");
+    opts.optopt("P", "partition", "topic partition", "partition");
+    opts.optflag("", "read-committed", "skip aborted and open transactions");
+    opts.optopt("g", "group", "share the topic with the group's other consumers", "group");
+    opts.optopt("", "assignor", "how the group splits the partitions", "range|roundrobin");
+    opts.optopt("", "session-timeout-ms", "leave the group after this long without a heartbeat", "milliseconds");
+    opts.optopt("", "reconnects", "times to connect again after losing the broker", "number");
+    opts.optopt("", "reconnect-backoff-ms", "pause before connecting again", "milliseconds");
     let args: Vec<_> = env::args().collect();
     let matches = match opts.parse(&args[1..]) {
         Ok(m) => m,
@@ -56,51 +99,78 @@ fn main() -> io::Result<()>{
         Some(s) => s.parse().expect("Couldn't parse Port"),
         None => 7070,
     };
-    let mut offset: u64 = match matches.opt_str("o") {
+    let topic = match matches.opt_str("t") {
+        Some(s) => s,
+        None => String::from("topic"),
+    };
+    let partition: u32 = match matches.opt_str("P") {
+        Some(s) => s.parse().expect("Couldn't parse partition"),
+        None => 0,
+    };
+    let offset: u64 = match matches.opt_str("o") {
         Some(s) => s.parse().expect("Couldn't parse offset"),
         None => 0,
     };
-
-    let tcp_stream  = TcpStream::connect(("127.0.0.1", port))?;
-    let mut stream = BufStream::new(tcp_stream);
-    handshake(&mut stream, offset)?;
+    let since = matches.opt_str("s").map(|since| parse_since(&since).expect("Couldn't parse since"));
+    let isolation_level = match matches.opt_present("read-committed") {
+        true => IsolationLevel::ReadCommitted,
+        false => IsolationLevel::ReadUncommitted,
+    };
+    let start = match since {
+        Some(timestamp) => StartAt::Timestamp(timestamp),
+        None => StartAt::Offset(offset),
+    };
+    let mut config = ConsumerConfig { isolation_level, ..ConsumerConfig::default() };
+    if let Some(session_timeout_ms) = matches.opt_str("session-timeout-ms") {
+        config.session_timeout_ms = session_timeout_ms.parse().expect("Couldn't parse session timeout");
+    }
+    if let Some(assignor) = matches.opt_str("assignor") {
+        config.assignor = assignor;
+    }
+    if let Some(reconnects) = matches.opt_str("reconnects") {
+        config.reconnects = reconnects.parse().expect("Couldn't parse reconnects");
+    }
+    if let Some(backoff) = matches.opt_str("reconnect-backoff-ms") {
+        config.reconnect_backoff_ms = backoff.parse().expect("Couldn't parse reconnect backoff");
+    }
+    config.group_id = matches.opt_str("g");
+    let labelled = config.group_id.is_some();
+    let mut consumer = Consumer::connect(("127.0.0.1", port), config)?;
 
     let stdout = io::stdout();
     let mut writer = stdout.lock();
 
-    stream.flush()?;
+    if labelled {
+        consumer.subscribe(&[&topic], start)?;
+        let result = consume_group(&mut consumer, &mut writer);
+        consumer.close()?;
+        return result;
+    }
 
-    let mut line = String::new();
+    consumer.assign(&topic, partition, start)?;
     loop {
-        let num_read = match stream.read_line(&mut line) {
-            Ok(n) => n,
+        match consumer.poll() {
+            Ok(records) => {
+                for record in records {
+                    print(&mut writer, record, false)?;
+                }
+            },
             Err(e) => {
+                let offset = consumer.position(&topic, partition).unwrap_or(offset);
                 writeln!(writer, "{} {:?}", offset, e)?;
                 break
-            }
-        };
-        match num_read {
-            0 => break,
-            2 => {
-                // Check for hearbeat KEEP alive signal
-                // this is to communicate to the broker when
-                // the consumer drops off if the consumer
-                // has read to the end of the queue and is
-                // waiting for more messages
-                // The keep alive single is a null byte
-                if line.as_bytes()[0] == 0 {
-                    line.clear();
-                    continue
-                }
             },
-            _ => {;},
         }
-
-        let message = format!("{}: {}", offset, line);
-        write!(writer, "{}", message).unwrap();
-
-        offset += line.len() as u64;
-        line.clear(); // clear to reuse the buffer
     }
     Ok(())
 }
+
+// Print the records of the partitions the group assigns until something
+// goes wrong.
+fn consume_group<W: Write>(consumer: &mut Consumer, writer: &mut W) -> io::Result<()> {
+    loop {
+        for record in consumer.poll()? {
+            print(writer, record, true)?;
+        }
+    }
+}
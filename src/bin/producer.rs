@@ -1,12 +1,13 @@
-extern crate byteorder;
-
 use std::{env, io};
-use std::io::{Write, BufWriter};
-use std::net::{TcpStream};
+use std::collections::VecDeque;
 use std::{thread, time};
 
 use getopts::Options;
 
+use latka::client::{Delivery, Producer, ProducerConfig, ProducerRecord};
+use latka::compression::Compression;
+use latka::protocol::Acks;
+
 
 
 static USAGE: &str = "
@@ -14,22 +15,78 @@ Streaming message queue producer from stdin
 
 Usage:
     producer
-    producer [--sleep=number] [--port=number]
-    producer [-s number] [-p number]
+    producer [--sleep=number] [--port=number] [--topic=name] [--partition=number]
+             [--key-separator=sep] [--acks=0|1|all] [--idempotent] [--verbose]
+             [--transactional-id=id] [--batch-size=bytes] [--linger-ms=number]
+             [--buffer-memory=bytes] [--retries=number] [--retry-backoff-ms=number]
+             [--delivery-timeout-ms=number] [--compression-type=codec]
+    producer [-s number] [-p number] [-t name] [-P number] [-k sep] [-a acks] [-i] [-v]
+             [-x id]
 
 Options:
-    -h --help     Show this screen.
-    -p --port     Connect to broker on port [default 7070]
-    -s --sleep    Milliseconds pause between writing to topic [default 100]
+    -h --help            Show this screen.
+    -p --port            Connect to broker on port [default 7070]
+    -t --topic           Produce to this topic [default topic]
+    -P --partition       Produce every record to this partition, otherwise
+                         keyed records are spread by key and the others
+                         stick to one partition until the next pause
+                         or until a batch fills up
+    -s --sleep           Milliseconds pause between lines, sending each line
+                         before pausing [default 100]
+    -k --key-separator   Split each line into key and value at the first sep,
+                         a line without sep is a tombstone for its key
+    -a --acks            0: don't wait for the broker
+                         1: wait until the broker wrote each batch [default]
+                         all: wait until the broker synced it to disk
+    -i --idempotent      Number the lines so the broker writes a resent one once
+    -v --verbose         Print topic-partition@offset for every line written
+    -x --transactional-id
+                         Write the lines in transactions, fencing off any
+                         older producer with the same id. A line .commit
+                         commits the lines since the last transaction, a
+                         line .abort aborts them, the end of input commits
+    --batch-size         Send a partition's lines once they add up to this
+                         many bytes [default 16384]
+    --linger-ms          or once the first of them waited this long for
+                         more [default 0]
+    --buffer-memory      Stop reading lines while this many bytes of them
+                         wait to be sent [default 33554432]
+    --retries            Send lines again this many times after the
+                         connection failed [default unlimited]
+    --retry-backoff-ms   Pause before sending again, doubling up to a
+                         second with each retry [default 100]
+    --delivery-timeout-ms
+                         Give up on lines not delivered this long after
+                         they were read [default 120000]
+    --compression-type   Compress each batch with none, gzip, snappy, lz4
+                         or zstd [default none]
+
+Lines that couldn't be delivered are printed to stderr, and the producer
+carries on with the next ones.
 ";
 
-const MESSAGE_PREFIX: u8 = 78;
+const COMMIT_LINE: &str = ".commit";
+const ABORT_LINE: &str = ".abort";
 
 
 fn main() -> io::Result<()> {
     let mut opts = Options::new();
     opts.optopt("s", "sleep", "sleep for testing", "sleep, milliseconds");
     opts.optopt("p", "port", "broker port", "port");
+    opts.optopt("t", "topic", "the stream topic", "topic");
+    opts.optopt("P", "partition", "topic partition", "partition");
+    opts.optopt("k", "key-separator", "split lines into key and value", "sep");
+    opts.optopt("a", "acks", "what the broker does before answering", "acks");
+    opts.optflag("i", "idempotent", "let the broker drop resent lines");
+    opts.optflag("v", "verbose", "print where each line was written");
+    opts.optopt("x", "transactional-id", "write lines in transactions", "id");
+    opts.optopt("", "batch-size", "bytes of lines sent together", "bytes");
+    opts.optopt("", "linger-ms", "how long lines wait for more to send together", "milliseconds");
+    opts.optopt("", "buffer-memory", "bytes of lines waiting to be sent before blocking", "bytes");
+    opts.optopt("", "retries", "times lines are sent again", "number");
+    opts.optopt("", "retry-backoff-ms", "pause before sending lines again", "milliseconds");
+    opts.optopt("", "delivery-timeout-ms", "how long lines may take to be delivered", "milliseconds");
+    opts.optopt("", "compression-type", "codec batches are compressed with", "codec");
     let args: Vec<_> = env::args().collect();
     let matches = match opts.parse(&args[1..]) {
         Ok(m) => m,
@@ -43,31 +100,126 @@ fn main() -> io::Result<()> {
         Some(s) => s.parse().expect("Couldn't parse Port"),
         None => 7070,
     };
+    let topic = match matches.opt_str("t") {
+        Some(s) => s,
+        None => String::from("topic"),
+    };
+    let partition: Option<u32> = matches.opt_str("P").map(
+        |s| s.parse().expect("Couldn't parse partition")
+    );
+    let key_separator = matches.opt_str("k");
+    let acks = match matches.opt_str("a").as_deref() {
+        Some("0") => Acks::None,
+        None | Some("1") => Acks::Leader,
+        Some("all") | Some("-1") => Acks::All,
+        Some(other) => panic!("Unknown acks {}", other),
+    };
+    let verbose = matches.opt_present("v");
+    let transactional_id = matches.opt_str("x");
+    let transactional = transactional_id.is_some();
+    let mut config = ProducerConfig {
+        acks,
+        idempotent: matches.opt_present("i"),
+        transactional_id,
+        ..ProducerConfig::default()
+    };
+    if let Some(s) = matches.opt_str("batch-size") {
+        config.batch_size = s.parse().expect("Couldn't parse batch size");
+    }
+    if let Some(s) = matches.opt_str("linger-ms") {
+        config.linger_ms = s.parse().expect("Couldn't parse linger");
+    }
+    if let Some(s) = matches.opt_str("buffer-memory") {
+        config.buffer_memory = s.parse().expect("Couldn't parse buffer memory");
+    }
+    if let Some(s) = matches.opt_str("retries") {
+        config.retries = s.parse().expect("Couldn't parse retries");
+    }
+    if let Some(s) = matches.opt_str("retry-backoff-ms") {
+        config.retry_backoff_ms = s.parse().expect("Couldn't parse retry backoff");
+    }
+    if let Some(s) = matches.opt_str("delivery-timeout-ms") {
+        config.delivery_timeout_ms = s.parse().expect("Couldn't parse delivery timeout");
+    }
+    if let Some(s) = matches.opt_str("compression-type") {
+        config.compression = Compression::parse(&s).unwrap_or_else(|| panic!("Unknown compression type {}", s));
+    }
 
-    // So each message is newline separated
+    // So each line of stdin becomes one record
     // and a producer ends streaming once it closes the connection
     // TODO: handle unable to connect with more helpful message
-    let stream  = TcpStream::connect(("127.0.0.1", port))?;
-    let mut writer = BufWriter::new(stream);
-    writer.write(&[MESSAGE_PREFIX])?;
-    writer.flush()?;
+    let mut producer = Producer::connect(("127.0.0.1", port), config)?;
+
+    // lines sent but not known to be delivered yet, oldest first
+    let mut pending = VecDeque::new();
+    let mut failed = 0;
 
     let stdin = io::stdin();
 
     let mut input = String::new();
-    loop {
-        match stdin.read_line(&mut input) {
-            Ok(n) => {if n == 0 {break}}
-            Err(_) => break,
+    while let Ok(n) = stdin.read_line(&mut input) {
+        if n == 0 {break}
+        let line = input.strip_suffix('\n').unwrap_or(&input);
+        if transactional && line == COMMIT_LINE {
+            producer.commit_transaction()?;
+            input.clear();
+            continue
+        }
+        if transactional && line == ABORT_LINE {
+            producer.abort_transaction()?;
+            input.clear();
+            continue
+        }
+        let mut record = match &key_separator {
+            Some(sep) => match line.split_once(sep.as_str()) {
+                Some((key, value)) => ProducerRecord::with_key(topic.as_str(), key, Some(value.into())),
+                None => ProducerRecord::with_key(topic.as_str(), line, None),
+            },
+            None => ProducerRecord::new(topic.as_str(), line),
+        };
+        if let Some(partition) = partition {
+            record = record.partition(partition);
         }
-        write!(writer, "{}", input)?;
+        pending.push_back((line.to_string(), producer.send(record)?));
+        failed += delivered(&mut pending, verbose, false);
         input.clear();
 
         if sleep == 0 {
             continue
         }
+        // failures are reported line by line
+        let _ = producer.flush();
+        failed += delivered(&mut pending, verbose, false);
         let pause = time::Duration::from_millis(sleep);
         thread::sleep(pause);
     }
-    Ok(())
+    if transactional {
+        producer.commit_transaction()?;
+    }
+    let _ = producer.close();
+    failed += delivered(&mut pending, verbose, true);
+    match failed {
+        0 => Ok(()),
+        _ => Err(io::Error::other(format!("{} lines weren't delivered", failed))),
+    }
+}
+
+// Take the deliveries off the front of `pending` that are done, or all of
+// them if `all`, printing where the lines were written if `verbose` and
+// the lines that failed. Returns how many failed.
+fn delivered(pending: &mut VecDeque<(String, Delivery)>, verbose: bool, all: bool) -> usize {
+    let mut failed = 0;
+    while pending.front().is_some_and(|(_, delivery)| all || delivery.is_done()) {
+        let (line, delivery) = pending.pop_front().unwrap();
+        match delivery.wait() {
+            Ok(metadata) => if let (true, Some(offset)) = (verbose, metadata.offset) {
+                println!("{}-{}@{}", metadata.topic, metadata.partition, offset)
+            },
+            Err(e) => {
+                eprintln!("ERROR: {}: {}", line, e);
+                failed += 1;
+            },
+        }
+    }
+    failed
 }
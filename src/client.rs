@@ -0,0 +1,513 @@
+// Clients of the broker. A `Producer` sends records to topics and a
+// `Consumer` fetches them, either from partitions it is assigned by hand or
+// from those a consumer group hands it. Both speak the protocol over one
+// `Connection` each and report failures as an `Error`, which tells apart
+// a broken connection from a request the broker turned down.
+use std::fmt;
+use std::io;
+
+use crate::protocol::{ErrorCode, Response, ResponseError};
+
+mod accumulator;
+mod connection;
+mod consumer;
+mod producer;
+
+pub use self::connection::Connection;
+pub use self::consumer::{Consumer, ConsumerConfig, ConsumerRecord, StartAt};
+pub use self::producer::{Delivery, Producer, ProducerConfig, ProducerRecord, RecordMetadata};
+
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug)]
+pub enum Error {
+    /// Talking to the broker failed.
+    Io(io::Error),
+    /// The broker turned the request down.
+    Broker(ResponseError),
+    /// The broker answered with something that wasn't asked for.
+    UnexpectedResponse(String),
+    /// The client was configured with options that don't go together.
+    Config(String),
+    /// Records weren't delivered in the time they were given.
+    TimedOut(String),
+}
+
+impl Error {
+    /// The code of the broker's refusal, `None` for any other error.
+    pub fn code(&self) -> Option<ErrorCode> {
+        match self {
+            Error::Broker(error) => Some(error.code),
+            _ => None,
+        }
+    }
+
+    /// Whether trying again may succeed, because the connection failed or
+    /// the broker couldn't make sense of what arrived.
+    pub fn is_retriable(&self) -> bool {
+        match self {
+            Error::Io(_) => true,
+            Error::Broker(error) => error.code == ErrorCode::CorruptMessage,
+            _ => false,
+        }
+    }
+
+    fn unexpected(response: Response) -> Error {
+        Error::UnexpectedResponse(format!("{:?}", response))
+    }
+}
+
+// A failed batch fails every record in it. Io errors can't be cloned, so
+// the copies keep only their kind and message.
+impl Clone for Error {
+    fn clone(&self) -> Error {
+        match self {
+            Error::Io(error) => Error::Io(io::Error::new(error.kind(), error.to_string())),
+            Error::Broker(error) => Error::Broker(error.clone()),
+            Error::UnexpectedResponse(response) => Error::UnexpectedResponse(response.clone()),
+            Error::Config(message) => Error::Config(message.clone()),
+            Error::TimedOut(message) => Error::TimedOut(message.clone()),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(error) => write!(f, "{}", error),
+            Error::Broker(error) => write!(f, "broker refused: {}", error),
+            Error::UnexpectedResponse(response) => write!(f, "unexpected response {}", response),
+            Error::Config(message) => write!(f, "invalid config: {}", message),
+            Error::TimedOut(message) => write!(f, "timed out: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(error) => Some(error),
+            Error::Broker(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+// the protocol wraps refusals in io errors, which are unwrapped again
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Error {
+        match error.get_ref().and_then(|e| e.downcast_ref::<ResponseError>()) {
+            Some(response_error) => Error::Broker(response_error.clone()),
+            None => Error::Io(error),
+        }
+    }
+}
+
+impl From<ResponseError> for Error {
+    fn from(error: ResponseError) -> Error {
+        Error::Broker(error)
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(error: Error) -> io::Error {
+        match error {
+            Error::Io(error) => error,
+            Error::Broker(error) => error.into(),
+            Error::UnexpectedResponse(_) => io::Error::new(io::ErrorKind::InvalidData, error),
+            Error::Config(_) => io::Error::new(io::ErrorKind::InvalidInput, error),
+            Error::TimedOut(_) => io::Error::new(io::ErrorKind::TimedOut, error),
+        }
+    }
+}
+
+
+#[cfg(test)]
+extern crate speculate;
+
+#[cfg(test)]
+mod tests {
+    use speculate::speculate;
+    use std::io::Write;
+    use std::net::{SocketAddr, TcpListener};
+    use std::sync::mpsc;
+    use std::thread::{self, JoinHandle};
+    use bufstream::BufStream;
+    use crate::compression::Compression;
+    use crate::partition::AbortedTransaction;
+    use crate::protocol::{self, ApiVersion, IsolationLevel, Request};
+    use crate::record::{self, Record};
+    use crate::segment::Offset;
+    use super::*;
+
+    speculate! {
+        // A broker answering one client with `handle`, which returns the
+        // requests it got once the client hangs up.
+        fn broker<F>(mut handle: F) -> (SocketAddr, JoinHandle<Vec<Request>>)
+        where
+            F: FnMut(&Request) -> Response + Send + 'static
+        {
+            flaky_broker(1, move |request| Some(handle(request)))
+        }
+
+        // A broker taking `connections` connections one after another and
+        // hanging up on requests `handle` doesn't answer, then going away.
+        fn flaky_broker<F>(connections: usize, mut handle: F) -> (SocketAddr, JoinHandle<Vec<Request>>)
+        where
+            F: FnMut(&Request) -> Option<Response> + Send + 'static
+        {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let address = listener.local_addr().unwrap();
+            let requests = thread::spawn(move || {
+                let mut requests = vec![];
+                for _ in 0..connections {
+                    let mut stream = BufStream::new(listener.accept().unwrap().0);
+                    while let Some((header, request)) = protocol::read_request(&mut stream).unwrap() {
+                        let request = request.unwrap();
+                        let response = match request {
+                            Request::ApiVersions => Some(Response::ApiVersions { versions: ApiVersion::supported() }),
+                            ref request => handle(request),
+                        };
+                        let hang_up = response.is_none();
+                        if let (Some(response), true) = (response, request.expects_response()) {
+                            protocol::write_response(&mut stream, header.api_version, header.correlation_id, &Ok(response)).unwrap();
+                            stream.flush().unwrap();
+                        }
+                        requests.push(request);
+                        if hang_up {
+                            break
+                        }
+                    }
+                }
+                requests
+            });
+            (address, requests)
+        }
+
+        fn produces(requests: Vec<Request>) -> usize {
+            requests.iter().filter(|request| matches!(request, Request::Produce { .. })).count()
+        }
+
+        test "idempotent producers number records per partition" {
+            let mut next_offset = 0;
+            let (address, requests) = broker(move |request| match request {
+                Request::InitProducerId { .. } => Response::InitProducerId { producer_id: 7, producer_epoch: 2 },
+                _ => {
+                    next_offset += 1;
+                    Response::Produce { base_offset: Some(next_offset - 1) }
+                },
+            });
+            let config = ProducerConfig { idempotent: true, ..ProducerConfig::default() };
+            let mut producer = Producer::connect(address, config).unwrap();
+            for &partition in &[0, 1, 0] {
+                producer.send(ProducerRecord::new("wombats", "WOMBIEST").partition(partition)).unwrap();
+            }
+            let delivery = producer.send(ProducerRecord::new("wombats", "WOMBIEST").partition(1)).unwrap();
+            producer.flush().unwrap();
+            let metadata = delivery.wait().unwrap();
+            assert_eq!(metadata.topic, "wombats");
+            assert_eq!(metadata.partition, 1);
+            drop(producer);
+
+            let mut sent: Vec<(u32, i64, i16, i32)> = requests.join().unwrap().into_iter().flat_map(|request| match request {
+                Request::Produce { partition, records, .. } => records.into_iter()
+                    .map(|record| (partition, record.producer_id, record.producer_epoch, record.sequence))
+                    .collect(),
+                _ => vec![],
+            }).collect();
+            // batches of different partitions may go in any order
+            sent.sort_unstable();
+            assert_eq!(sent, vec![(0, 7, 2, 0), (0, 7, 2, 1), (1, 7, 2, 0), (1, 7, 2, 1)]);
+        }
+
+        test "a transaction that failed to commit is committed again" {
+            let mut hung_up = false;
+            let (address, requests) = flaky_broker(2, move |request| match (request, hung_up) {
+                (Request::InitProducerId { .. }, _) => Some(Response::InitProducerId { producer_id: 7, producer_epoch: 2 }),
+                (Request::AddPartitionsToTxn { .. }, _) => Some(Response::AddPartitionsToTxn),
+                (Request::EndTxn { .. }, false) => {
+                    hung_up = true;
+                    None
+                },
+                (Request::EndTxn { .. }, true) => Some(Response::EndTxn),
+                _ => Some(Response::Produce { base_offset: Some(0) }),
+            });
+            let config = ProducerConfig { transactional_id: Some(String::from("wombat")), ..ProducerConfig::default() };
+            let mut producer = Producer::connect(address, config).unwrap();
+            producer.send(ProducerRecord::new("wombats", "WOMBIEST").partition(0)).unwrap();
+            assert!(matches!(producer.commit_transaction(), Err(Error::Io(_))));
+            producer.commit_transaction().unwrap();
+            producer.commit_transaction().unwrap();
+            drop(producer);
+            let ended = requests.join().unwrap().into_iter()
+                .filter(|request| matches!(request, Request::EndTxn { commit: true, .. }))
+                .count();
+            assert_eq!(ended, 2, "only the failed commit is sent again");
+        }
+
+        test "idempotent producers start over once a batch failed for good" {
+            let (mut producer_id, mut hung_up) = (6, false);
+            let (address, requests) = flaky_broker(2, move |request| match (request, hung_up) {
+                (Request::InitProducerId { .. }, _) => {
+                    producer_id += 1;
+                    Some(Response::InitProducerId { producer_id, producer_epoch: 0 })
+                },
+                (_, false) => {
+                    hung_up = true;
+                    None
+                },
+                (_, true) => Some(Response::Produce { base_offset: Some(0) }),
+            });
+            let config = ProducerConfig { idempotent: true, retries: 0, ..ProducerConfig::default() };
+            let mut producer = Producer::connect(address, config).unwrap();
+            producer.send(ProducerRecord::new("wombats", "WOMBIEST").partition(0)).unwrap();
+            assert!(matches!(producer.flush(), Err(Error::Io(_))));
+            for _ in 0..2 {
+                producer.send(ProducerRecord::new("wombats", "WOMBIEST").partition(0)).unwrap();
+                producer.flush().unwrap();
+            }
+            drop(producer);
+
+            let sent: Vec<(i64, i32)> = requests.join().unwrap().into_iter().flat_map(|request| match request {
+                Request::Produce { records, .. } => records.into_iter().map(|record| (record.producer_id, record.sequence)).collect(),
+                _ => vec![],
+            }).collect();
+            assert_eq!(sent, vec![(7, 0), (8, 0), (8, 1)]);
+        }
+
+        test "transactions with a failed batch can only be aborted" {
+            let (mut producer_epoch, mut hung_up) = (-1, false);
+            let (address, requests) = flaky_broker(2, move |request| match (request, hung_up) {
+                (Request::InitProducerId { .. }, _) => {
+                    producer_epoch += 1;
+                    Some(Response::InitProducerId { producer_id: 7, producer_epoch })
+                },
+                (Request::AddPartitionsToTxn { .. }, _) => Some(Response::AddPartitionsToTxn),
+                (Request::EndTxn { .. }, _) => Some(Response::EndTxn),
+                (_, false) => {
+                    hung_up = true;
+                    None
+                },
+                (_, true) => Some(Response::Produce { base_offset: Some(0) }),
+            });
+            let config = ProducerConfig { transactional_id: Some(String::from("wombat")), retries: 0, ..ProducerConfig::default() };
+            let mut producer = Producer::connect(address, config).unwrap();
+            producer.send(ProducerRecord::new("wombats", "WOMBIEST").partition(0)).unwrap();
+            assert!(matches!(producer.flush(), Err(Error::Io(_))));
+            assert!(matches!(producer.commit_transaction(), Err(Error::Io(_))));
+            producer.abort_transaction().unwrap();
+            producer.send(ProducerRecord::new("wombats", "WOMBIEST").partition(0)).unwrap();
+            producer.commit_transaction().unwrap();
+            drop(producer);
+
+            let sent: Vec<(i16, i32)> = requests.join().unwrap().into_iter().flat_map(|request| match request {
+                Request::Produce { records, .. } => records.into_iter().map(|record| (record.producer_epoch, record.sequence)).collect(),
+                _ => vec![],
+            }).collect();
+            assert_eq!(sent, vec![(0, 0), (1, 0)]);
+        }
+
+        test "keyless records go where the partitioner puts them" {
+            let (address, requests) = broker(|request| match request {
+                Request::Metadata { .. } => Response::Metadata { partitions: 3 },
+                _ => Response::Produce { base_offset: Some(0) },
+            });
+            let mut producer = Producer::connect(address, ProducerConfig::default()).unwrap();
+            let mut send = || producer.send(ProducerRecord::new("wombats", "WOMBIEST")).unwrap();
+            let (first, second) = (send(), send());
+            producer.flush().unwrap();
+            let third = producer.send(ProducerRecord::new("wombats", "WOMBIEST")).unwrap();
+            producer.close().unwrap();
+            let first = first.wait().unwrap().partition;
+            assert_eq!(second.wait().unwrap().partition, first);
+            assert_ne!(third.wait().unwrap().partition, first, "a flush moves on to another partition");
+            let metadata = requests.join().unwrap().into_iter()
+                .filter(|request| matches!(request, Request::Metadata { .. }))
+                .count();
+            assert_eq!(metadata, 1, "partition counts are remembered");
+        }
+
+        test "batches are sent again once the broker is back" {
+            let mut hung_up = false;
+            let (address, requests) = flaky_broker(2, move |_| match hung_up {
+                false => {
+                    hung_up = true;
+                    None
+                },
+                true => Some(Response::Produce { base_offset: Some(3) }),
+            });
+            let config = ProducerConfig { retry_backoff_ms: 10, ..ProducerConfig::default() };
+            let mut producer = Producer::connect(address, config).unwrap();
+            let delivery = producer.send(ProducerRecord::new("wombats", "WOMBIEST").partition(0)).unwrap();
+            producer.close().unwrap();
+            assert_eq!(delivery.wait().unwrap().offset, Some(3));
+            assert_eq!(produces(requests.join().unwrap()), 2);
+        }
+
+        test "batches fail once they run out of retries or time" {
+            let (address, requests) = flaky_broker(1, |_| None);
+            let config = ProducerConfig { retries: 0, ..ProducerConfig::default() };
+            let mut producer = Producer::connect(address, config).unwrap();
+            let delivery = producer.send(ProducerRecord::new("wombats", "WOMBIEST").partition(0)).unwrap();
+            assert!(matches!(producer.flush(), Err(Error::Io(_))));
+            assert!(matches!(delivery.wait(), Err(Error::Io(_))));
+            drop(producer);
+            assert_eq!(produces(requests.join().unwrap()), 1);
+
+            // the broker is gone for good now
+            let (address, _) = flaky_broker(1, |_| None);
+            let config = ProducerConfig { retry_backoff_ms: 10, delivery_timeout_ms: 100, ..ProducerConfig::default() };
+            let mut producer = Producer::connect(address, config).unwrap();
+            let delivery = producer.send(ProducerRecord::new("wombats", "WOMBIEST").partition(0)).unwrap();
+            assert!(matches!(delivery.wait(), Err(Error::TimedOut(_))));
+        }
+
+        test "callbacks hear how sending went" {
+            let (address, _) = broker(|_| Response::Produce { base_offset: Some(8) });
+            let mut producer = Producer::connect(address, ProducerConfig::default()).unwrap();
+            let (sender, receiver) = mpsc::channel();
+            let delivery = producer.send_with_callback(
+                ProducerRecord::new("wombats", "WOMBIEST").partition(0),
+                move |result| sender.send(result.map(|metadata| metadata.offset)).unwrap(),
+            ).unwrap();
+            assert_eq!(receiver.recv().unwrap().unwrap(), Some(8));
+            assert!(delivery.is_done());
+        }
+
+        test "every clone of a delivery hears how it went" {
+            let (address, _) = broker(|_| Response::Produce { base_offset: Some(8) });
+            let mut producer = Producer::connect(address, ProducerConfig::default()).unwrap();
+            let delivery = producer.send(ProducerRecord::new("wombats", "WOMBIEST").partition(0)).unwrap();
+            let clone = delivery.clone();
+            assert_eq!(delivery.clone().wait().unwrap().offset, Some(8));
+            assert!(delivery.is_done());
+            assert_eq!(clone.wait().unwrap().offset, Some(8));
+            assert_eq!(delivery.wait().unwrap().offset, Some(8));
+        }
+
+        test "compressed batches go as one record and come back as many" {
+            let (address, requests) = broker(|_| Response::Produce { base_offset: Some(0) });
+            let config = ProducerConfig { compression: Compression::Zstd, linger_ms: 1000, ..ProducerConfig::default() };
+            let mut producer = Producer::connect(address, config).unwrap();
+            let deliveries: Vec<Delivery> = (0..3)
+                .map(|_| producer.send(ProducerRecord::new("wombats", "WOMBIEST").partition(0)).unwrap())
+                .collect();
+            producer.close().unwrap();
+            let offsets: Vec<Option<Offset>> = deliveries.into_iter().map(|delivery| delivery.wait().unwrap().offset).collect();
+            assert_eq!(offsets, vec![Some(0), Some(1), Some(2)]);
+            let sent: Vec<Record> = requests.join().unwrap().into_iter().flat_map(|request| match request {
+                Request::Produce { records, .. } => records,
+                _ => vec![],
+            }).collect();
+            assert_eq!(sent.len(), 1);
+            assert!(sent[0].is_compressed());
+
+            // a fetch from inside the batch gets all of it
+            let (address, _) = broker(move |_| Response::Fetch {
+                high_watermark: 3,
+                last_stable_offset: 3,
+                log_start_offset: 0,
+                aborted: vec![],
+                records: sent.clone(),
+            });
+            let mut consumer = Consumer::connect(address, ConsumerConfig::default()).unwrap();
+            consumer.assign("wombats", 0, StartAt::Offset(1)).unwrap();
+            let polled: Vec<Offset> = consumer.poll().unwrap().iter().map(|record| record.offset).collect();
+            assert_eq!(polled, vec![1, 2]);
+            assert_eq!(consumer.position("wombats", 0), Some(3));
+        }
+
+        test "polls skip aborted records and move past markers" {
+            let mut aborted = Record { producer_id: 5, ..Record::new(b"ABORTED".to_vec()) };
+            aborted.attributes |= record::TRANSACTIONAL;
+            let records: Vec<Record> = vec![Record::new(b"WOMBIEST".to_vec()), aborted, Record::control(5, 0, false)]
+                .into_iter()
+                .enumerate()
+                .map(|(offset, record)| Record { offset: offset as u64 + 4, ..record })
+                .collect();
+            let (address, _) = broker(move |_| Response::Fetch {
+                high_watermark: 7,
+                last_stable_offset: 7,
+                log_start_offset: 0,
+                aborted: vec![AbortedTransaction { producer_id: 5, first_offset: 5, last_offset: 6 }],
+                records: records.clone(),
+            });
+            let config = ConsumerConfig { isolation_level: IsolationLevel::ReadCommitted, ..ConsumerConfig::default() };
+            let mut consumer = Consumer::connect(address, config).unwrap();
+            consumer.assign("wombats", 0, StartAt::Offset(4)).unwrap();
+            let polled = consumer.poll().unwrap();
+            assert_eq!(polled.len(), 1);
+            assert_eq!((polled[0].offset, polled[0].value.as_deref()), (4, Some(&b"WOMBIEST"[..])));
+            assert_eq!(consumer.position("wombats", 0), Some(7));
+        }
+
+        test "polls connect again and carry on where they were" {
+            let mut hung_up = false;
+            let (address, requests) = flaky_broker(2, move |request| match (request, hung_up) {
+                (Request::Fetch { .. }, false) => {
+                    hung_up = true;
+                    None
+                },
+                (Request::Fetch { offset, .. }, true) => Some(Response::Fetch {
+                    high_watermark: offset + 1,
+                    last_stable_offset: offset + 1,
+                    log_start_offset: 0,
+                    aborted: vec![],
+                    records: vec![Record { offset: *offset, ..Record::new(b"WOMBIEST".to_vec()) }],
+                }),
+                _ => Some(Response::Heartbeat),
+            });
+            let config = ConsumerConfig { reconnect_backoff_ms: 10, ..ConsumerConfig::default() };
+            let mut consumer = Consumer::connect(address, config).unwrap();
+            consumer.assign("wombats", 0, StartAt::Offset(4)).unwrap();
+            assert!(consumer.poll().unwrap().is_empty());
+            let polled = consumer.poll().unwrap();
+            assert_eq!(polled.iter().map(|record| record.offset).collect::<Vec<_>>(), vec![4]);
+            assert_eq!(consumer.position("wombats", 0), Some(5));
+            drop(consumer);
+            let fetched: Vec<Offset> = requests.join().unwrap().into_iter().filter_map(|request| match request {
+                Request::Fetch { offset, .. } => Some(offset),
+                _ => None,
+            }).collect();
+            assert_eq!(fetched, vec![4, 4]);
+        }
+
+        test "polls fail once they run out of reconnects" {
+            let (address, _) = flaky_broker(1, |_| None);
+            let config = ConsumerConfig { reconnects: 1, reconnect_backoff_ms: 10, ..ConsumerConfig::default() };
+            let mut consumer = Consumer::connect(address, config).unwrap();
+            consumer.assign("wombats", 0, StartAt::Offset(0)).unwrap();
+            assert!(consumer.poll().unwrap().is_empty());
+            assert!(matches!(consumer.poll(), Err(Error::Io(_))));
+        }
+
+        test "subscribing needs a group" {
+            let (address, _) = broker(|_| Response::Heartbeat);
+            let mut consumer = Consumer::connect(address, ConsumerConfig::default()).unwrap();
+            assert!(matches!(consumer.subscribe(&["wombats"], StartAt::Offset(0)), Err(Error::Config(_))));
+        }
+
+        test "refusals keep their code through io errors" {
+            let refusal = ResponseError::new(ErrorCode::OffsetOutOfRange, "offset 9 out of range 0..5");
+            let error = Error::from(io::Error::from(refusal.clone()));
+            assert_eq!(error.code(), Some(ErrorCode::OffsetOutOfRange));
+
+            let error = io::Error::from(Error::Broker(refusal));
+            assert_eq!(ErrorCode::for_error(&error), ErrorCode::OffsetOutOfRange);
+        }
+
+        test "other errors have no code" {
+            let error = Error::from(io::Error::new(io::ErrorKind::ConnectionReset, "reset"));
+            assert!(matches!(error, Error::Io(_)));
+            assert_eq!(error.code(), None);
+        }
+
+        test "broken connections are worth retrying, refusals mostly aren't" {
+            assert!(Error::from(io::Error::new(io::ErrorKind::ConnectionReset, "reset")).is_retriable());
+            assert!(Error::Broker(ResponseError::new(ErrorCode::CorruptMessage, "bad crc")).is_retriable());
+            assert!(!Error::Broker(ResponseError::new(ErrorCode::InvalidProducerEpoch, "fenced")).is_retriable());
+            assert!(!Error::TimedOut(String::from("too slow")).is_retriable());
+        }
+    }
+}
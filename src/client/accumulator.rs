@@ -0,0 +1,147 @@
+// Records wait in the accumulator until the sender thread sends them off,
+// in one batch per partition. A batch goes once it holds `batch_size`
+// bytes, once it waited `linger` for more, or straight away when the
+// producer flushes or closes. Batches of a partition leave in the order
+// they were started, so sequence numbers follow on.
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use crate::record::Record;
+use super::producer::Delivery;
+use super::Error;
+
+
+pub type TopicPartition = (String, u32);
+
+pub struct Batch {
+    pub records: Vec<Record>,
+    pub deliveries: Vec<Delivery>,
+    pub bytes: usize,
+    pub created: Instant,
+    // full, so records go to a new batch
+    sealed: bool,
+}
+
+#[derive(Default)]
+pub struct Accumulator {
+    batches: HashMap<TopicPartition, VecDeque<Batch>>,
+    // bytes of records not delivered yet
+    pub buffered: usize,
+    // batches the sender took but hasn't finished with
+    pub in_flight: usize,
+    // send everything now, for a producer flushing or closing
+    pub flushing: bool,
+    pub closed: bool,
+    // the first failure since the last flush
+    pub error: Option<Error>,
+}
+
+impl Accumulator {
+    /// Add `record` to the open batch of its partition, starting a new one
+    /// if it doesn't fit in `batch_size`. Returns whether a batch filled up.
+    pub fn append(&mut self, topic_partition: TopicPartition, record: Record, delivery: Delivery, batch_size: usize) -> bool {
+        let len = record.encoded_len();
+        let batches = self.batches.entry(topic_partition).or_default();
+        let mut filled = false;
+        match batches.back_mut() {
+            Some(batch) if !batch.sealed && batch.bytes + len > batch_size => {
+                batch.sealed = true;
+                filled = true;
+            },
+            _ => {},
+        }
+        if batches.back().is_none_or(|batch| batch.sealed) {
+            batches.push_back(Batch { records: vec![], deliveries: vec![], bytes: 0, created: Instant::now(), sealed: false });
+        }
+        let batch = batches.back_mut().unwrap();
+        batch.records.push(record);
+        batch.deliveries.push(delivery);
+        batch.bytes += len;
+        if batch.bytes >= batch_size {
+            batch.sealed = true;
+            filled = true;
+        }
+        self.buffered += len;
+        filled
+    }
+
+    /// Take the batches due to be sent, in order.
+    pub fn drain_ready(&mut self, now: Instant, linger: Duration) -> Vec<(TopicPartition, Batch)> {
+        let send_all = self.flushing || self.closed;
+        let mut ready = vec![];
+        for (topic_partition, batches) in self.batches.iter_mut() {
+            while let Some(batch) = batches.front() {
+                if !(send_all || batch.sealed || now >= batch.created + linger) {
+                    break
+                }
+                ready.push((topic_partition.clone(), batches.pop_front().unwrap()));
+            }
+        }
+        self.batches.retain(|_, batches| !batches.is_empty());
+        ready
+    }
+
+    /// When the oldest batch waiting out its linger is due.
+    pub fn next_deadline(&self, linger: Duration) -> Option<Instant> {
+        self.batches.values().filter_map(|batches| batches.front()).map(|batch| batch.created + linger).min()
+    }
+
+    /// Whether everything appended was delivered, or failed to be.
+    pub fn is_empty(&self) -> bool {
+        self.batches.is_empty() && self.in_flight == 0
+    }
+}
+
+
+#[cfg(test)]
+extern crate speculate;
+
+#[cfg(test)]
+mod tests {
+    use speculate::speculate;
+    use super::*;
+
+    speculate! {
+        before {
+            let mut accumulator = Accumulator::default();
+            let record = || Record::new(b"WOMBIEST".to_vec());
+            let len = record().encoded_len();
+            let wombats = || (String::from("wombats"), 0);
+        }
+
+        test "batches fill up to the batch size" {
+            assert!(!accumulator.append(wombats(), record(), Delivery::new(), len * 2));
+            assert!(accumulator.append(wombats(), record(), Delivery::new(), len * 2));
+            assert!(!accumulator.append(wombats(), record(), Delivery::new(), len * 2));
+            assert_eq!(accumulator.buffered, len * 3);
+
+            let ready = accumulator.drain_ready(Instant::now(), Duration::from_secs(60));
+            assert_eq!(ready.len(), 1, "the open batch lingers");
+            assert_eq!(ready[0].1.records.len(), 2);
+            assert!(!accumulator.is_empty());
+        }
+
+        test "a record too big for a batch gets one of its own" {
+            accumulator.append(wombats(), record(), Delivery::new(), len * 2);
+            let big = Record::new(vec![b'w'; len * 3]);
+            assert!(accumulator.append(wombats(), big, Delivery::new(), len * 2));
+            let ready = accumulator.drain_ready(Instant::now(), Duration::from_secs(60));
+            let sizes: Vec<usize> = ready.iter().map(|(_, batch)| batch.records.len()).collect();
+            assert_eq!(sizes, vec![1, 1]);
+        }
+
+        test "batches go once they lingered or on a flush" {
+            accumulator.append(wombats(), record(), Delivery::new(), len * 10);
+            let linger = Duration::from_millis(50);
+            assert!(accumulator.drain_ready(Instant::now(), linger).is_empty());
+            let deadline = accumulator.next_deadline(linger).unwrap();
+            assert!(deadline > Instant::now());
+            assert_eq!(accumulator.drain_ready(deadline, linger).len(), 1);
+            assert!(accumulator.next_deadline(linger).is_none());
+
+            accumulator.append(wombats(), record(), Delivery::new(), len * 10);
+            accumulator.flushing = true;
+            assert_eq!(accumulator.drain_ready(Instant::now(), linger).len(), 1);
+        }
+    }
+}
@@ -0,0 +1,98 @@
+use std::io::Write;
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+
+use bufstream::BufStream;
+
+use crate::protocol::{self, ApiKey, ApiVersion, Request, Response};
+use super::{Error, Result};
+
+
+/// A connection to the broker, sending each request at the newest version
+/// both sides support and waiting for its response. A connection that
+/// failed is opened again by the next request.
+pub struct Connection {
+    addresses: Vec<SocketAddr>,
+    // `None` once it failed
+    stream: Option<BufStream<TcpStream>>,
+    client_id: String,
+    correlation_id: i32,
+    // what the broker told us it supports
+    versions: Vec<ApiVersion>,
+}
+
+impl Connection {
+    /// Connect to the broker at `address` and ask what it supports.
+    pub fn open<A: ToSocketAddrs>(address: A, client_id: &str) -> Result<Connection> {
+        let mut connection = Connection {
+            addresses: address.to_socket_addrs()?.collect(),
+            stream: None,
+            client_id: client_id.to_string(),
+            correlation_id: 0,
+            versions: vec![],
+        };
+        connection.reconnect()?;
+        Ok(connection)
+    }
+
+    /// The version `api_key` requests are sent at.
+    pub fn version(&self, api_key: ApiKey) -> Result<i16> {
+        match api_key {
+            // the one request every broker understands at version 0
+            ApiKey::ApiVersions => Ok(0),
+            _ => Ok(protocol::negotiate_version(&self.versions, api_key)?),
+        }
+    }
+
+    /// Whether the connection is up, as far as we know.
+    pub fn is_connected(&self) -> bool {
+        self.stream.is_some()
+    }
+
+    /// Send `request` and wait for its response, connecting again first if
+    /// the connection failed. A produce the broker doesn't answer gets a
+    /// response without an offset.
+    pub fn send(&mut self, request: &Request) -> Result<Response> {
+        if self.stream.is_none() {
+            self.reconnect()?;
+        }
+        let result = self.exchange(request);
+        // after anything but a refusal it's anyone's guess where in the
+        // stream we are
+        if let Err(Error::Io(_)) | Err(Error::UnexpectedResponse(_)) = result {
+            self.stream = None;
+        }
+        result
+    }
+
+    fn reconnect(&mut self) -> Result<()> {
+        let tcp_stream = TcpStream::connect(&self.addresses[..])?;
+        // a request bigger than the buffer goes out in several writes,
+        // which mustn't wait for each other to be acknowledged
+        tcp_stream.set_nodelay(true)?;
+        self.stream = Some(BufStream::new(tcp_stream));
+        // the broker may have been upgraded or downgraded meanwhile
+        self.versions = match self.send(&Request::ApiVersions)? {
+            Response::ApiVersions { versions } => versions,
+            other => return Err(Error::unexpected(other)),
+        };
+        Ok(())
+    }
+
+    fn exchange(&mut self, request: &Request) -> Result<Response> {
+        let api_version = self.version(request.api_key())?;
+        let stream = self.stream.as_mut().unwrap();
+        self.correlation_id = self.correlation_id.wrapping_add(1);
+        protocol::write_request(stream, api_version, self.correlation_id, &self.client_id, request)?;
+        stream.flush()?;
+        if !request.expects_response() {
+            return Ok(Response::Produce { base_offset: None });
+        }
+        let (correlation_id, response) = protocol::read_response(stream, request.api_key(), api_version)?;
+        if correlation_id != self.correlation_id {
+            return Err(Error::UnexpectedResponse(format!(
+                "response to request {} while waiting for {}", correlation_id, self.correlation_id
+            )));
+        }
+        Ok(response?)
+    }
+}
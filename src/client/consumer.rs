@@ -0,0 +1,444 @@
+use std::collections::HashMap;
+use std::net::ToSocketAddrs;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::partition;
+use crate::protocol::{ApiKey, ErrorCode, IsolationLevel, Request, Response};
+use crate::record;
+use crate::segment::Offset;
+use super::{Connection, Error, Result};
+
+
+const FETCH_MAX_BYTES: u32 = 1 << 20;
+// pause before fetching again once caught up with a broker that can't wait
+const FETCH_BACKOFF_MS: u64 = 100;
+
+
+#[derive(Debug, Clone)]
+pub struct ConsumerConfig {
+    pub client_id: String,
+    pub isolation_level: IsolationLevel,
+    // how long the broker may hold a poll until there are records
+    pub max_wait_ms: u32,
+    // share subscribed topics with the other consumers of this group
+    pub group_id: Option<String>,
+    // how the group splits the partitions between its members
+    pub assignor: String,
+    // the group hands the partitions to the others after this long without a heartbeat
+    pub session_timeout_ms: u32,
+    // how often a group member commits how far it got
+    pub auto_commit_interval_ms: u64,
+    // times in a row a poll connects again after the connection failed
+    pub reconnects: u32,
+    // wait this long before the first reconnect, doubling up to the max
+    // after each one that fails
+    pub reconnect_backoff_ms: u64,
+    pub reconnect_backoff_max_ms: u64,
+}
+
+impl Default for ConsumerConfig {
+    fn default() -> ConsumerConfig {
+        ConsumerConfig {
+            client_id: String::from("latka-consumer"),
+            isolation_level: IsolationLevel::ReadUncommitted,
+            max_wait_ms: 500,
+            group_id: None,
+            assignor: String::from("range"),
+            session_timeout_ms: 10_000,
+            auto_commit_interval_ms: 1000,
+            reconnects: u32::MAX,
+            reconnect_backoff_ms: 100,
+            reconnect_backoff_max_ms: 1000,
+        }
+    }
+}
+
+
+/// Where to start consuming a partition.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StartAt {
+    Offset(Offset),
+    // the first record stamped at or after these epoch milliseconds
+    Timestamp(i64),
+}
+
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConsumerRecord {
+    pub topic: String,
+    pub partition: u32,
+    pub offset: Offset,
+    pub timestamp: i64,
+    pub key: Option<Vec<u8>>,
+    pub value: Option<Vec<u8>>,
+}
+
+
+// This consumer's membership of a group.
+struct Member {
+    group_id: String,
+    member_id: String,
+    generation_id: i32,
+    topics: Vec<String>,
+    // where partitions the group never committed start
+    start: StartAt,
+    // whether the group moved on and has to be joined again
+    rejoin: bool,
+    // what the group last committed for each partition
+    committed: HashMap<(String, u32), Offset>,
+    last_heartbeat: Instant,
+    last_commit: Instant,
+}
+
+
+pub struct Consumer {
+    connection: Connection,
+    config: ConsumerConfig,
+    // the next offset to fetch of each partition consumed
+    positions: HashMap<(String, u32), Offset>,
+    // the partitions consumed, in the order they are fetched
+    assigned: Vec<(String, u32)>,
+    member: Option<Member>,
+    // polls in a row that failed to reach the broker
+    failures: u32,
+}
+
+impl Consumer {
+    /// Connect to the broker at `address`.
+    pub fn connect<A: ToSocketAddrs>(address: A, config: ConsumerConfig) -> Result<Consumer> {
+        let connection = Connection::open(address, &config.client_id)?;
+        Ok(Consumer { connection, config, positions: HashMap::new(), assigned: vec![], member: None, failures: 0 })
+    }
+
+    /// Consume `partition` of `topic` from `start` on.
+    pub fn assign(&mut self, topic: &str, partition: u32, start: StartAt) -> Result<()> {
+        if self.member.is_some() {
+            return Err(Error::Config(String::from("a group member can't be assigned partitions")));
+        }
+        let offset = self.start_offset(topic, partition, start)?;
+        let topic_partition = (topic.to_string(), partition);
+        if !self.assigned.contains(&topic_partition) {
+            self.assigned.push(topic_partition.clone());
+        }
+        self.positions.insert(topic_partition, offset);
+        Ok(())
+    }
+
+    /// Consume the partitions of `topics` the group assigns this consumer,
+    /// resuming where the group committed it got to. Partitions the group
+    /// never committed start at `start`.
+    pub fn subscribe(&mut self, topics: &[&str], start: StartAt) -> Result<()> {
+        let group_id = match &self.config.group_id {
+            Some(group_id) => group_id.clone(),
+            None => return Err(Error::Config(String::from("subscribing needs a group id"))),
+        };
+        if !self.assigned.is_empty() {
+            return Err(Error::Config(String::from("partitions were assigned by hand already")));
+        }
+        self.member = Some(Member {
+            group_id,
+            member_id: String::new(),
+            generation_id: -1,
+            topics: topics.iter().map(|topic| topic.to_string()).collect(),
+            start,
+            rejoin: true,
+            committed: HashMap::new(),
+            last_heartbeat: Instant::now(),
+            last_commit: Instant::now(),
+        });
+        Ok(())
+    }
+
+    /// The partitions consumed.
+    pub fn assignment(&self) -> &[(String, u32)] {
+        &self.assigned
+    }
+
+    /// The next offset to fetch from `partition` of `topic`.
+    pub fn position(&self, topic: &str, partition: u32) -> Option<Offset> {
+        self.positions.get(&(topic.to_string(), partition)).copied()
+    }
+
+    /// Fetch the next records of every partition consumed, waiting up to
+    /// `max_wait_ms` for there to be any. Group members heartbeat, commit
+    /// what earlier polls returned and follow rebalances while polling.
+    /// A poll that loses the connection backs off and returns what it got,
+    /// and the next one connects again to carry on from the same positions,
+    /// until `reconnects` polls in a row failed.
+    pub fn poll(&mut self) -> Result<Vec<ConsumerRecord>> {
+        let mut records = vec![];
+        match self.poll_into(&mut records) {
+            Ok(()) => self.failures = 0,
+            Err(Error::Io(_)) if self.failures < self.config.reconnects => {
+                thread::sleep(self.reconnect_backoff());
+                self.failures += 1;
+            },
+            Err(e) => return Err(e),
+        }
+        Ok(records)
+    }
+
+    /// Commit the positions of a group member that moved since its last
+    /// commit.
+    pub fn commit(&mut self) -> Result<()> {
+        let member = match &mut self.member {
+            Some(member) if !member.member_id.is_empty() => member,
+            _ => return Ok(()),
+        };
+        let offsets: Vec<(String, u32, Offset)> = self.positions.iter()
+            .filter(|&(topic_partition, offset)| member.committed.get(topic_partition) != Some(offset))
+            .map(|((topic, partition), &offset)| (topic.clone(), *partition, offset))
+            .collect();
+        if offsets.is_empty() {
+            return Ok(());
+        }
+        let request = Request::OffsetCommit {
+            group_id: member.group_id.clone(),
+            generation_id: member.generation_id,
+            member_id: member.member_id.clone(),
+            offsets: offsets.clone(),
+        };
+        match self.connection.send(&request)? {
+            Response::OffsetCommit => {},
+            other => return Err(Error::unexpected(other)),
+        }
+        member.committed.extend(offsets.into_iter().map(|(topic, partition, offset)| ((topic, partition), offset)));
+        member.last_commit = Instant::now();
+        Ok(())
+    }
+
+    /// Leave the group, which hands this member's partitions to the others
+    /// straight away.
+    pub fn close(mut self) -> Result<()> {
+        let member = match self.member.take() {
+            Some(member) if !member.member_id.is_empty() => member,
+            _ => return Ok(()),
+        };
+        let request = Request::LeaveGroup { group_id: member.group_id, member_id: member.member_id };
+        match self.connection.send(&request)? {
+            Response::LeaveGroup => Ok(()),
+            other => Err(Error::unexpected(other)),
+        }
+    }
+
+    fn poll_into(&mut self, records: &mut Vec<ConsumerRecord>) -> Result<()> {
+        if self.member.is_some() {
+            self.keep_membership()?;
+        }
+        if self.assigned.is_empty() {
+            thread::sleep(Duration::from_millis(self.config.max_wait_ms as u64));
+            return Ok(());
+        }
+        // every partition gets its share of the wait, so a quiet one
+        // doesn't hold up the others for long
+        let max_wait_ms = self.config.max_wait_ms / self.assigned.len() as u32;
+        let mut fetched = false;
+        for (topic, partition) in self.assigned.clone() {
+            fetched |= self.fetch(&topic, partition, max_wait_ms, records)?;
+        }
+        if !fetched {
+            thread::sleep(self.backoff()?);
+        }
+        Ok(())
+    }
+
+    // Join the group again if it rebalanced, otherwise commit and heartbeat
+    // when they're due.
+    fn keep_membership(&mut self) -> Result<()> {
+        let (commit_due, heartbeat_due) = match &self.member {
+            Some(member) if member.rejoin => return self.join(),
+            Some(member) => (
+                member.last_commit.elapsed() >= Duration::from_millis(self.config.auto_commit_interval_ms),
+                member.last_heartbeat.elapsed() >= Duration::from_millis(self.config.session_timeout_ms as u64 / 3),
+            ),
+            None => return Ok(()),
+        };
+        if commit_due {
+            match self.commit() {
+                Ok(()) => {},
+                Err(ref e) if must_rejoin(e) => return self.rejoin(),
+                Err(e) => return Err(e),
+            }
+        }
+        if !heartbeat_due {
+            return Ok(());
+        }
+        let member = self.member.as_mut().unwrap();
+        let request = Request::Heartbeat {
+            group_id: member.group_id.clone(),
+            generation_id: member.generation_id,
+            member_id: member.member_id.clone(),
+        };
+        match self.connection.send(&request) {
+            Ok(_) => {
+                member.last_heartbeat = Instant::now();
+                Ok(())
+            },
+            Err(ref e) if must_rejoin(e) => {
+                // hand the partitions over where this member got to,
+                // which the group still takes while it rebalances
+                match self.commit() {
+                    Ok(()) => self.rejoin(),
+                    Err(ref e) if must_rejoin(e) => self.rejoin(),
+                    Err(e) => Err(e),
+                }
+            },
+            Err(e) => Err(e),
+        }
+    }
+
+    fn rejoin(&mut self) -> Result<()> {
+        if let Some(member) = &mut self.member {
+            member.rejoin = true;
+        }
+        self.join()
+    }
+
+    // Join the group and take over the partitions it assigns, from where
+    // the group last committed.
+    fn join(&mut self) -> Result<()> {
+        let member = self.member.as_mut().unwrap();
+        let assigned = loop {
+            let request = Request::JoinGroup {
+                group_id: member.group_id.clone(),
+                member_id: member.member_id.clone(),
+                session_timeout_ms: self.config.session_timeout_ms,
+                topics: member.topics.clone(),
+                assignors: vec![self.config.assignor.clone()],
+            };
+            match self.connection.send(&request) {
+                Ok(Response::JoinGroup { generation_id, member_id, .. }) => {
+                    member.generation_id = generation_id;
+                    member.member_id = member_id;
+                },
+                Ok(other) => return Err(Error::unexpected(other)),
+                // the group forgot this member, say when the broker restarted
+                Err(ref e) if e.code() == Some(ErrorCode::UnknownMemberId) => {
+                    member.member_id.clear();
+                    continue
+                },
+                Err(e) => return Err(e),
+            }
+            let request = Request::SyncGroup {
+                group_id: member.group_id.clone(),
+                generation_id: member.generation_id,
+                member_id: member.member_id.clone(),
+            };
+            match self.connection.send(&request) {
+                Ok(Response::SyncGroup { partitions }) => break partitions,
+                Ok(other) => return Err(Error::unexpected(other)),
+                // the group rebalanced again meanwhile
+                Err(ref e) if must_rejoin(e) => continue,
+                Err(e) => return Err(e),
+            }
+        };
+        // another member may have moved on with any partition since, but
+        // where this member got to counts too if the group couldn't take its
+        // last commit, say because the broker restarted and forgot it
+        let consumed = std::mem::take(&mut self.positions);
+        member.committed.clear();
+        let request = Request::OffsetFetch { group_id: member.group_id.clone(), partitions: assigned.clone() };
+        match self.connection.send(&request)? {
+            Response::OffsetFetch { offsets } => {
+                for (topic, partition, offset) in offsets {
+                    let topic_partition = (topic, partition);
+                    if let Some(offset) = offset {
+                        member.committed.insert(topic_partition.clone(), offset);
+                    }
+                    if let Some(offset) = offset.max(consumed.get(&topic_partition).copied()) {
+                        self.positions.insert(topic_partition, offset);
+                    }
+                }
+            },
+            other => return Err(Error::unexpected(other)),
+        }
+        let start = member.start;
+        member.rejoin = false;
+        member.last_heartbeat = Instant::now();
+        member.last_commit = Instant::now();
+        for (topic, partition) in &assigned {
+            if !self.positions.contains_key(&(topic.clone(), *partition)) {
+                let offset = self.start_offset(topic, *partition, start)?;
+                self.positions.insert((topic.clone(), *partition), offset);
+            }
+        }
+        self.assigned = assigned;
+        Ok(())
+    }
+
+    fn start_offset(&mut self, topic: &str, partition: u32, start: StartAt) -> Result<Offset> {
+        let timestamp = match start {
+            StartAt::Offset(offset) => return Ok(offset),
+            StartAt::Timestamp(timestamp) => timestamp,
+        };
+        let request = Request::ListOffsets { topic: topic.to_string(), partition, timestamp };
+        match self.connection.send(&request)? {
+            Response::ListOffsets { offset } => Ok(offset),
+            other => Err(Error::unexpected(other)),
+        }
+    }
+
+    // Add the records of `partition` from its position on, returning
+    // whether the broker had any.
+    fn fetch(&mut self, topic: &str, partition: u32, max_wait_ms: u32, records: &mut Vec<ConsumerRecord>) -> Result<bool> {
+        let topic_partition = (topic.to_string(), partition);
+        let position = self.positions[&topic_partition];
+        let request = Request::Fetch {
+            topic: topic.to_string(),
+            partition,
+            offset: position,
+            max_bytes: FETCH_MAX_BYTES,
+            isolation_level: self.config.isolation_level,
+            max_wait_ms,
+            min_bytes: 1,
+        };
+        let (mut fetched, aborted) = match self.connection.send(&request)? {
+            Response::Fetch { records, aborted, .. } => (record::decompress_all(records)?, aborted),
+            other => return Err(Error::unexpected(other)),
+        };
+        // compressed batches come whole, even if the position is inside one
+        fetched.retain(|record| record.offset >= position);
+        let next_offset = match fetched.last() {
+            Some(record) => record.offset + 1,
+            None => return Ok(false),
+        };
+        // transaction markers are never returned, aborted records only
+        // when reading uncommitted
+        records.extend(partition::committed(fetched, &aborted).into_iter().map(|record| ConsumerRecord {
+            topic: topic.to_string(),
+            partition,
+            offset: record.offset,
+            timestamp: record.timestamp,
+            key: record.key,
+            value: record.value,
+        }));
+        self.positions.insert(topic_partition, next_offset);
+        Ok(true)
+    }
+
+    // How long to pause after an empty poll, a broker that can't wait
+    // for records answers right away.
+    fn backoff(&self) -> Result<Duration> {
+        match self.connection.version(ApiKey::Fetch)? {
+            0 | 1 => Ok(Duration::from_millis(FETCH_BACKOFF_MS)),
+            _ => Ok(Duration::from_millis(0)),
+        }
+    }
+
+    // How long to pause before connecting again, longer the more polls
+    // in a row failed.
+    fn reconnect_backoff(&self) -> Duration {
+        let backoff_ms = self.config.reconnect_backoff_ms.saturating_mul(2u64.saturating_pow(self.failures));
+        Duration::from_millis(backoff_ms.min(self.config.reconnect_backoff_max_ms))
+    }
+}
+
+// The group moved on without this member, which has to join it again,
+// as a new member if the group forgot it.
+fn must_rejoin(error: &Error) -> bool {
+    matches!(
+        error.code(),
+        Some(ErrorCode::RebalanceInProgress) | Some(ErrorCode::IllegalGeneration) | Some(ErrorCode::UnknownMemberId)
+    )
+}
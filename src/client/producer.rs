@@ -0,0 +1,525 @@
+// Producers hand records to a sender thread, which sends them in batches
+// so shipping lots of small records doesn't take a request each. Sending
+// blocks while the records waiting to be sent take up `buffer_memory`.
+// A batch that failed in a way that may pass, like the broker restarting,
+// is sent again after a growing backoff until it runs out of retries or
+// of its `delivery_timeout_ms`. Resending may write records twice unless
+// the producer is idempotent. Batches are numbered and compressed on the
+// sender thread, and a batch that failed for good leaves a gap in the
+// numbers that an idempotent producer gets past with a new producer id.
+use std::collections::{HashMap, HashSet};
+use std::net::ToSocketAddrs;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::compression::Compression;
+use crate::partitioner::{DefaultPartitioner, Partitioner};
+use crate::protocol::{Acks, Request, Response};
+use crate::record::{self, Record};
+use crate::segment::Offset;
+use super::accumulator::{Accumulator, Batch, TopicPartition};
+use super::{Connection, Error, Result};
+
+
+#[derive(Debug, Clone)]
+pub struct ProducerConfig {
+    pub client_id: String,
+    // what the broker does before answering a produce
+    pub acks: Acks,
+    // number records so the broker writes a resent one only once
+    pub idempotent: bool,
+    // write in transactions, fencing off older producers with the same id
+    pub transactional_id: Option<String>,
+    // send a partition's batch once its records take up this many bytes
+    pub batch_size: usize,
+    // or once it waited this long for more records
+    pub linger_ms: u64,
+    // block sending once records waiting to be sent take up this many bytes
+    pub buffer_memory: usize,
+    // compress each batch with this codec
+    pub compression: Compression,
+    // times a failed batch is sent again
+    pub retries: u32,
+    // wait this long before the first retry, doubling up to the max after
+    // each one
+    pub retry_backoff_ms: u64,
+    pub retry_backoff_max_ms: u64,
+    // fail records not delivered this long after they were queued
+    pub delivery_timeout_ms: u64,
+}
+
+impl Default for ProducerConfig {
+    fn default() -> ProducerConfig {
+        ProducerConfig {
+            client_id: String::from("latka-producer"),
+            acks: Acks::Leader,
+            idempotent: false,
+            transactional_id: None,
+            batch_size: 16 << 10,
+            linger_ms: 0,
+            buffer_memory: 32 << 20,
+            compression: Compression::None,
+            retries: u32::MAX,
+            retry_backoff_ms: 100,
+            retry_backoff_max_ms: 1000,
+            delivery_timeout_ms: 120_000,
+        }
+    }
+}
+
+
+/// A record to send, to `partition` or wherever the partitioner puts it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProducerRecord {
+    pub topic: String,
+    pub partition: Option<u32>,
+    pub key: Option<Vec<u8>>,
+    // `None` is a tombstone, deleting the key from compacted topics
+    pub value: Option<Vec<u8>>,
+}
+
+impl ProducerRecord {
+    pub fn new<T: Into<String>, V: Into<Vec<u8>>>(topic: T, value: V) -> ProducerRecord {
+        ProducerRecord { topic: topic.into(), partition: None, key: None, value: Some(value.into()) }
+    }
+
+    pub fn with_key<T: Into<String>, K: Into<Vec<u8>>>(topic: T, key: K, value: Option<Vec<u8>>) -> ProducerRecord {
+        ProducerRecord { topic: topic.into(), partition: None, key: Some(key.into()), value }
+    }
+
+    pub fn partition(mut self, partition: u32) -> ProducerRecord {
+        self.partition = Some(partition);
+        self
+    }
+}
+
+
+/// Where a record was written, without an offset if the broker wasn't
+/// asked to answer.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecordMetadata {
+    pub topic: String,
+    pub partition: u32,
+    pub offset: Option<Offset>,
+}
+
+
+type Callback = Box<dyn FnOnce(Result<RecordMetadata>) + Send>;
+
+#[derive(Default)]
+struct Outcome {
+    result: Option<Result<RecordMetadata>>,
+    // called on the sender thread once the result is known
+    callback: Option<Callback>,
+}
+
+/// The outcome of sending a record, known once its batch was delivered or
+/// failed for good.
+#[derive(Clone)]
+pub struct Delivery {
+    outcome: Arc<(Mutex<Outcome>, Condvar)>,
+}
+
+impl Delivery {
+    pub(crate) fn new() -> Delivery {
+        Delivery { outcome: Arc::new((Mutex::new(Outcome::default()), Condvar::new())) }
+    }
+
+    fn with_callback(callback: Callback) -> Delivery {
+        let delivery = Delivery::new();
+        delivery.outcome.0.lock().unwrap().callback = Some(callback);
+        delivery
+    }
+
+    /// Whether the record was delivered, or failed to be.
+    pub fn is_done(&self) -> bool {
+        self.outcome.0.lock().unwrap().result.is_some()
+    }
+
+    /// Wait until the record was delivered, or failed to be. Every clone
+    /// of a delivery gets the same result.
+    pub fn wait(self) -> Result<RecordMetadata> {
+        let (outcome, done) = &*self.outcome;
+        let mut outcome = outcome.lock().unwrap();
+        loop {
+            match &outcome.result {
+                Some(result) => return result.clone(),
+                None => outcome = done.wait(outcome).unwrap(),
+            }
+        }
+    }
+
+    fn complete(&self, result: Result<RecordMetadata>) {
+        let (outcome, done) = &*self.outcome;
+        let mut outcome = outcome.lock().unwrap();
+        let callback = outcome.callback.take();
+        outcome.result = Some(result.clone());
+        drop(outcome);
+        done.notify_all();
+        if let Some(callback) = callback {
+            callback(result);
+        }
+    }
+}
+
+
+// What the producer and its sender thread share.
+#[derive(Default)]
+struct Shared {
+    accumulator: Mutex<Accumulator>,
+    // something was appended, sent or asked for
+    changed: Condvar,
+    sequences: Mutex<Sequences>,
+}
+
+// How the batches of an idempotent or transactional producer are numbered,
+// as the sender thread sends them.
+#[derive(Default)]
+struct Sequences {
+    // (producer id, epoch)
+    producer: Option<(i64, i16)>,
+    // the next sequence number of each partition
+    next: HashMap<TopicPartition, i32>,
+    // why a batch failed for good, leaving a gap in its partition's
+    // sequence numbers the broker won't get past
+    failed: Option<Error>,
+}
+
+
+pub struct Producer {
+    connection: Arc<Mutex<Connection>>,
+    config: ProducerConfig,
+    shared: Arc<Shared>,
+    sender: Option<JoinHandle<()>>,
+    partitioner: Box<dyn Partitioner>,
+    // partition count of each topic produced to
+    partitions: HashMap<String, u32>,
+    // partitions written to in the ongoing transaction
+    in_transaction: HashSet<TopicPartition>,
+}
+
+impl Producer {
+    /// Connect to the broker at `address`, getting a producer id from it if
+    /// the producer is idempotent or transactional.
+    pub fn connect<A: ToSocketAddrs>(address: A, config: ProducerConfig) -> Result<Producer> {
+        if config.transactional_id.is_some() && config.acks == Acks::None {
+            return Err(Error::Config(String::from("transactions need acks 1 or all")));
+        }
+        let mut connection = Connection::open(address, &config.client_id)?;
+        let producer = match config.idempotent || config.transactional_id.is_some() {
+            true => Some(init_producer_id(&mut connection, config.transactional_id.clone())?),
+            false => None,
+        };
+        let connection = Arc::new(Mutex::new(connection));
+        let shared = Arc::new(Shared::default());
+        shared.sequences.lock().unwrap().producer = producer;
+        let sender = {
+            let (connection, shared, config) = (connection.clone(), shared.clone(), config.clone());
+            thread::spawn(move || send_batches(&connection, &shared, &config))
+        };
+        Ok(Producer {
+            connection,
+            config,
+            shared,
+            sender: Some(sender),
+            partitioner: Box::new(DefaultPartitioner::new()),
+            partitions: HashMap::new(),
+            in_transaction: HashSet::new(),
+        })
+    }
+
+    /// Place records without a partition with `partitioner` instead.
+    pub fn with_partitioner(mut self, partitioner: Box<dyn Partitioner>) -> Producer {
+        self.partitioner = partitioner;
+        self
+    }
+
+    /// Queue `record` to be sent, as part of the ongoing transaction of a
+    /// transactional producer, waiting for room if the buffer is full.
+    pub fn send(&mut self, record: ProducerRecord) -> Result<Delivery> {
+        self.enqueue(record, Delivery::new())
+    }
+
+    /// Queue `record` like `send`, calling `callback` on the sender thread
+    /// once it was delivered or failed. It isn't called if queueing fails.
+    pub fn send_with_callback<F>(&mut self, record: ProducerRecord, callback: F) -> Result<Delivery>
+    where
+        F: FnOnce(Result<RecordMetadata>) + Send + 'static
+    {
+        self.enqueue(record, Delivery::with_callback(Box::new(callback)))
+    }
+
+    fn enqueue(&mut self, record: ProducerRecord, delivery: Delivery) -> Result<Delivery> {
+        let partition = match record.partition {
+            Some(partition) => partition,
+            None => {
+                let partitions = self.partitions(&record.topic)?;
+                self.partitioner.partition(record.key.as_deref(), partitions)
+            },
+        };
+        let mut encoded = Record { key: record.key, value: record.value, ..Record::new(vec![]) };
+        let len = encoded.encoded_len();
+        if len > self.config.buffer_memory {
+            return Err(Error::Config(format!("a record of {} bytes doesn't fit in the buffer", len)));
+        }
+        let topic_partition = (record.topic, partition);
+        let producer = self.shared.sequences.lock().unwrap().producer;
+        if let (Some(transactional_id), Some((producer_id, producer_epoch))) = (&self.config.transactional_id, producer) {
+            if !self.in_transaction.contains(&topic_partition) {
+                let request = Request::AddPartitionsToTxn {
+                    transactional_id: transactional_id.clone(),
+                    producer_id,
+                    producer_epoch,
+                    partitions: vec![topic_partition.clone()],
+                };
+                match self.connection.lock().unwrap().send(&request)? {
+                    Response::AddPartitionsToTxn => self.in_transaction.insert(topic_partition.clone()),
+                    other => return Err(Error::unexpected(other)),
+                };
+            }
+            encoded.attributes |= record::TRANSACTIONAL;
+        }
+
+        let mut accumulator = self.shared.accumulator.lock().unwrap();
+        while accumulator.buffered + len > self.config.buffer_memory {
+            accumulator = self.shared.changed.wait(accumulator).unwrap();
+        }
+        let filled = accumulator.append(topic_partition, encoded, delivery.clone(), self.config.batch_size);
+        drop(accumulator);
+        self.shared.changed.notify_all();
+        if filled {
+            self.partitioner.on_new_batch();
+        }
+        Ok(delivery)
+    }
+
+    /// Send everything queued right away and wait until it was delivered,
+    /// returning the first failure since the last flush.
+    pub fn flush(&mut self) -> Result<()> {
+        let mut accumulator = self.shared.accumulator.lock().unwrap();
+        accumulator.flushing = true;
+        self.shared.changed.notify_all();
+        while !accumulator.is_empty() {
+            accumulator = self.shared.changed.wait(accumulator).unwrap();
+        }
+        accumulator.flushing = false;
+        let error = accumulator.error.take();
+        drop(accumulator);
+        self.partitioner.on_new_batch();
+        error.map_or(Ok(()), Err)
+    }
+
+    /// Deliver everything queued and stop the sender.
+    pub fn close(mut self) -> Result<()> {
+        self.flush()
+    }
+
+    /// Deliver the ongoing transaction and commit it, if it wrote anything.
+    pub fn commit_transaction(&mut self) -> Result<()> {
+        self.end_transaction(true)
+    }
+
+    /// Abort the ongoing transaction, if it wrote anything.
+    pub fn abort_transaction(&mut self) -> Result<()> {
+        self.end_transaction(false)
+    }
+
+    fn end_transaction(&mut self, commit: bool) -> Result<()> {
+        let producer = self.shared.sequences.lock().unwrap().producer;
+        let (transactional_id, (producer_id, producer_epoch)) = match (&self.config.transactional_id, producer) {
+            (Some(transactional_id), Some(producer)) => (transactional_id.clone(), producer),
+            _ => return Err(Error::Config(String::from("not a transactional producer"))),
+        };
+        // the markers must come after every record of the transaction
+        let flushed = self.flush();
+        let failed = self.shared.sequences.lock().unwrap().failed.clone();
+        if commit {
+            flushed?;
+            // records after the failed batch were turned down, so only an
+            // abort ends the transaction
+            if let Some(error) = failed {
+                return Err(error);
+            }
+        }
+        if !self.in_transaction.is_empty() {
+            // a transaction that failed to end is still open and can be ended again
+            let request = Request::EndTxn { transactional_id: transactional_id.clone(), producer_id, producer_epoch, commit };
+            match self.connection.lock().unwrap().send(&request)? {
+                Response::EndTxn => self.in_transaction.clear(),
+                other => return Err(Error::unexpected(other)),
+            }
+        }
+        // the next epoch starts every partition's sequence numbers over
+        if failed.is_some() {
+            let producer = init_producer_id(&mut self.connection.lock().unwrap(), Some(transactional_id))?;
+            let mut sequences = self.shared.sequences.lock().unwrap();
+            *sequences = Sequences { producer: Some(producer), ..Sequences::default() };
+        }
+        Ok(())
+    }
+
+    fn partitions(&mut self, topic: &str) -> Result<u32> {
+        if let Some(&partitions) = self.partitions.get(topic) {
+            return Ok(partitions);
+        }
+        let request = Request::Metadata { topic: topic.to_string() };
+        let partitions = match self.connection.lock().unwrap().send(&request)? {
+            Response::Metadata { partitions } => partitions,
+            other => return Err(Error::unexpected(other)),
+        };
+        self.partitions.insert(topic.to_string(), partitions);
+        Ok(partitions)
+    }
+}
+
+impl Drop for Producer {
+    // what's still queued is sent before the sender stops
+    fn drop(&mut self) {
+        self.shared.accumulator.lock().unwrap().closed = true;
+        self.shared.changed.notify_all();
+        if let Some(sender) = self.sender.take() {
+            let _ = sender.join();
+        }
+    }
+}
+
+
+// The sender thread, sending batches as they're due until the producer
+// closes and everything was sent.
+fn send_batches(connection: &Mutex<Connection>, shared: &Shared, config: &ProducerConfig) {
+    let linger = Duration::from_millis(config.linger_ms);
+    loop {
+        let ready = {
+            let mut accumulator = shared.accumulator.lock().unwrap();
+            loop {
+                let now = Instant::now();
+                let ready = accumulator.drain_ready(now, linger);
+                if !ready.is_empty() {
+                    accumulator.in_flight += ready.len();
+                    break ready;
+                }
+                if accumulator.closed && accumulator.is_empty() {
+                    return;
+                }
+                accumulator = match accumulator.next_deadline(linger) {
+                    Some(deadline) => shared.changed.wait_timeout(accumulator, deadline.saturating_duration_since(now)).unwrap().0,
+                    None => shared.changed.wait(accumulator).unwrap(),
+                };
+            }
+        };
+        for (topic_partition, batch) in ready {
+            let Batch { records, deliveries, bytes, created, .. } = batch;
+            let result = number(connection, &shared.sequences, config, &topic_partition, records)
+                .and_then(|records| compress(records, config.compression))
+                .and_then(|records| {
+                    let request = Request::Produce { topic: topic_partition.0.clone(), partition: topic_partition.1, acks: config.acks, records };
+                    produce(connection, config, &request, created)
+                });
+            if let Err(e) = &result {
+                let mut sequences = shared.sequences.lock().unwrap();
+                if sequences.producer.is_some() && sequences.failed.is_none() {
+                    sequences.failed = Some(e.clone());
+                }
+            }
+            let (topic, partition) = topic_partition;
+            for (i, delivery) in deliveries.iter().enumerate() {
+                delivery.complete(match &result {
+                    Ok(base_offset) => Ok(RecordMetadata {
+                        topic: topic.clone(),
+                        partition,
+                        offset: base_offset.map(|offset| offset + i as Offset),
+                    }),
+                    Err(e) => Err(e.clone()),
+                });
+            }
+            let mut accumulator = shared.accumulator.lock().unwrap();
+            accumulator.buffered -= bytes;
+            accumulator.in_flight -= 1;
+            if let (Err(e), None) = (result, &accumulator.error) {
+                accumulator.error = Some(e);
+            }
+            drop(accumulator);
+            shared.changed.notify_all();
+        }
+    }
+}
+
+// Stamp the records of a batch with the producer id and the sequence numbers
+// following the previous batch of the partition. After a batch failed for
+// good an idempotent producer first gets a new producer id, which starts
+// every partition over, a transactional one waits for the abort to do that.
+fn number(
+    connection: &Mutex<Connection>, sequences: &Mutex<Sequences>, config: &ProducerConfig,
+    topic_partition: &TopicPartition, mut records: Vec<Record>
+) -> Result<Vec<Record>> {
+    let mut sequences = sequences.lock().unwrap();
+    if sequences.failed.is_some() && config.transactional_id.is_none() {
+        let producer = init_producer_id(&mut connection.lock().unwrap(), None)?;
+        *sequences = Sequences { producer: Some(producer), ..Sequences::default() };
+    }
+    let (producer_id, producer_epoch) = match sequences.producer {
+        Some(producer) => producer,
+        None => return Ok(records),
+    };
+    let sequence = sequences.next.entry(topic_partition.clone()).or_insert(0);
+    for record in &mut records {
+        record.producer_id = producer_id;
+        record.producer_epoch = producer_epoch;
+        record.sequence = *sequence;
+        *sequence = sequence.wrapping_add(1);
+    }
+    Ok(records)
+}
+
+fn init_producer_id(connection: &mut Connection, transactional_id: Option<String>) -> Result<(i64, i16)> {
+    match connection.send(&Request::InitProducerId { transactional_id })? {
+        Response::InitProducerId { producer_id, producer_epoch } => Ok((producer_id, producer_epoch)),
+        other => Err(Error::unexpected(other)),
+    }
+}
+
+// The records of a batch as they're sent, numbered from 0 on and wrapped
+// up in one compressed record unless the codec is none.
+fn compress(mut records: Vec<Record>, compression: Compression) -> Result<Vec<Record>> {
+    if compression == Compression::None {
+        return Ok(records);
+    }
+    for (record, offset) in records.iter_mut().zip(0..) {
+        record.offset = offset;
+    }
+    Ok(vec![Record::compress(&records, compression)?])
+}
+
+// Send a batch queued at `created`, again after failures that may pass,
+// backing off longer each time.
+fn produce(connection: &Mutex<Connection>, config: &ProducerConfig, request: &Request, created: Instant) -> Result<Option<Offset>> {
+    let deadline = created + Duration::from_millis(config.delivery_timeout_ms);
+    let mut backoff = Duration::from_millis(config.retry_backoff_ms);
+    let mut retries = 0;
+    let mut last_error = None;
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            let mut message = format!("not delivered within {} ms", config.delivery_timeout_ms);
+            if let Some(error) = last_error {
+                message += &format!(", last failing with: {}", error);
+            }
+            return Err(Error::TimedOut(message));
+        }
+        // the lock isn't held while backing off, so the producer can still
+        // ask for metadata meanwhile
+        let error = match connection.lock().unwrap().send(request) {
+            Ok(Response::Produce { base_offset }) => return Ok(base_offset),
+            Ok(other) => return Err(Error::unexpected(other)),
+            Err(e) => e,
+        };
+        if !error.is_retriable() || retries == config.retries {
+            return Err(error);
+        }
+        thread::sleep(backoff.min(deadline.saturating_duration_since(Instant::now())));
+        backoff = (backoff * 2).min(Duration::from_millis(config.retry_backoff_max_ms));
+        retries += 1;
+        last_error = Some(error);
+    }
+}
@@ -0,0 +1,161 @@
+// Records can be compressed a batch at a time: the records are framed back
+// to back as usual, compressed together and stored as the value of a single
+// wrapper record, whose attributes name the codec. The codec ids are those
+// of the Java client. Snappy and lz4 use their standard frame formats.
+use std::io::{self, Read, Write, Error, ErrorKind};
+
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+
+use crate::protocol;
+use crate::record;
+
+
+// a batch never holds more than would have fit in a request uncompressed
+pub const MAX_DECOMPRESSED_SIZE: usize = protocol::MAX_FRAME_SIZE;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Compression {
+    #[default]
+    None = 0,
+    Gzip = 1,
+    Snappy = 2,
+    Lz4 = 3,
+    Zstd = 4,
+}
+
+impl Compression {
+    /// The codec named by the attributes of a record.
+    pub fn from_attributes(attributes: u8) -> io::Result<Compression> {
+        match attributes & record::COMPRESSION {
+            0 => Ok(Compression::None),
+            1 => Ok(Compression::Gzip),
+            2 => Ok(Compression::Snappy),
+            3 => Ok(Compression::Lz4),
+            4 => Ok(Compression::Zstd),
+            other => Err(Error::new(ErrorKind::InvalidData, format!("unknown compression codec {}", other))),
+        }
+    }
+
+    pub fn attributes(self) -> u8 {
+        self as u8
+    }
+
+    pub fn parse(name: &str) -> Option<Compression> {
+        match name {
+            "none" | "uncompressed" => Some(Compression::None),
+            "gzip" => Some(Compression::Gzip),
+            "snappy" => Some(Compression::Snappy),
+            "lz4" => Some(Compression::Lz4),
+            "zstd" => Some(Compression::Zstd),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Compression::None => "none",
+            Compression::Gzip => "gzip",
+            Compression::Snappy => "snappy",
+            Compression::Lz4 => "lz4",
+            Compression::Zstd => "zstd",
+        }
+    }
+
+    pub fn compress(self, bytes: &[u8]) -> io::Result<Vec<u8>> {
+        let compressed = Vec::with_capacity(bytes.len() / 2);
+        match self {
+            Compression::None => Ok(bytes.to_vec()),
+            Compression::Gzip => {
+                let mut encoder = GzEncoder::new(compressed, flate2::Compression::default());
+                encoder.write_all(bytes)?;
+                encoder.finish()
+            },
+            Compression::Snappy => {
+                let mut encoder = snap::write::FrameEncoder::new(compressed);
+                encoder.write_all(bytes)?;
+                encoder.into_inner().map_err(|e| e.into_error())
+            },
+            Compression::Lz4 => {
+                let mut encoder = lz4_flex::frame::FrameEncoder::new(compressed);
+                encoder.write_all(bytes)?;
+                encoder.finish().map_err(io::Error::other)
+            },
+            Compression::Zstd => zstd::encode_all(bytes, zstd::DEFAULT_COMPRESSION_LEVEL),
+        }
+    }
+
+    /// Decompress `bytes`, refusing to produce more than
+    /// `MAX_DECOMPRESSED_SIZE` bytes of them.
+    pub fn decompress(self, bytes: &[u8]) -> io::Result<Vec<u8>> {
+        let mut decompressed = Vec::with_capacity((bytes.len() * 2).min(MAX_DECOMPRESSED_SIZE));
+        // one byte more tells a batch at the limit from one beyond it
+        let limit = MAX_DECOMPRESSED_SIZE as u64 + 1;
+        match self {
+            Compression::None => decompressed.extend_from_slice(bytes),
+            Compression::Gzip => { GzDecoder::new(bytes).take(limit).read_to_end(&mut decompressed)?; },
+            Compression::Snappy => { snap::read::FrameDecoder::new(bytes).take(limit).read_to_end(&mut decompressed)?; },
+            Compression::Lz4 => { lz4_flex::frame::FrameDecoder::new(bytes).take(limit).read_to_end(&mut decompressed)?; },
+            Compression::Zstd => { zstd::Decoder::new(bytes)?.take(limit).read_to_end(&mut decompressed)?; },
+        }
+        if decompressed.len() > MAX_DECOMPRESSED_SIZE {
+            return Err(Error::new(
+                ErrorKind::InvalidData, format!("batch decompresses to more than {} bytes", MAX_DECOMPRESSED_SIZE)
+            ));
+        }
+        Ok(decompressed)
+    }
+}
+
+
+#[cfg(test)]
+extern crate speculate;
+
+#[cfg(test)]
+mod tests {
+    use speculate::speculate;
+    use super::*;
+
+    speculate! {
+        before {
+            let codecs = [Compression::None, Compression::Gzip, Compression::Snappy, Compression::Lz4, Compression::Zstd];
+        }
+
+        test "every codec round trips" {
+            let bytes = b"WOMBIEST WOODBINE ".repeat(100);
+            for codec in codecs {
+                let compressed = codec.compress(&bytes).unwrap();
+                if codec != Compression::None {
+                    assert!(compressed.len() < bytes.len() / 4, "{} compresses", codec.name());
+                }
+                assert_eq!(codec.decompress(&compressed).unwrap(), bytes);
+            }
+        }
+
+        test "codecs go by their attribute bits and names" {
+            for codec in codecs {
+                assert_eq!(Compression::from_attributes(0x10 | codec.attributes()).unwrap(), codec);
+                assert_eq!(Compression::parse(codec.name()), Some(codec));
+            }
+            assert_eq!(Compression::from_attributes(5).unwrap_err().kind(), ErrorKind::InvalidData);
+            assert_eq!(Compression::parse("uncompressed"), Some(Compression::None));
+            assert_eq!(Compression::parse("brotli"), None);
+        }
+
+        test "batches decompress up to the limit" {
+            let bytes = vec![0; MAX_DECOMPRESSED_SIZE + 1];
+            for codec in codecs {
+                let compressed = codec.compress(&bytes[1..]).unwrap();
+                assert_eq!(codec.decompress(&compressed).unwrap().len(), MAX_DECOMPRESSED_SIZE);
+                let bomb = codec.compress(&bytes).unwrap();
+                assert_eq!(codec.decompress(&bomb).unwrap_err().kind(), ErrorKind::InvalidData, "{} stops", codec.name());
+            }
+        }
+
+        test "garbage doesn't decompress" {
+            for codec in &codecs[1..] {
+                assert!(codec.decompress(b"WOMBIEST").is_err(), "{} refuses garbage", codec.name());
+            }
+        }
+    }
+}
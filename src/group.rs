@@ -0,0 +1,421 @@
+// The group coordinator lets the consumers of a group share the partitions
+// of the topics they subscribe to. A consumer joins the group and waits:
+// joining starts a rebalance, which ends once every member rejoined or the
+// longest session timeout of the group passed, dropping the members that
+// didn't. The group then moves to its next generation, the coordinator
+// assigns the partitions and each member picks up its own with a sync.
+//
+// Members heartbeat while they consume. A member that stops for its session
+// timeout, or leaves, triggers a rebalance, which the others learn about
+// from their next heartbeat and join again.
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::sync::{Condvar, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::assignor::{self, Assignment};
+use crate::protocol::{ErrorCode, ResponseError};
+
+
+/// What a member learns from joining the group.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Joined {
+    pub generation_id: i32,
+    pub member_id: String,
+    pub leader_id: String,
+    pub assignor: String,
+    pub members: Vec<String>,
+}
+
+
+struct Member {
+    topics: Vec<String>,
+    assignors: Vec<String>,
+    session_timeout: Duration,
+    last_heartbeat: Instant,
+    // rejoined during the ongoing rebalance
+    joined: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum State {
+    Empty,
+    PreparingRebalance { deadline: Instant },
+    Stable,
+}
+
+struct Group {
+    generation_id: i32,
+    state: State,
+    members: BTreeMap<String, Member>,
+    leader_id: Option<String>,
+    assignor: String,
+    assignment: Assignment,
+}
+
+impl Group {
+    fn new() -> Group {
+        Group {
+            generation_id: 0,
+            state: State::Empty,
+            members: BTreeMap::new(),
+            leader_id: None,
+            assignor: String::new(),
+            assignment: Assignment::new(),
+        }
+    }
+
+    // Every member has to join again within the longest session timeout.
+    fn prepare_rebalance(&mut self, now: Instant) {
+        let timeout = self.members.values().map(|member| member.session_timeout).max().unwrap_or_default();
+        for member in self.members.values_mut() {
+            member.joined = false;
+        }
+        self.state = State::PreparingRebalance { deadline: now + timeout };
+    }
+
+    // Move to the next generation once everyone is in or time is up,
+    // returning whether it did.
+    fn try_complete_rebalance(&mut self, now: Instant, partitions: &HashMap<String, u32>) -> bool {
+        let deadline = match self.state {
+            State::PreparingRebalance { deadline } => deadline,
+            _ => return false,
+        };
+        if now < deadline && self.members.values().any(|member| !member.joined) {
+            return false;
+        }
+        self.members.retain(|_, member| member.joined);
+        self.generation_id += 1;
+        self.assignment.clear();
+        if self.members.is_empty() {
+            self.state = State::Empty;
+            self.leader_id = None;
+            return true;
+        }
+        let leader_id = match &self.leader_id {
+            Some(leader_id) if self.members.contains_key(leader_id) => leader_id.clone(),
+            _ => self.members.keys().next().unwrap().clone(),
+        };
+        let members = &self.members;
+        // joining made sure there is one everybody knows
+        let name = members[&leader_id].assignors.iter()
+            .find(|&name| members.values().all(|member| member.assignors.contains(name)))
+            .unwrap();
+        let subscriptions = members.iter().map(|(id, member)| (id.clone(), member.topics.clone())).collect();
+        self.assignment = assignor::assignor(name).unwrap().assign(&subscriptions, partitions);
+        self.assignor = name.clone();
+        self.leader_id = Some(leader_id);
+        for member in self.members.values_mut() {
+            member.last_heartbeat = now;
+        }
+        self.state = State::Stable;
+        true
+    }
+
+    fn member(&mut self, group_id: &str, member_id: &str) -> io::Result<&mut Member> {
+        self.members.get_mut(member_id).ok_or_else(|| unknown_member(group_id, member_id))
+    }
+
+    // The member is part of the current, settled generation.
+    fn check_generation(&mut self, group_id: &str, generation_id: i32, member_id: &str) -> io::Result<()> {
+        self.member(group_id, member_id)?;
+        if let State::PreparingRebalance { .. } = self.state {
+            return Err(ResponseError::new(
+                ErrorCode::RebalanceInProgress, format!("group {} is rebalancing", group_id)
+            ).into());
+        }
+        if generation_id != self.generation_id {
+            return Err(ResponseError::new(
+                ErrorCode::IllegalGeneration,
+                format!("group {} is at generation {}, not {}", group_id, self.generation_id, generation_id)
+            ).into());
+        }
+        Ok(())
+    }
+}
+
+
+pub struct Coordinator {
+    groups: Mutex<HashMap<String, Group>>,
+    // notified whenever a rebalance completes
+    rebalanced: Condvar,
+    // partition count of every topic
+    partitions: HashMap<String, u32>,
+    next_member_id: AtomicU64,
+}
+
+impl Coordinator {
+    pub fn new(partitions: HashMap<String, u32>) -> Coordinator {
+        Coordinator {
+            groups: Mutex::new(HashMap::new()),
+            rebalanced: Condvar::new(),
+            partitions,
+            next_member_id: AtomicU64::new(0),
+        }
+    }
+
+    /// Join `group_id`, or join it again with the `member_id` handed out
+    /// before, and wait until the group rebalanced.
+    pub fn join(
+        &self, group_id: &str, member_id: &str, session_timeout: Duration,
+        topics: Vec<String>, assignors: Vec<String>
+    ) -> io::Result<Joined> {
+        if !assignors.iter().any(|name| assignor::assignor(name).is_some()) {
+            return Err(inconsistent(group_id, "none of the assignors is known to the broker"));
+        }
+        let mut groups = self.groups.lock().unwrap();
+        let group = groups.entry(group_id.to_string()).or_insert_with(Group::new);
+        let member_id = match member_id {
+            "" => format!("{}-{}", group_id, self.next_member_id.fetch_add(1, Ordering::SeqCst)),
+            member_id => {
+                group.member(group_id, member_id)?;
+                member_id.to_string()
+            },
+        };
+        let others = group.members.iter().filter(|(id, _)| **id != member_id).map(|(_, member)| member);
+        if !assignors.iter().any(|name| {
+            assignor::assignor(name).is_some() && others.clone().all(|member| member.assignors.contains(name))
+        }) {
+            return Err(inconsistent(group_id, "no assignor is known to every member"));
+        }
+
+        let now = Instant::now();
+        if let State::Empty | State::Stable = group.state {
+            group.prepare_rebalance(now);
+        }
+        group.members.insert(member_id.clone(), Member {
+            topics, assignors, session_timeout, last_heartbeat: now, joined: true,
+        });
+        let generation_id = group.generation_id;
+        loop {
+            let group = groups.get_mut(group_id).unwrap();
+            let now = Instant::now();
+            if group.try_complete_rebalance(now, &self.partitions) {
+                self.rebalanced.notify_all();
+            }
+            if group.generation_id > generation_id {
+                group.member(group_id, &member_id)?;
+                return Ok(Joined {
+                    generation_id: group.generation_id,
+                    member_id,
+                    leader_id: group.leader_id.clone().unwrap(),
+                    assignor: group.assignor.clone(),
+                    members: group.members.keys().cloned().collect(),
+                });
+            }
+            let timeout = match group.state {
+                State::PreparingRebalance { deadline } => deadline.saturating_duration_since(now),
+                _ => Duration::from_millis(0),
+            };
+            groups = self.rebalanced.wait_timeout(groups, timeout).unwrap().0;
+        }
+    }
+
+    /// The partitions assigned to a member in `generation_id`.
+    pub fn sync(&self, group_id: &str, generation_id: i32, member_id: &str) -> io::Result<Vec<(String, u32)>> {
+        let mut groups = self.groups.lock().unwrap();
+        let group = groups.get_mut(group_id).ok_or_else(|| unknown_member(group_id, member_id))?;
+        group.check_generation(group_id, generation_id, member_id)?;
+        group.member(group_id, member_id)?.last_heartbeat = Instant::now();
+        Ok(group.assignment.get(member_id).cloned().unwrap_or_default())
+    }
+
+    /// Tell the coordinator the member is still alive, failing once the
+    /// group is rebalancing and the member has to join again.
+    pub fn heartbeat(&self, group_id: &str, generation_id: i32, member_id: &str) -> io::Result<()> {
+        let mut groups = self.groups.lock().unwrap();
+        let group = groups.get_mut(group_id).ok_or_else(|| unknown_member(group_id, member_id))?;
+        group.check_generation(group_id, generation_id, member_id)?;
+        group.member(group_id, member_id)?.last_heartbeat = Instant::now();
+        Ok(())
+    }
+
+    /// Check a member may commit offsets for its group: it must be of the
+    /// current generation, but may still commit while the group rebalances
+    /// so the next owner of its partitions carries on where it stopped.
+    pub fn check_commit(&self, group_id: &str, generation_id: i32, member_id: &str) -> io::Result<()> {
+        let mut groups = self.groups.lock().unwrap();
+        let group = groups.get_mut(group_id).ok_or_else(|| unknown_member(group_id, member_id))?;
+        group.member(group_id, member_id)?;
+        if generation_id != group.generation_id {
+            return Err(ResponseError::new(
+                ErrorCode::IllegalGeneration,
+                format!("group {} is at generation {}, not {}", group_id, group.generation_id, generation_id)
+            ).into());
+        }
+        Ok(())
+    }
+
+    pub fn leave(&self, group_id: &str, member_id: &str) -> io::Result<()> {
+        let mut groups = self.groups.lock().unwrap();
+        let group = groups.get_mut(group_id).ok_or_else(|| unknown_member(group_id, member_id))?;
+        group.member(group_id, member_id)?;
+        group.members.remove(member_id);
+        let now = Instant::now();
+        if group.state == State::Stable {
+            group.prepare_rebalance(now);
+        }
+        if group.try_complete_rebalance(now, &self.partitions) {
+            self.rebalanced.notify_all();
+        }
+        Ok(())
+    }
+
+    /// Remove members whose session timed out and finish rebalances
+    /// nobody is waiting for. Returns the ids of the removed members.
+    pub fn expire_members(&self) -> Vec<String> {
+        let mut groups = self.groups.lock().unwrap();
+        let now = Instant::now();
+        let mut expired = vec![];
+        for group in groups.values_mut() {
+            if group.state == State::Stable {
+                let before = group.members.len();
+                group.members.retain(|id, member| {
+                    let alive = now.duration_since(member.last_heartbeat) < member.session_timeout;
+                    if !alive {
+                        expired.push(id.clone());
+                    }
+                    alive
+                });
+                if group.members.len() < before {
+                    group.prepare_rebalance(now);
+                }
+            }
+            if group.try_complete_rebalance(now, &self.partitions) {
+                self.rebalanced.notify_all();
+            }
+        }
+        expired
+    }
+}
+
+fn unknown_member(group_id: &str, member_id: &str) -> io::Error {
+    ResponseError::new(
+        ErrorCode::UnknownMemberId, format!("group {} has no member {:?}", group_id, member_id)
+    ).into()
+}
+
+fn inconsistent(group_id: &str, reason: &str) -> io::Error {
+    ResponseError::new(ErrorCode::InconsistentGroupProtocol, format!("can't join group {}: {}", group_id, reason)).into()
+}
+
+
+#[cfg(test)]
+extern crate speculate;
+
+#[cfg(test)]
+mod tests {
+    use speculate::speculate;
+    use std::sync::Arc;
+    use std::thread;
+    use super::*;
+
+    speculate! {
+        before {
+            let coordinator = Arc::new(Coordinator::new(
+                vec![(String::from("wombats"), 4)].into_iter().collect()
+            ));
+        }
+
+        fn join(coordinator: &Coordinator, member_id: &str, session_ms: u64) -> io::Result<Joined> {
+            coordinator.join(
+                "group", member_id, Duration::from_millis(session_ms),
+                vec![String::from("wombats")], vec![String::from("range")]
+            )
+        }
+
+        fn code(result: io::Result<()>) -> ErrorCode {
+            ErrorCode::for_error(&result.unwrap_err())
+        }
+
+        test "a lone member gets every partition" {
+            let joined = join(&coordinator, "", 1000).unwrap();
+            assert_eq!(joined.generation_id, 1);
+            assert_eq!(joined.leader_id, joined.member_id);
+            assert_eq!(joined.assignor, "range");
+            let partitions = coordinator.sync("group", 1, &joined.member_id).unwrap();
+            assert_eq!(partitions.len(), 4);
+        }
+
+        test "a new member makes the group rebalance" {
+            let first = join(&coordinator, "", 1000).unwrap();
+            let second = {
+                let coordinator = Arc::clone(&coordinator);
+                thread::spawn(move || join(&coordinator, "", 1000).unwrap())
+            };
+            // the join is waiting for the first member to come back
+            thread::sleep(Duration::from_millis(50));
+            assert_eq!(code(coordinator.heartbeat("group", 1, &first.member_id)), ErrorCode::RebalanceInProgress);
+            let first = join(&coordinator, &first.member_id, 1000).unwrap();
+            let second = second.join().unwrap();
+            assert_eq!(first.generation_id, 2);
+            assert_eq!(second.generation_id, 2);
+            assert_eq!(first.members.len(), 2);
+
+            let mine = coordinator.sync("group", 2, &first.member_id).unwrap();
+            let theirs = coordinator.sync("group", 2, &second.member_id).unwrap();
+            assert_eq!(mine.len() + theirs.len(), 4);
+            assert!(mine.iter().all(|partition| !theirs.contains(partition)));
+        }
+
+        test "members that don't rejoin in time are dropped" {
+            let first = join(&coordinator, "", 100).unwrap();
+            let second = join(&coordinator, "", 100).unwrap();
+            assert_eq!(second.generation_id, 2);
+            assert_eq!(second.members, vec![second.member_id.clone()]);
+            assert_eq!(code(coordinator.heartbeat("group", 2, &first.member_id)), ErrorCode::UnknownMemberId);
+        }
+
+        test "members that stop heartbeating expire" {
+            let first = join(&coordinator, "", 100).unwrap();
+            assert!(coordinator.expire_members().is_empty());
+            thread::sleep(Duration::from_millis(150));
+            assert_eq!(coordinator.expire_members(), vec![first.member_id.clone()]);
+            assert_eq!(code(coordinator.heartbeat("group", 1, &first.member_id)), ErrorCode::UnknownMemberId);
+        }
+
+        test "leaving hands the partitions to the others" {
+            let first = join(&coordinator, "", 1000).unwrap();
+            let second = {
+                let coordinator = Arc::clone(&coordinator);
+                thread::spawn(move || join(&coordinator, "", 1000).unwrap())
+            };
+            thread::sleep(Duration::from_millis(50));
+            join(&coordinator, &first.member_id, 1000).unwrap();
+            let second = second.join().unwrap();
+
+            coordinator.leave("group", &first.member_id).unwrap();
+            assert_eq!(code(coordinator.heartbeat("group", 2, &second.member_id)), ErrorCode::RebalanceInProgress);
+            let second = join(&coordinator, &second.member_id, 1000).unwrap();
+            assert_eq!(second.generation_id, 3);
+            assert_eq!(coordinator.sync("group", 3, &second.member_id).unwrap().len(), 4);
+        }
+
+        test "members commit while the group rebalances" {
+            let first = join(&coordinator, "", 1000).unwrap();
+            let second = {
+                let coordinator = Arc::clone(&coordinator);
+                thread::spawn(move || join(&coordinator, "", 1000).unwrap())
+            };
+            thread::sleep(Duration::from_millis(50));
+            coordinator.check_commit("group", 1, &first.member_id).unwrap();
+            join(&coordinator, &first.member_id, 1000).unwrap();
+            second.join().unwrap();
+            let err = coordinator.check_commit("group", 1, &first.member_id).unwrap_err();
+            assert_eq!(ErrorCode::for_error(&err), ErrorCode::IllegalGeneration);
+        }
+
+        test "old generations and strangers are turned away" {
+            let joined = join(&coordinator, "", 1000).unwrap();
+            assert_eq!(code(coordinator.heartbeat("group", 0, &joined.member_id)), ErrorCode::IllegalGeneration);
+            assert_eq!(code(coordinator.heartbeat("group", 1, "stranger")), ErrorCode::UnknownMemberId);
+            let err = join(&coordinator, "stranger", 1000).unwrap_err();
+            assert_eq!(ErrorCode::for_error(&err), ErrorCode::UnknownMemberId);
+            let err = coordinator.join(
+                "group", "", Duration::from_millis(1000), vec![], vec![String::from("roundrobin")]
+            ).unwrap_err();
+            assert_eq!(ErrorCode::for_error(&err), ErrorCode::InconsistentGroupProtocol);
+        }
+    }
+}
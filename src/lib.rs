@@ -1,11 +1,21 @@
+pub mod compression;
+pub mod record;
 pub mod segment;
 pub mod partition;
+pub mod partitioner;
+pub mod protocol;
+pub mod assignor;
+pub mod group;
+pub mod offsets;
+pub mod transfer;
+pub mod client;
 
 #[cfg(test)]
 mod tests {
 
 
     #[test]
+    #[allow(clippy::assertions_on_constants)]
     fn test_that_it_works() {
         assert!(true)
     }
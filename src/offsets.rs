@@ -0,0 +1,161 @@
+// Consumer groups commit how far they got in each partition to the broker,
+// which keeps the offsets in an internal compacted topic: every commit is a
+// record keyed by group, topic and partition, so compaction throws away all
+// but the latest commit of each. The broker reads the whole topic when it
+// starts and answers offset fetches from memory.
+//
+// Keys are   group_len: u16 | group | topic_len: u16 | topic | partition: u32
+// values are offset: u64, all big endian.
+use std::collections::HashMap;
+use std::io::{self, Read, Write, Error, ErrorKind};
+use std::sync::Mutex;
+
+use byteorder::{ReadBytesExt, WriteBytesExt, NetworkEndian};
+
+use crate::partition::{CleanupPolicy, Config, Partition};
+use crate::record::Record;
+use crate::segment::Offset;
+
+
+/// The internal topic holding committed offsets.
+pub const OFFSETS_TOPIC: &str = "__consumer_offsets";
+const SEGMENT_BYTES: u64 = 100 << 20;
+
+// (group, topic, partition)
+type Key = (String, String, u32);
+
+
+pub struct OffsetStore {
+    log: Mutex<Partition>,
+    offsets: Mutex<HashMap<Key, Offset>>,
+}
+
+impl OffsetStore {
+    /// Open the offsets topic in `data_dir`, creating it if needed, and
+    /// load the latest commit of every partition.
+    pub fn open(data_dir: &str) -> io::Result<OffsetStore> {
+        let mut log = Partition::with_config(offsets_topic(data_dir), 0, config())?;
+        log.open_active()?;
+        let mut offsets = HashMap::new();
+        let mut offset = log.log_start_offset();
+        while offset < log.next_offset() {
+            let records = log.read(offset, log.next_offset(), usize::MAX)?;
+            let last = match records.last() {
+                Some(record) => record.offset,
+                None => break,
+            };
+            for record in records {
+                if let (Some(key), Some(value)) = (&record.key, &record.value) {
+                    offsets.insert(decode_key(key)?, (&value[..]).read_u64::<NetworkEndian>()?);
+                }
+            }
+            offset = last + 1;
+        }
+        Ok(OffsetStore { log: Mutex::new(log), offsets: Mutex::new(offsets) })
+    }
+
+    /// Durably record that `group` consumed the partitions up to the
+    /// offsets, which are where it resumes.
+    pub fn commit(&self, group: &str, offsets: &[(String, u32, Offset)]) -> io::Result<()> {
+        let mut log = self.log.lock().unwrap();
+        for (topic, partition, offset) in offsets {
+            let key = encode_key(group, topic, *partition)?;
+            let mut value = vec![];
+            value.write_u64::<NetworkEndian>(*offset)?;
+            log.append(&mut Record::with_key(key, Some(value)))?;
+        }
+        log.sync()?;
+        let mut committed = self.offsets.lock().unwrap();
+        for (topic, partition, offset) in offsets {
+            committed.insert((group.to_string(), topic.clone(), *partition), *offset);
+        }
+        Ok(())
+    }
+
+    /// The last offset `group` committed for the partition.
+    pub fn fetch(&self, group: &str, topic: &str, partition: u32) -> Option<Offset> {
+        let key = (group.to_string(), topic.to_string(), partition);
+        self.offsets.lock().unwrap().get(&key).copied()
+    }
+
+    /// Compact the closed segments of the offsets topic, returning how many
+    /// commits were dropped.
+    pub fn compact(&self) -> io::Result<usize> {
+        let path = self.log.lock().unwrap().path().to_string();
+        // the cleaner works on its own view of the segments, like any other,
+        // and only swapping the compacted ones in holds up commits
+        let topic = path.rsplit_once('/').map(|(topic, _)| topic.to_string()).unwrap_or_default();
+        let cleaned = Partition::with_config(topic, 0, config())?.clean()?;
+        self.log.lock().unwrap().install(cleaned)
+    }
+}
+
+fn offsets_topic(data_dir: &str) -> String {
+    format!("{}/{}", data_dir, OFFSETS_TOPIC)
+}
+
+fn config() -> Config {
+    Config { segment_bytes: SEGMENT_BYTES, cleanup_policy: CleanupPolicy::Compact, ..Config::default() }
+}
+
+fn encode_key(group: &str, topic: &str, partition: u32) -> io::Result<Vec<u8>> {
+    let mut key = vec![];
+    for string in &[group, topic] {
+        if string.len() > u16::MAX as usize {
+            return Err(Error::new(ErrorKind::InvalidInput, "name too long for an offset commit"));
+        }
+        key.write_u16::<NetworkEndian>(string.len() as u16)?;
+        key.write_all(string.as_bytes())?;
+    }
+    key.write_u32::<NetworkEndian>(partition)?;
+    Ok(key)
+}
+
+fn decode_key(mut key: &[u8]) -> io::Result<Key> {
+    let mut string = || -> io::Result<String> {
+        let len = key.read_u16::<NetworkEndian>()?;
+        let mut bytes = vec![0; len as usize];
+        key.read_exact(&mut bytes)?;
+        String::from_utf8(bytes).map_err(|e| Error::new(ErrorKind::InvalidData, e))
+    };
+    let group = string()?;
+    let topic = string()?;
+    Ok((group, topic, key.read_u32::<NetworkEndian>()?))
+}
+
+
+#[cfg(test)]
+extern crate speculate;
+
+#[cfg(test)]
+mod tests {
+    use speculate::speculate;
+    use std::fs::remove_dir_all;
+    use super::*;
+
+    speculate! {
+        after {
+            let _ = remove_dir_all("tmp/");
+        }
+
+        test "commits survive a restart" {
+            {
+                let store = OffsetStore::open("tmp").unwrap();
+                assert_eq!(store.fetch("wombats", "burrows", 0), None);
+                store.commit("wombats", &[(String::from("burrows"), 0, 5), (String::from("burrows"), 1, 7)]).unwrap();
+                store.commit("wombats", &[(String::from("burrows"), 0, 9)]).unwrap();
+                store.commit("numbats", &[(String::from("burrows"), 0, 2)]).unwrap();
+            }
+            let store = OffsetStore::open("tmp").unwrap();
+            assert_eq!(store.fetch("wombats", "burrows", 0), Some(9));
+            assert_eq!(store.fetch("wombats", "burrows", 1), Some(7));
+            assert_eq!(store.fetch("numbats", "burrows", 0), Some(2));
+            assert_eq!(store.fetch("numbats", "burrows", 1), None);
+        }
+
+        test "keys round trip" {
+            let key = encode_key("wombats", "burrows", 3).unwrap();
+            assert_eq!(decode_key(&key).unwrap(), (String::from("wombats"), String::from("burrows"), 3));
+        }
+    }
+}
@@ -9,64 +9,921 @@ use std::io::{Seek, SeekFrom, BufReader, BufWriter,Write, Read, BufRead, Error};
 use std::io::ErrorKind::ConnectionReset;
 use std::net::{TcpListener, TcpStream};
 use std::sync::{Arc, Mutex};
-use std::collections::{LinkedList, BinaryHeap};
+use std::collections::{LinkedList, BinaryHeap, BTreeMap, HashMap, HashSet, VecDeque};
 
 
-use crate::segment::{Segment, Offset, Client};
+use byteorder::{ReadBytesExt, WriteBytesExt, NetworkEndian};
+
+use crate::compression::Compression;
+use crate::protocol::{ErrorCode, ResponseError};
+use crate::record::{self, Record, NO_PRODUCER_ID};
+use crate::segment::{Segment, Offset, Client, Truncation};
+use crate::transfer::FileSlice;
+
+
+pub const DEFAULT_SEGMENT_BYTES: u64 = 1 << 30;
+pub const DEFAULT_DELETE_RETENTION_MS: i64 = 24 * 60 * 60 * 1000;
+// how many of its latest batches are remembered per producer to spot resent ones
+const PRODUCER_BATCHES: usize = 5;
+
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CleanupPolicy {
+    // old segments are deleted by retention
+    Delete,
+    // closed segments are rewritten keeping the newest record per key
+    Compact,
+}
+
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    // roll the active segment once appending would grow it past this
+    pub segment_bytes: u64,
+    // or once its first record is older than this
+    pub segment_ms: Option<i64>,
+    // delete the oldest closed segments while the partition is bigger than this
+    pub retention_bytes: Option<u64>,
+    // or while their newest record is older than this
+    pub retention_ms: Option<i64>,
+    pub cleanup_policy: CleanupPolicy,
+    // how long compaction keeps a tombstone (a keyed record with a null value)
+    pub delete_retention_ms: i64,
+    // compress appended batches with this codec, `None` keeps the producer's
+    pub compression: Option<Compression>,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            segment_bytes: DEFAULT_SEGMENT_BYTES,
+            segment_ms: None,
+            retention_bytes: None,
+            retention_ms: None,
+            cleanup_policy: CleanupPolicy::Delete,
+            delete_retention_ms: DEFAULT_DELETE_RETENTION_MS,
+            compression: None,
+        }
+    }
+}
+
+
+// Sequences `first_sequence..=last_sequence` of a producer were written
+// at consecutive offsets from `base_offset`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct BatchMetadata {
+    first_sequence: i32,
+    last_sequence: i32,
+    base_offset: Offset,
+}
+
+impl BatchMetadata {
+    // Sequences wrap around, so the batch may run past `i32::MAX`.
+    fn len(&self) -> Offset {
+        self.position(self.last_sequence) + 1
+    }
+
+    // How far into the batch `sequence` is, if it is in it at all.
+    fn position(&self, sequence: i32) -> Offset {
+        sequence.wrapping_sub(self.first_sequence) as u32 as Offset
+    }
+}
+
+#[derive(Debug, Default)]
+struct ProducerState {
+    epoch: i16,
+    batches: VecDeque<BatchMetadata>,
+    // first offset of the producer's transaction still waiting for its marker
+    transaction_start: Option<Offset>,
+}
+
+impl ProducerState {
+    fn last_sequence(&self) -> Option<i32> {
+        self.batches.back().map(|batch| batch.last_sequence)
+    }
+
+    // Batches that follow on from the previous one in both sequence and
+    // offset are merged, which is also how a log scan puts them back together.
+    fn push(&mut self, epoch: i16, batch: BatchMetadata) {
+        if epoch != self.epoch {
+            self.epoch = epoch;
+            self.batches.clear();
+        }
+        if let Some(last) = self.batches.back_mut() {
+            let next_offset = last.base_offset + last.len();
+            if batch.first_sequence == last.last_sequence.wrapping_add(1) && batch.base_offset == next_offset {
+                last.last_sequence = batch.last_sequence;
+                return;
+            }
+        }
+        self.batches.push_back(batch);
+        if self.batches.len() > PRODUCER_BATCHES {
+            self.batches.pop_front();
+        }
+    }
+
+    // Where the batch with these sequences was written, if it already was.
+    fn duplicate(&self, first_sequence: i32, last_sequence: i32) -> Option<Offset> {
+        self.batches.iter().find_map(|batch| {
+            let first = batch.position(first_sequence);
+            (first <= batch.position(last_sequence) && batch.position(last_sequence) < batch.len())
+                .then(|| batch.base_offset + first)
+        })
+    }
+}
+
+
+/// The records of `producer_id` from `first_offset` up to the abort marker
+/// at `last_offset` belong to a transaction that was rolled back.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AbortedTransaction {
+    pub producer_id: i64,
+    pub first_offset: Offset,
+    pub last_offset: Offset,
+}
+
+/// Drop control records and the records of aborted transactions from
+/// `records`, given the aborted transactions overlapping them.
+pub fn committed(records: Vec<Record>, aborted: &[AbortedTransaction]) -> Vec<Record> {
+    let mut aborted = aborted.to_vec();
+    aborted.sort_unstable_by_key(|transaction| transaction.first_offset);
+    let mut aborted = aborted.into_iter().peekable();
+    // producers whose aborted transaction the records are in the middle of
+    let mut aborting = HashSet::new();
+    records.into_iter().filter(|record| {
+        while let Some(transaction) = aborted.next_if(|t| t.first_offset <= record.offset) {
+            aborting.insert(transaction.producer_id);
+        }
+        if record.is_control() {
+            if record.commits() == Some(false) {
+                aborting.remove(&record.producer_id);
+            }
+            return false;
+        }
+        !(record.is_transactional() && aborting.contains(&record.producer_id))
+    }).collect()
+}
+
+
+/// Compacted copies of closed segments, waiting to replace them.
+pub struct Cleaned {
+    segments: Vec<Segment>,
+    removed: usize,
+}
 
 
 pub struct Partition  {
     path: String,
     topic: String,
     partition: u32,
-    segments: BinaryHeap<Segment>,
+    // the closed segments by base offset, open for reading
+    segments: BTreeMap<Offset, Segment>,
+    active: Option<Segment>,
+    config: Config,
+    // idempotent producers that wrote to the partition, by producer id
+    producers: HashMap<i64, ProducerState>,
+    aborted: Vec<AbortedTransaction>,
 }
 
 impl Partition {
     pub fn new(topic: String, part: u32) -> io::Result<Partition> {
+        Partition::with_config(topic, part, Config::default())
+    }
+
+    pub fn with_config(topic: String, part: u32, config: Config) -> io::Result<Partition> {
         fs::create_dir_all(format!("{}/{}", topic, part))?;
         Ok(Partition {
             path: format!("{}/{}", &topic, &part),
-            topic: topic,
+            topic,
             partition: part,
-            segments: BinaryHeap::new(),
+            segments: BTreeMap::new(),
+            active: None,
+            config,
+            producers: HashMap::new(),
+            aborted: vec![],
         })
     }
-    pub fn fill_segments(&mut self) -> io::Result<()> {
-        if !self.segments.is_empty() {
-            self.segments.clear();
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// Open the segments for reading and take the newest as the active one,
+    /// recovering whatever a crash left at its end, and open it for
+    /// appending. The sequence numbers of idempotent producers and the state
+    /// of transactions are restored from the snapshot taken when the active
+    /// segment was started and the records after it, or failing that rebuilt
+    /// from the whole log.
+    pub fn open_active(&mut self) -> io::Result<Option<Truncation>> {
+        self.fill_segments()?;
+        let mut active = match self.segments.pop_last() {
+            Some((_, newest)) => newest,
+            None => Segment::new(self.path.clone(), 0)?,
         };
 
+        let truncation = active.recover()?;
+        active.open(Client::Active)?;
+        self.active = Some(active);
+        self.load_producers()?;
+        Ok(truncation)
+    }
+
+    fn load_producers(&mut self) -> io::Result<()> {
+        self.producers.clear();
+        self.aborted.clear();
+        let from = self.restore_snapshot()?;
+        let bases: Vec<Offset> = self.segments_from(from).map(|segment| segment.base_offset()).collect();
+        for base in bases {
+            self.segment_mut(base)?.seek_offset(from)?;
+            while let Some(frame) = self.segment_mut(base)?.read_record()? {
+                if frame.producer_id == NO_PRODUCER_ID {
+                    continue;
+                }
+                for record in frame.decompress()? {
+                    if record.offset < from {
+                        continue;
+                    }
+                    if let Some(commit) = record.commits() {
+                        self.apply_marker(record.producer_id, record.offset, commit);
+                        continue;
+                    }
+                    let state = self.producers.entry(record.producer_id).or_default();
+                    state.push(
+                        record.producer_epoch,
+                        BatchMetadata {
+                            first_sequence: record.sequence,
+                            last_sequence: record.sequence,
+                            base_offset: record.offset,
+                        }
+                    );
+                    if record.is_transactional() && state.transaction_start.is_none() {
+                        state.transaction_start = Some(record.offset);
+                    }
+                }
+            }
+        }
+        let log_start_offset = self.log_start_offset();
+        self.aborted.retain(|transaction| transaction.last_offset >= log_start_offset);
+        Ok(())
+    }
+
+    // Save the state of the producers as of `offset`, where the active
+    // segment starts, so opening the partition only replays the records from
+    // there. The snapshot before it is no longer needed.
+    //
+    //   crc: u32 | producer_count: u32 | producers | aborted_count: u32 | aborted
+    //
+    // where a producer is   producer_id: i64 | epoch: i16 | transaction_start: i64 |
+    //                       batch_count: u32 | (first_sequence: i32 | last_sequence: i32 | base_offset: u64)...
+    // and an aborted one is producer_id: i64 | first_offset: u64 | last_offset: u64
+    fn write_snapshot(&self, offset: Offset) -> io::Result<()> {
+        let mut buf = vec![];
+        buf.write_u32::<NetworkEndian>(self.producers.len() as u32)?;
+        for (&producer_id, state) in &self.producers {
+            buf.write_i64::<NetworkEndian>(producer_id)?;
+            buf.write_i16::<NetworkEndian>(state.epoch)?;
+            buf.write_i64::<NetworkEndian>(state.transaction_start.map_or(-1, |start| start as i64))?;
+            buf.write_u32::<NetworkEndian>(state.batches.len() as u32)?;
+            for batch in &state.batches {
+                buf.write_i32::<NetworkEndian>(batch.first_sequence)?;
+                buf.write_i32::<NetworkEndian>(batch.last_sequence)?;
+                buf.write_u64::<NetworkEndian>(batch.base_offset)?;
+            }
+        }
+        buf.write_u32::<NetworkEndian>(self.aborted.len() as u32)?;
+        for transaction in &self.aborted {
+            buf.write_i64::<NetworkEndian>(transaction.producer_id)?;
+            buf.write_u64::<NetworkEndian>(transaction.first_offset)?;
+            buf.write_u64::<NetworkEndian>(transaction.last_offset)?;
+        }
+
+        // written aside so a crash never leaves half a snapshot
+        let filename = self.snapshot_filename(offset);
+        let partial = format!("{}.partial", filename);
+        let mut file = File::create(&partial)?;
+        file.write_u32::<NetworkEndian>(crc32fast::hash(&buf))?;
+        file.write_all(&buf)?;
+        file.sync_data()?;
+        fs::rename(&partial, &filename)?;
+        for older in self.snapshots()?.into_iter().filter(|&older| older != offset) {
+            fs::remove_file(self.snapshot_filename(older))?;
+        }
+        Ok(())
+    }
+
+    // Restore the producers from the newest snapshot, returning the offset
+    // the log is to be replayed from: 0 without a snapshot, or with one that
+    // is damaged or ahead of the log.
+    fn restore_snapshot(&mut self) -> io::Result<Offset> {
+        let next_offset = self.next_offset();
+        let offset = match self.snapshots()?.pop() {
+            Some(offset) if offset <= next_offset => offset,
+            _ => return Ok(0),
+        };
+        let bytes = fs::read(self.snapshot_filename(offset))?;
+        match self.read_snapshot(&bytes) {
+            Ok(()) => Ok(offset),
+            Err(ref e) if e.kind() == io::ErrorKind::InvalidData || e.kind() == io::ErrorKind::UnexpectedEof => {
+                self.producers.clear();
+                self.aborted.clear();
+                Ok(0)
+            },
+            Err(e) => Err(e),
+        }
+    }
+
+    fn read_snapshot(&mut self, bytes: &[u8]) -> io::Result<()> {
+        let mut cursor = bytes;
+        let crc = cursor.read_u32::<NetworkEndian>()?;
+        if crc != crc32fast::hash(cursor) {
+            return Err(Error::new(io::ErrorKind::InvalidData, "snapshot checksum mismatch"));
+        }
+        for _ in 0..cursor.read_u32::<NetworkEndian>()? {
+            let producer_id = cursor.read_i64::<NetworkEndian>()?;
+            let epoch = cursor.read_i16::<NetworkEndian>()?;
+            let transaction_start = cursor.read_i64::<NetworkEndian>()?;
+            let mut state = ProducerState {
+                epoch,
+                batches: VecDeque::new(),
+                transaction_start: (transaction_start >= 0).then_some(transaction_start as Offset),
+            };
+            for _ in 0..cursor.read_u32::<NetworkEndian>()? {
+                state.batches.push_back(BatchMetadata {
+                    first_sequence: cursor.read_i32::<NetworkEndian>()?,
+                    last_sequence: cursor.read_i32::<NetworkEndian>()?,
+                    base_offset: cursor.read_u64::<NetworkEndian>()?,
+                });
+            }
+            self.producers.insert(producer_id, state);
+        }
+        for _ in 0..cursor.read_u32::<NetworkEndian>()? {
+            self.aborted.push(AbortedTransaction {
+                producer_id: cursor.read_i64::<NetworkEndian>()?,
+                first_offset: cursor.read_u64::<NetworkEndian>()?,
+                last_offset: cursor.read_u64::<NetworkEndian>()?,
+            });
+        }
+        Ok(())
+    }
+
+    // Offsets of the snapshots on disk, oldest first.
+    fn snapshots(&self) -> io::Result<Vec<Offset>> {
+        let mut offsets = vec![];
         for entry in fs::read_dir(&self.path)? {
-            let path = entry.unwrap().path();
-            let stem = path.as_path().file_stem().unwrap();
-            let str_stem = stem.to_str().unwrap();
-            self.segments.push(
-                Segment::new(
-                    path.to_string_lossy().to_string(),
-                    str_stem.parse::<Offset>().unwrap()
-                ).unwrap()
-            )
+            let path = entry?.path();
+            if path.extension().is_none_or(|ext| ext != "snapshot") {
+                continue;
+            }
+            if let Some(offset) = path.file_stem().and_then(|stem| stem.to_str()).and_then(|stem| stem.parse().ok()) {
+                offsets.push(offset);
+            }
+        }
+        offsets.sort_unstable();
+        Ok(offsets)
+    }
+
+    fn snapshot_filename(&self, offset: Offset) -> String {
+        format!("{}/{:0>20}.snapshot", self.path, offset)
+    }
+
+    // Close the producer's transaction, remembering it if it was aborted.
+    fn apply_marker(&mut self, producer_id: i64, offset: Offset, commit: bool) {
+        let start = self.producers.get_mut(&producer_id).and_then(|state| state.transaction_start.take());
+        if let (Some(first_offset), false) = (start, commit) {
+            self.aborted.push(AbortedTransaction { producer_id, first_offset, last_offset: offset });
+        }
+    }
+
+    /// Write the marker committing or aborting the transaction of a
+    /// producer, returning its offset.
+    pub fn end_transaction(&mut self, producer_id: i64, epoch: i16, commit: bool) -> io::Result<Offset> {
+        if let Some(state) = self.producers.get(&producer_id) {
+            if epoch < state.epoch {
+                return Err(fenced(producer_id, epoch, state.epoch));
+            }
+        }
+        let offset = self.append(&mut Record::control(producer_id, epoch, commit))?;
+        self.apply_marker(producer_id, offset, commit);
+        Ok(offset)
+    }
+
+    /// Producers, and their epochs, with a transaction waiting for its marker.
+    pub fn ongoing_transactions(&self) -> Vec<(i64, i16)> {
+        self.producers.iter().filter(|(_, state)| state.transaction_start.is_some())
+            .map(|(&producer_id, state)| (producer_id, state.epoch))
+            .collect()
+    }
+
+    /// The offset up to which every transaction is decided, the first
+    /// record of the oldest ongoing one.
+    pub fn last_stable_offset(&self) -> Offset {
+        self.producers.values().filter_map(|state| state.transaction_start)
+            .min()
+            .unwrap_or_else(|| self.next_offset())
+    }
+
+    /// Aborted transactions with records in `from..to`.
+    pub fn aborted_transactions(&self, from: Offset, to: Offset) -> Vec<AbortedTransaction> {
+        self.aborted.iter().filter(|t| t.first_offset < to && t.last_offset >= from).copied().collect()
+    }
+
+    /// The earliest offset still held by the partition.
+    pub fn log_start_offset(&self) -> Offset {
+        match (self.segments.values().next(), &self.active) {
+            (Some(oldest), _) => oldest.base_offset(),
+            (None, Some(active)) => active.base_offset(),
+            (None, None) => 0,
+        }
+    }
+
+    /// The offset the next appended record gets.
+    pub fn next_offset(&self) -> Offset {
+        self.active.as_ref().map_or(0, |active| active.next_offset())
+    }
+
+    /// Assign the record the next offset and append it, rolling the
+    /// active segment first if it is full or too old.
+    pub fn append(&mut self, record: &mut Record) -> io::Result<Offset> {
+        record.offset = self.next_offset();
+        self.append_frames(std::slice::from_ref(record))?;
+        Ok(record.offset)
+    }
+
+    // Append records, or wrappers of compressed ones, at the offsets they
+    // already have. They go to the same segment, so a snapshot taken when
+    // it rolls never splits them.
+    fn append_frames(&mut self, frames: &[Record]) -> io::Result<()> {
+        if self.should_roll(frames.iter().map(Record::encoded_len).sum()) {
+            self.roll()?;
+        }
+        let active = self.active_mut()?;
+        for frame in frames {
+            active.append(frame)?;
+        }
+        Ok(())
+    }
+
+    /// Append records a producer sent together and return the offset of the
+    /// first. The records of an idempotent producer must carry the sequence
+    /// numbers following its previous batch. A batch that was appended
+    /// before isn't appended again, its original offset is returned instead.
+    ///
+    /// Compressed records are checked one by one like the others. The batch
+    /// is stored compressed with the partition's codec, or the producer's
+    /// if it has none, reusing what the producer compressed if it can.
+    pub fn append_batch(&mut self, frames: Vec<Record>) -> io::Result<Offset> {
+        let sent_compressed = match &frames[..] {
+            [frame] if frame.is_compressed() => frame.value.clone().map(|value| {
+                Compression::from_attributes(frame.attributes).map(|compression| (compression, value))
+            }).transpose()?,
+            _ => None,
+        };
+        let compression = self.config.compression
+            .or(sent_compressed.as_ref().map(|(compression, _)| *compression))
+            .unwrap_or_default();
+        let mut records = record::decompress_all(frames)
+            .map_err(|e| ResponseError::new(ErrorCode::CorruptMessage, e.to_string()))?;
+        let (producer_id, epoch, first_sequence) = match records.first() {
+            Some(first) => (first.producer_id, first.producer_epoch, first.sequence),
+            None => return Ok(self.next_offset()),
+        };
+        let last_sequence = first_sequence.wrapping_add(records.len() as i32 - 1);
+        let transactional = records[0].is_transactional();
+        if records.iter().any(|record| record.is_control() || record.is_transactional() != transactional) {
+            return Err(ResponseError::new(
+                ErrorCode::InvalidRequest, "batch mixes transactional and other records"
+            ).into());
+        }
+        if transactional && producer_id == NO_PRODUCER_ID {
+            return Err(ResponseError::new(
+                ErrorCode::InvalidRequest, "transactional records need a producer id"
+            ).into());
+        }
+        if producer_id != NO_PRODUCER_ID {
+            let consistent = records.iter().zip(0..).all(|(record, i)| {
+                record.producer_id == producer_id && record.producer_epoch == epoch
+                    && record.sequence == first_sequence.wrapping_add(i)
+            });
+            if !consistent {
+                return Err(ResponseError::new(
+                    ErrorCode::InvalidRequest, "batch sequence numbers don't follow on"
+                ).into());
+            }
+            if let Some(duplicate) = self.check_sequence(producer_id, epoch, first_sequence, last_sequence)? {
+                return Ok(duplicate);
+            }
+        }
+
+        // the compressed records can be stored as they came if they were
+        // numbered from 0 on like the records of a batch
+        let in_order = records.iter().zip(0..).all(|(record, offset)| record.offset == offset);
+        let base_offset = self.next_offset();
+        for (record, offset) in records.iter_mut().zip(base_offset..) {
+            record.offset = offset;
+        }
+        let frames = match (compression, sent_compressed) {
+            (Compression::None, _) => records,
+            (compression, Some((sent, value))) if sent == compression && in_order => {
+                vec![Record::wrap(&records, compression, value)]
+            },
+            (compression, _) => vec![Record::compress(&records, compression)?],
+        };
+        self.append_frames(&frames)?;
+        if producer_id != NO_PRODUCER_ID {
+            let state = self.producers.entry(producer_id).or_default();
+            state.push(epoch, BatchMetadata { first_sequence, last_sequence, base_offset });
+            if transactional && state.transaction_start.is_none() {
+                state.transaction_start = Some(base_offset);
+            }
+        }
+        Ok(base_offset)
+    }
+
+    // `Some` offset if the batch was already appended, an error unless
+    // it is the next one the producer should send.
+    fn check_sequence(
+        &self, producer_id: i64, epoch: i16, first_sequence: i32, last_sequence: i32
+    ) -> io::Result<Option<Offset>> {
+        let state = match self.producers.get(&producer_id) {
+            Some(state) => state,
+            None => return Ok(None),
+        };
+        if epoch < state.epoch {
+            return Err(fenced(producer_id, epoch, state.epoch));
+        }
+        if epoch > state.epoch {
+            return match first_sequence {
+                0 => Ok(None),
+                _ => Err(out_of_order(producer_id, 0, first_sequence)),
+            };
+        }
+        if let Some(offset) = state.duplicate(first_sequence, last_sequence) {
+            return Ok(Some(offset));
+        }
+        match state.last_sequence() {
+            Some(last) if first_sequence != last.wrapping_add(1) => {
+                Err(out_of_order(producer_id, last.wrapping_add(1), first_sequence))
+            },
+            _ => Ok(None),
+        }
+    }
+
+    /// Close the active segment and start a new one at the next offset.
+    pub fn roll(&mut self) -> io::Result<()> {
+        let mut active = match self.active.take() {
+            Some(active) => active,
+            None => return Err(not_open()),
+        };
+        let mut next = Segment::new(self.path.clone(), active.next_offset())?;
+        next.open(Client::Active)?;
+        active.sync()?;
+        active.close();
+        active.open(Client::Consumer)?;
+        self.segments.insert(active.base_offset(), active);
+        self.active = Some(next);
+        self.write_snapshot(self.next_offset())
+    }
+
+    /// Delete closed segments, oldest first, while the partition exceeds
+    /// `retention_bytes` or their newest record is older than `retention_ms`.
+    /// Returns the base offsets of the deleted segments.
+    pub fn enforce_retention(&mut self) -> io::Result<Vec<Offset>> {
+        let mut deleted = vec![];
+        let mut total = self.segments.values().map(|segment| segment.len()).sum::<u64>()
+            + self.active.as_ref().map_or(0, |active| active.size());
+        let now = record::now_ms();
+        while let Some((_, mut oldest)) = self.segments.pop_first() {
+            let len = oldest.len();
+            let over_size = self.config.retention_bytes.is_some_and(|limit| total - len >= limit);
+            let expired = match self.config.retention_ms {
+                Some(retention_ms) => oldest.largest_timestamp()? < now - retention_ms,
+                None => false,
+            };
+            if !over_size && !expired {
+                self.segments.insert(oldest.base_offset(), oldest);
+                break;
+            }
+            total -= len;
+            deleted.push(oldest.base_offset());
+            oldest.delete()?;
+        }
+        let log_start_offset = self.log_start_offset();
+        self.aborted.retain(|transaction| transaction.last_offset >= log_start_offset);
+        Ok(deleted)
+    }
+
+    /// Rewrite every closed segment keeping only the newest record of each
+    /// key, tombstones younger than `delete_retention_ms` and every
+    /// transaction marker. Records keep
+    /// their offsets, so a compacted segment has gaps.
+    /// Only committed records supersede a key, aborted ones are dropped,
+    /// and nothing from the first transaction still waiting for its marker
+    /// on is touched.
+    /// Returns how many records were removed.
+    pub fn compact(&mut self) -> io::Result<usize> {
+        let cleaned = self.clean()?;
+        self.install(cleaned)
+    }
+
+    /// Build the segments `compact` would leave aside, from the files on
+    /// disk. The newest segment on disk is taken to be the active one and is
+    /// left alone, which lets a cleaner run on its own `Partition` while
+    /// another one appends, and hand the result over to `install` on it.
+    pub fn clean(&self) -> io::Result<Cleaned> {
+        let mut bases = self.sorted_bases()?;
+        bases.pop(); // the active segment
+
+        // the cleaner doesn't follow transactions, so it finds out from the
+        // markers which ones are decided
+        let mut open = HashMap::new();
+        self.for_each_record(&bases, |record| {
+            if record.is_control() {
+                open.remove(&record.producer_id);
+            } else if record.is_transactional() {
+                open.entry(record.producer_id).or_insert(record.offset);
+            }
+        })?;
+        let stable = open.into_values().min().unwrap_or(Offset::MAX);
+
+        // the keyed records of a transaction only count once it committed,
+        // and may then supersede keys written after them
+        let mut latest: HashMap<Vec<u8>, Offset> = HashMap::new();
+        let mut pending: HashMap<i64, Vec<(Vec<u8>, Offset)>> = HashMap::new();
+        self.for_each_record(&bases, |record| match record.key {
+            // markers share a key but are never superseded
+            _ if record.is_control() => {
+                let keys = pending.remove(&record.producer_id).unwrap_or_default();
+                if record.commits() == Some(true) {
+                    for (key, offset) in keys {
+                        let newest = latest.entry(key).or_insert(offset);
+                        *newest = (*newest).max(offset);
+                    }
+                }
+            },
+            _ if record.offset >= stable => {},
+            Some(key) if record.is_transactional() => pending.entry(record.producer_id).or_default().push((key, record.offset)),
+            Some(key) => { latest.insert(key, record.offset); },
+            None => {},
+        })?;
+
+        // a cleaned segment is built aside, then moved over the original
+        let cleaning = format!("{}/.cleaning", self.path);
+        if fs::metadata(&cleaning).is_ok() {
+            fs::remove_dir_all(&cleaning)?;
         }
+        fs::create_dir(&cleaning)?;
+        let tombstone_horizon = record::now_ms() - self.config.delete_retention_ms;
+        let mut segments = vec![];
+        let mut removed = 0;
+        for &base in &bases {
+            let mut segment = Segment::new(self.path.clone(), base)?;
+            segment.open(Client::Consumer)?;
+            let mut cleaned = Segment::new(cleaning.clone(), base)?;
+            cleaned.open(Client::Producer)?;
+            let keep = |record: &Record| match &record.key {
+                _ if record.is_control() || record.offset >= stable => true,
+                None => true,
+                Some(key) => latest.get(key) == Some(&record.offset)
+                    && (record.value.is_some() || record.timestamp >= tombstone_horizon),
+            };
+            let mut dropped = 0;
+            while let Some(frame) = segment.read_record()? {
+                if !frame.is_compressed() {
+                    if keep(&frame) {
+                        cleaned.append(&frame)?;
+                    } else {
+                        dropped += 1;
+                    }
+                    continue;
+                }
+                // what's left of a compressed batch is compressed again
+                let compression = Compression::from_attributes(frame.attributes)?;
+                let records = frame.clone().decompress()?;
+                let kept: Vec<Record> = records.iter().filter(|record| keep(record)).cloned().collect();
+                dropped += records.len() - kept.len();
+                if kept.len() == records.len() {
+                    cleaned.append(&frame)?;
+                } else if !kept.is_empty() {
+                    cleaned.append(&Record::compress(&kept, compression)?)?;
+                }
+            }
+            if dropped == 0 {
+                cleaned.delete()?;
+                continue;
+            }
+            cleaned.sync()?;
+            cleaned.close();
+            segments.push(cleaned);
+            removed += dropped;
+        }
+        Ok(Cleaned { segments, removed })
+    }
 
+    // Every record of the segments at `bases`, oldest first.
+    fn for_each_record<F: FnMut(Record)>(&self, bases: &[Offset], mut f: F) -> io::Result<()> {
+        for &base in bases {
+            let mut segment = Segment::new(self.path.clone(), base)?;
+            segment.open(Client::Consumer)?;
+            while let Some(frame) = segment.read_record()? {
+                frame.decompress()?.into_iter().for_each(&mut f);
+            }
+        }
         Ok(())
-        //     let mut segments: Vec<(String, u64)> = fs::read_dir(&self.path)?.map(|entry| {
-        //         let path = entry.unwrap().path();
-        //         let stem = path.as_path().file_stem().unwrap();
-        //         let str_stem = stem.to_str().unwrap();
-        //         (String::from(path.to_string_lossy()), str_stem.parse::<Offset>().unwrap())
-        //     }).collect();
+    }
+
+    /// Move the segments `clean` built over the originals, which are read
+    /// from then on. Returns how many records were removed.
+    pub fn install(&mut self, cleaned: Cleaned) -> io::Result<usize> {
+        for segment in cleaned.segments {
+            let base = segment.base_offset();
+            segment.replace(&Segment::new(self.path.clone(), base)?)?;
+            if let Some(original) = self.segments.get_mut(&base) {
+                original.open(Client::Consumer)?;
+            }
+        }
+        fs::remove_dir_all(format!("{}/.cleaning", self.path))?;
+        Ok(cleaned.removed)
+    }
 
-        // segments.sort_unstable();
+    pub fn sync(&mut self) -> io::Result<()> {
+        self.active_mut()?.sync()
+    }
 
-        // for (path, off) in segments {
-        //     if let Ok(seg) = Segment::new(path, off) {
-        //         self.segments.push(seg);
-        //     }
-        // }
+    fn should_roll(&self, len: usize) -> bool {
+        let active = match &self.active {
+            Some(active) if active.size() > 0 => active,
+            _ => return false,
+        };
+        // index positions are u32, which caps a segment at 4GiB
+        let segment_bytes = self.config.segment_bytes.min(u32::MAX as u64);
+        if active.size() + len as u64 > segment_bytes {
+            return true;
+        }
+        match (self.config.segment_ms, active.first_timestamp()) {
+            (Some(segment_ms), Some(first)) => record::now_ms() - first > segment_ms,
+            _ => false,
+        }
     }
 
+    fn active_mut(&mut self) -> io::Result<&mut Segment> {
+        self.active.as_mut().ok_or_else(not_open)
+    }
 
+    /// Open every segment on disk for reading.
+    pub fn fill_segments(&mut self) -> io::Result<()> {
+        self.segments.clear();
+        for mut segment in self.crawl_segments()? {
+            segment.open(Client::Consumer)?;
+            self.segments.insert(segment.base_offset(), segment);
+        }
+        Ok(())
+    }
+
+    fn crawl_segments(&self) -> io::Result<BinaryHeap<Segment>> {
+        let mut segments = BinaryHeap::new();
+
+        for entry in fs::read_dir(&self.path)? {
+            let path = entry?.path();
+            if path.extension().is_none_or(|ext| ext != "log") {
+                continue; // indexes live next to their segment
+            }
+            // a stray log file isn't a segment, whatever else it is
+            let base_offset = match path.file_stem().and_then(|stem| stem.to_str()).map(str::parse::<Offset>) {
+                Some(Ok(base_offset)) => base_offset,
+                _ => continue,
+            };
+            segments.push(Segment::new(self.path.clone(), base_offset)?)
+        }
+
+        Ok(segments)
+    }
+
+    // Base offsets of the segments on disk, oldest first.
+    fn sorted_bases(&self) -> io::Result<Vec<Offset>> {
+        let mut bases: Vec<Offset> = self.crawl_segments()?.into_iter().map(|s| s.base_offset()).collect();
+        bases.sort_unstable();
+        Ok(bases)
+    }
+
+    // The open segment starting at `base`, closed or active.
+    fn segment_mut(&mut self, base: Offset) -> io::Result<&mut Segment> {
+        match self.segments.get_mut(&base) {
+            Some(segment) => Ok(segment),
+            None => self.active.as_mut().ok_or_else(not_open),
+        }
+    }
+
+    // The open segments from the one holding `offset` on, oldest first.
+    fn segments_from(&mut self, offset: Offset) -> impl Iterator<Item = &mut Segment> {
+        let first = match &self.active {
+            Some(active) if active.base_offset() <= offset => active.base_offset(),
+            _ => self.segments.range(..=offset).next_back().map_or(0, |(&base, _)| base),
+        };
+        self.segments.range_mut(first..).map(|(_, segment)| segment).chain(self.active.as_mut())
+    }
+
+    /// First offset whose record is stamped at or after `timestamp`,
+    /// `None` if the whole partition is older.
+    pub fn offset_for_timestamp(&mut self, timestamp: i64) -> io::Result<Option<Offset>> {
+        for segment in self.segments_from(0) {
+            if let Some(offset) = segment.offset_for_timestamp(timestamp)? {
+                return Ok(Some(offset));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Records from `offset` up to, but not including, `end`, stopping
+    /// before they add up to more than `max_bytes` unless that would leave
+    /// nothing to return. Offsets compaction removed are skipped. Compressed
+    /// records are returned decompressed, counting their compressed size.
+    pub fn read(&mut self, offset: Offset, end: Offset, max_bytes: usize) -> io::Result<Vec<Record>> {
+        let mut records = vec![];
+        let mut bytes = 0;
+        for segment in self.segments_from(offset) {
+            segment.seek_offset(offset)?;
+            loop {
+                let frame = match segment.read_record() {
+                    Ok(Some(frame)) => frame,
+                    Ok(None) => break,
+                    // a producer may still be appending this record
+                    Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+                    Err(e) => return Err(e),
+                };
+                let len = frame.encoded_len();
+                // a compressed batch may start before `offset` or run past `end`
+                let mut held = frame.decompress()?;
+                held.retain(|record| record.offset >= offset);
+                let past_end = held.iter().position(|record| record.offset >= end);
+                if past_end == Some(0) {
+                    return Ok(records);
+                }
+                bytes += len;
+                if bytes > max_bytes && !records.is_empty() {
+                    return Ok(records);
+                }
+                if let Some(past_end) = past_end {
+                    held.truncate(past_end);
+                    records.extend(held);
+                    return Ok(records);
+                }
+                records.extend(held);
+            }
+        }
+        Ok(records)
+    }
+
+    /// The same records as `read`, as the runs of segment files holding
+    /// them, found by reading only their headers.
+    pub fn slices(&mut self, offset: Offset, end: Offset, max_bytes: usize) -> io::Result<Vec<FileSlice>> {
+        let mut slices = vec![];
+        let mut bytes = 0;
+        for segment in self.segments_from(offset) {
+            let start = segment.seek_offset(offset)?;
+            let mut position = start;
+            let full = loop {
+                let (record_offset, size) = match record::read_header(segment) {
+                    Ok(Some(header)) => header,
+                    Ok(None) => break false,
+                    Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => break false,
+                    Err(e) => return Err(e),
+                };
+                let len = (record::HEADER_SIZE + size as usize) as u64;
+                if record_offset >= end || (bytes + len as usize > max_bytes && bytes > 0) {
+                    break true;
+                }
+                bytes += len as usize;
+                position += len;
+                segment.seek(SeekFrom::Start(position))?;
+            };
+            if position > start {
+                let file = segment.open_file()?;
+                slices.push(FileSlice { file, position: start, len: position - start });
+            }
+            if full {
+                break
+            }
+        }
+        Ok(slices)
+    }
+}
+
+fn not_open() -> Error {
+    Error::new(io::ErrorKind::NotConnected, "partition has no active segment")
+}
+
+fn fenced(producer_id: i64, epoch: i16, current: i16) -> Error {
+    ResponseError::new(
+        ErrorCode::InvalidProducerEpoch,
+        format!("producer {} epoch {} is older than {}", producer_id, epoch, current)
+    ).into()
+}
+
+fn out_of_order(producer_id: i64, expected: i32, sequence: i32) -> Error {
+    ResponseError::new(
+        ErrorCode::OutOfOrderSequenceNumber,
+        format!("producer {} sent sequence {} where {} was expected", producer_id, sequence, expected)
+    ).into()
 }
 
 
@@ -107,38 +964,657 @@ mod tests {
     use std::fs::{create_dir, remove_dir_all, remove_file};
     use std::io::{BufReader, BufWriter, Write, Read, BufRead, Cursor};
     use super::*;
+    use crate::protocol::ErrorCode;
+    use crate::record::Record;
 
     speculate! {
         after {
             remove_dir_all("tmp/");
         }
 
+        // `len` records of a producer, numbered on from `first_sequence`.
+        fn batch(producer_id: i64, epoch: i16, first_sequence: i32, len: i32) -> Vec<Record> {
+            (0..len).map(|i| Record {
+                producer_id,
+                producer_epoch: epoch,
+                sequence: first_sequence.wrapping_add(i),
+                ..Record::new(b"WOMBIEST".to_vec())
+            }).collect()
+        }
+
+        // The same, written inside a transaction.
+        fn transaction(producer_id: i64, first_sequence: i32, len: i32) -> Vec<Record> {
+            batch(producer_id, 0, first_sequence, len).into_iter()
+                .map(|record| Record { attributes: record::TRANSACTIONAL, ..record })
+                .collect()
+        }
+
+        fn offsets(records: Vec<Record>) -> Vec<Offset> {
+            records.iter().map(|record| record.offset).collect()
+        }
+
         test "new partition" {
             let partition = Partition::new(String::from("tmp"), 0).unwrap();
             assert_eq!(partition.partition, 0);
             assert_eq!(partition.topic, "tmp");
         }
 
+        describe "append" {
+            test "append assigns offsets" {
+                let mut partition = Partition::new(String::from("tmp"), 0).unwrap();
+                partition.open_active().expect("open active segment");
+                for expected in 0..5 {
+                    let mut record = Record::new(b"WOMBIEST".to_vec());
+                    assert_eq!(partition.append(&mut record).unwrap(), expected);
+                    assert_eq!(record.offset, expected);
+                }
+                assert_eq!(partition.next_offset(), 5);
+            }
+
+            test "roll by size" {
+                let record_len = Record::new(b"WOMBIEST".to_vec()).encoded_len() as u64;
+                let config = Config { segment_bytes: record_len * 3, ..Config::default() };
+                let mut partition = Partition::with_config(String::from("tmp"), 0, config).unwrap();
+                partition.open_active().expect("open active segment");
+                for _ in 0..7 {
+                    partition.append(&mut Record::new(b"WOMBIEST".to_vec())).unwrap();
+                }
+                assert_eq!(partition.segments.len(), 2);
+                assert_eq!(partition.active.as_ref().unwrap().base_offset(), 6);
+            }
+
+            test "roll by age" {
+                let config = Config { segment_ms: Some(60_000), ..Config::default() };
+                let mut partition = Partition::with_config(String::from("tmp"), 0, config).unwrap();
+                partition.open_active().expect("open active segment");
+                let mut old = Record::new(b"WOMBIEST".to_vec());
+                old.timestamp -= 120_000;
+                partition.append(&mut old).unwrap();
+                partition.append(&mut Record::new(b"WOMBIEST".to_vec())).unwrap();
+                partition.append(&mut Record::new(b"WOMBIEST".to_vec())).unwrap();
+                assert_eq!(partition.segments.len(), 1);
+                assert_eq!(partition.active.as_ref().unwrap().base_offset(), 1);
+            }
+
+            test "reopen resumes the newest segment" {
+                let record_len = Record::new(b"WOMBIEST".to_vec()).encoded_len() as u64;
+                let config = Config { segment_bytes: record_len * 3, ..Config::default() };
+                {
+                    let mut partition = Partition::with_config(String::from("tmp"), 0, config.clone()).unwrap();
+                    partition.open_active().expect("open active segment");
+                    for _ in 0..4 {
+                        partition.append(&mut Record::new(b"WOMBIEST".to_vec())).unwrap();
+                    }
+                }
+                let mut partition = Partition::with_config(String::from("tmp"), 0, config).unwrap();
+                assert!(partition.open_active().expect("open active segment").is_none());
+                assert_eq!(partition.next_offset(), 4);
+                assert_eq!(partition.segments.len(), 1);
+            }
+        }
+
+        describe "retention" {
+            test "retention by size" {
+                let record_len = Record::new(b"WOMBIEST".to_vec()).encoded_len() as u64;
+                let config = Config {
+                    segment_bytes: record_len * 2,
+                    retention_bytes: Some(record_len * 5),
+                    ..Config::default()
+                };
+                let mut partition = Partition::with_config(String::from("tmp"), 0, config).unwrap();
+                partition.open_active().expect("open active segment");
+                for _ in 0..9 {
+                    partition.append(&mut Record::new(b"WOMBIEST".to_vec())).unwrap();
+                }
+                assert_eq!(partition.log_start_offset(), 0);
+
+                let deleted = partition.enforce_retention().expect("enforce retention");
+                assert_eq!(deleted, vec![0, 2]);
+                assert_eq!(partition.log_start_offset(), 4);
+                partition.fill_segments().expect("fill segments");
+                assert_eq!(partition.segments.len(), 3); // the active one included
+            }
+
+            test "reads don't bring deleted segments back" {
+                let record_len = Record::new(b"WOMBIEST".to_vec()).encoded_len() as u64;
+                let config = Config {
+                    segment_bytes: record_len * 2,
+                    retention_bytes: Some(record_len * 2),
+                    ..Config::default()
+                };
+                let mut partition = Partition::with_config(String::from("tmp"), 0, config).unwrap();
+                partition.open_active().expect("open active segment");
+                for _ in 0..6 {
+                    partition.append(&mut Record::new(b"WOMBIEST".to_vec())).unwrap();
+                }
+                assert_eq!(partition.enforce_retention().expect("enforce retention"), vec![0, 2]);
+
+                assert_eq!(offsets(partition.read(0, 6, usize::MAX).unwrap()), vec![4, 5]);
+                assert_eq!(partition.slices(0, 6, usize::MAX).unwrap().len(), 1);
+                partition.fill_segments().expect("fill segments");
+                assert_eq!(partition.log_start_offset(), 4);
+            }
+
+            test "retention by age" {
+                let config = Config {
+                    segment_bytes: 1,
+                    retention_ms: Some(60_000),
+                    ..Config::default()
+                };
+                let mut partition = Partition::with_config(String::from("tmp"), 0, config).unwrap();
+                partition.open_active().expect("open active segment");
+                for age in [180_000, 120_000, 0, 0] {
+                    let mut record = Record::new(b"WOMBIEST".to_vec());
+                    record.timestamp -= age;
+                    partition.append(&mut record).unwrap();
+                }
+
+                let deleted = partition.enforce_retention().expect("enforce retention");
+                assert_eq!(deleted, vec![0, 1]);
+                assert_eq!(partition.log_start_offset(), 2);
+                assert!(partition.enforce_retention().expect("enforce retention").is_empty());
+            }
+        }
+
+        describe "compaction" {
+            test "compaction keeps the newest record per key" {
+                let config = Config {
+                    segment_bytes: 1,
+                    cleanup_policy: CleanupPolicy::Compact,
+                    ..Config::default()
+                };
+                let mut partition = Partition::with_config(String::from("tmp"), 0, config).unwrap();
+                partition.open_active().expect("open active segment");
+                let mut old_tombstone = Record::with_key(b"gone".to_vec(), None);
+                old_tombstone.timestamp -= 2 * DEFAULT_DELETE_RETENTION_MS;
+                let records = vec![
+                    Record::with_key(b"a".to_vec(), Some(b"1".to_vec())),  // 0 superseded
+                    Record::with_key(b"b".to_vec(), Some(b"1".to_vec())),  // 1 deleted below
+                    Record::new(b"keyless".to_vec()),                      // 2 kept
+                    Record::with_key(b"a".to_vec(), Some(b"2".to_vec())),  // 3 kept
+                    Record::with_key(b"b".to_vec(), None),                 // 4 fresh tombstone, kept
+                    old_tombstone,                                         // 5 expired tombstone
+                    Record::with_key(b"a".to_vec(), Some(b"3".to_vec())),  // 6 active, untouched
+                ];
+                for mut record in records {
+                    partition.append(&mut record).unwrap();
+                }
+
+                assert_eq!(partition.compact().expect("compact"), 3);
+
+                let mut offsets = vec![];
+                partition.fill_segments().expect("fill segments");
+                while let Some((_, mut segment)) = partition.segments.pop_first() {
+                    while let Some(record) = segment.read_record().unwrap() {
+                        offsets.push(record.offset);
+                    }
+                }
+                assert_eq!(offsets, vec![2, 3, 4, 6]);
+                assert_eq!(fs::read_dir("tmp/0").unwrap().count(), 7 * 3 + 1, "cleaning dir is gone, the snapshot stays");
+                assert_eq!(partition.compact().expect("compact again"), 0);
+            }
+
+            test "compacted segments are read once they're installed" {
+                let config = Config { cleanup_policy: CleanupPolicy::Compact, ..Config::default() };
+                let mut partition = Partition::with_config(String::from("tmp"), 0, config.clone()).unwrap();
+                partition.open_active().expect("open active segment");
+                for _ in 0..3 {
+                    partition.append(&mut Record::with_key(b"a".to_vec(), Some(b"WOMBIEST".to_vec()))).unwrap();
+                }
+                partition.roll().expect("roll");
+                partition.append(&mut Record::new(b"WOMBIEST".to_vec())).unwrap();
+
+                let cleaner = Partition::with_config(String::from("tmp"), 0, config).unwrap();
+                let cleaned = cleaner.clean().expect("clean");
+                assert_eq!(offsets(partition.read(0, 4, usize::MAX).unwrap()), vec![0, 1, 2, 3]);
+                assert_eq!(partition.install(cleaned).expect("install"), 2);
+                assert_eq!(offsets(partition.read(0, 4, usize::MAX).unwrap()), vec![2, 3]);
+            }
+
+            test "only committed records supersede a key" {
+                let config = Config { cleanup_policy: CleanupPolicy::Compact, ..Config::default() };
+                let mut partition = Partition::with_config(String::from("tmp"), 0, config).unwrap();
+                partition.open_active().expect("open active segment");
+                let overwrite = |sequence: i32| vec![Record {
+                    producer_id: 1,
+                    sequence,
+                    attributes: record::TRANSACTIONAL,
+                    ..Record::with_key(b"a".to_vec(), Some(b"WOODBINE".to_vec()))
+                }];
+                partition.append(&mut Record::with_key(b"a".to_vec(), Some(b"WOMBIEST".to_vec()))).unwrap();
+                partition.append_batch(overwrite(0)).unwrap();
+                partition.end_transaction(1, 0, false).unwrap();
+                partition.append_batch(overwrite(1)).unwrap();
+                partition.roll().expect("roll");
+                partition.append(&mut Record::new(b"WOMBIEST".to_vec())).unwrap();
+
+                assert_eq!(partition.compact().expect("compact"), 1, "only the aborted overwrite goes");
+                let read_committed = |partition: &mut Partition| {
+                    let aborted = partition.aborted_transactions(0, partition.next_offset());
+                    offsets(committed(partition.read(0, partition.next_offset(), usize::MAX).unwrap(), &aborted))
+                };
+                assert_eq!(read_committed(&mut partition), vec![0, 3, 4]);
+
+                partition.end_transaction(1, 0, true).unwrap();
+                partition.roll().expect("roll");
+                assert_eq!(partition.compact().expect("compact once committed"), 1);
+                assert_eq!(read_committed(&mut partition), vec![3, 4]);
+            }
+
+            test "compacted segments can be searched" {
+                let config = Config { cleanup_policy: CleanupPolicy::Compact, ..Config::default() };
+                let mut partition = Partition::with_config(String::from("tmp"), 0, config).unwrap();
+                partition.open_active().expect("open active segment");
+                for i in 0..600 {
+                    let key = format!("{}", i % 10).into_bytes();
+                    partition.append(&mut Record::with_key(key, Some(b"WOMBIEST".to_vec()))).unwrap();
+                }
+                partition.roll().expect("roll");
+
+                assert_eq!(partition.compact().expect("compact"), 590);
+                let mut segment = Segment::new(partition.path.clone(), 0).unwrap();
+                segment.open(Client::Consumer).expect("open segment");
+                segment.seek_offset(300).expect("seek to offset");
+                assert_eq!(segment.read_record().unwrap().unwrap().offset, 590);
+            }
+        }
+
+        describe "idempotence" {
+            test "resent batches are appended once" {
+                let mut partition = Partition::new(String::from("tmp"), 0).unwrap();
+                partition.open_active().expect("open active segment");
+                assert_eq!(partition.append_batch(batch(1, 0, 0, 3)).unwrap(), 0);
+                partition.append(&mut Record::new(b"WOMBIEST".to_vec())).unwrap();
+                assert_eq!(partition.append_batch(batch(1, 0, 3, 2)).unwrap(), 4);
+
+                assert_eq!(partition.append_batch(batch(1, 0, 0, 3)).unwrap(), 0);
+                assert_eq!(partition.append_batch(batch(1, 0, 3, 2)).unwrap(), 4);
+                assert_eq!(partition.next_offset(), 6);
+            }
+
+            test "out of order sequences are rejected" {
+                let mut partition = Partition::new(String::from("tmp"), 0).unwrap();
+                partition.open_active().expect("open active segment");
+                partition.append_batch(batch(1, 0, 0, 2)).unwrap();
+
+                let err = partition.append_batch(batch(1, 0, 5, 1)).unwrap_err();
+                assert_eq!(ErrorCode::for_error(&err), ErrorCode::OutOfOrderSequenceNumber);
+                let err = partition.append_batch(batch(1, 0, 1, 2)).unwrap_err();
+                assert_eq!(ErrorCode::for_error(&err), ErrorCode::OutOfOrderSequenceNumber);
+                assert_eq!(partition.next_offset(), 2);
+            }
+
+            test "sequences wrap around" {
+                let mut partition = Partition::new(String::from("tmp"), 0).unwrap();
+                partition.open_active().expect("open active segment");
+                assert_eq!(partition.append_batch(batch(1, 0, i32::MAX - 1, 3)).unwrap(), 0);
+                assert_eq!(partition.append_batch(batch(1, 0, i32::MIN + 1, 2)).unwrap(), 3);
+                assert_eq!(partition.append_batch(batch(1, 0, i32::MAX, 2)).unwrap(), 1, "resent across the wrap");
+                let err = partition.append_batch(batch(1, 0, i32::MIN + 5, 1)).unwrap_err();
+                assert_eq!(ErrorCode::for_error(&err), ErrorCode::OutOfOrderSequenceNumber);
+                assert_eq!(partition.next_offset(), 5);
+            }
+
+            test "older epochs are fenced" {
+                let mut partition = Partition::new(String::from("tmp"), 0).unwrap();
+                partition.open_active().expect("open active segment");
+                partition.append_batch(batch(1, 0, 0, 2)).unwrap();
+                partition.append_batch(batch(1, 1, 0, 1)).unwrap();
+
+                let err = partition.append_batch(batch(1, 0, 2, 1)).unwrap_err();
+                assert_eq!(ErrorCode::for_error(&err), ErrorCode::InvalidProducerEpoch);
+            }
+
+            test "sequences survive a restart" {
+                {
+                    let mut partition = Partition::new(String::from("tmp"), 0).unwrap();
+                    partition.open_active().expect("open active segment");
+                    partition.append_batch(batch(1, 0, 0, 3)).unwrap();
+                    partition.append_batch(batch(2, 0, 0, 1)).unwrap();
+                    partition.append_batch(batch(1, 0, 3, 2)).unwrap();
+                }
+                let mut partition = Partition::new(String::from("tmp"), 0).unwrap();
+                partition.open_active().expect("reopen active segment");
+                assert_eq!(partition.append_batch(batch(1, 0, 3, 2)).unwrap(), 4);
+                assert_eq!(partition.append_batch(batch(2, 0, 0, 1)).unwrap(), 3);
+                assert_eq!(partition.append_batch(batch(1, 0, 5, 1)).unwrap(), 6);
+                assert_eq!(partition.next_offset(), 7);
+            }
+        }
+
+        describe "transactions" {
+            test "open transactions hold back the last stable offset" {
+                let mut partition = Partition::new(String::from("tmp"), 0).unwrap();
+                partition.open_active().expect("open active segment");
+                partition.append(&mut Record::new(b"WOMBIEST".to_vec())).unwrap();
+                partition.append_batch(transaction(1, 0, 2)).unwrap();
+                partition.append_batch(transaction(2, 0, 1)).unwrap();
+                assert_eq!(partition.last_stable_offset(), 1);
+
+                assert_eq!(partition.end_transaction(1, 0, true).unwrap(), 4);
+                assert_eq!(partition.last_stable_offset(), 3);
+                assert_eq!(partition.ongoing_transactions(), vec![(2, 0)]);
+                partition.end_transaction(2, 0, false).unwrap();
+                assert_eq!(partition.last_stable_offset(), 6);
+                assert!(partition.ongoing_transactions().is_empty());
+            }
+
+            test "aborted records are filtered out" {
+                let mut partition = Partition::new(String::from("tmp"), 0).unwrap();
+                partition.open_active().expect("open active segment");
+                partition.append_batch(transaction(1, 0, 2)).unwrap();
+                partition.append_batch(transaction(2, 0, 1)).unwrap();
+                partition.append(&mut Record::new(b"WOMBIEST".to_vec())).unwrap();
+                partition.end_transaction(1, 0, false).unwrap();
+                partition.end_transaction(2, 0, true).unwrap();
+                partition.append_batch(transaction(1, 2, 1)).unwrap();
+                partition.end_transaction(1, 0, true).unwrap();
+
+                let aborted = partition.aborted_transactions(0, partition.next_offset());
+                assert_eq!(aborted, vec![AbortedTransaction { producer_id: 1, first_offset: 0, last_offset: 4 }]);
+                let records = committed(partition.read(0, partition.next_offset(), usize::MAX).unwrap(), &aborted);
+                assert_eq!(offsets(records), vec![2, 3, 6]);
+            }
+
+            test "transactions survive a restart" {
+                {
+                    let mut partition = Partition::new(String::from("tmp"), 0).unwrap();
+                    partition.open_active().expect("open active segment");
+                    partition.append_batch(transaction(1, 0, 2)).unwrap();
+                    partition.end_transaction(1, 0, false).unwrap();
+                    partition.append_batch(transaction(2, 0, 1)).unwrap();
+                }
+                let mut partition = Partition::new(String::from("tmp"), 0).unwrap();
+                partition.open_active().expect("reopen active segment");
+                assert_eq!(partition.last_stable_offset(), 3);
+                assert_eq!(partition.ongoing_transactions(), vec![(2, 0)]);
+                assert_eq!(partition.aborted_transactions(0, 4).len(), 1);
+            }
+
+            test "non transactional batches can't be mixed in" {
+                let mut partition = Partition::new(String::from("tmp"), 0).unwrap();
+                partition.open_active().expect("open active segment");
+                let mut records = transaction(1, 0, 2);
+                records[1].attributes = 0;
+                let err = partition.append_batch(records).unwrap_err();
+                assert_eq!(ErrorCode::for_error(&err), ErrorCode::InvalidRequest);
+                let err = partition.append_batch(transaction(NO_PRODUCER_ID, 0, 1)).unwrap_err();
+                assert_eq!(ErrorCode::for_error(&err), ErrorCode::InvalidRequest);
+            }
+        }
+
+        describe "snapshots" {
+            test "producers are restored from the snapshot of the last roll" {
+                {
+                    let mut partition = Partition::new(String::from("tmp"), 0).unwrap();
+                    partition.open_active().expect("open active segment");
+                    partition.append_batch(batch(1, 0, 0, 3)).unwrap();
+                    partition.append_batch(transaction(2, 0, 2)).unwrap();
+                    partition.end_transaction(2, 0, false).unwrap();
+                    partition.append_batch(transaction(3, 0, 1)).unwrap();
+                    partition.roll().expect("roll");
+                    partition.append_batch(batch(1, 0, 3, 1)).unwrap();
+                    partition.roll().expect("roll");
+                    assert_eq!(partition.snapshots().unwrap(), vec![8], "older snapshots go");
+                }
+                // what the snapshot holds isn't read again
+                for log in ["tmp/0/00000000000000000000.log", "tmp/0/00000000000000000007.log"] {
+                    OpenOptions::new().write(true).open(log).unwrap().set_len(0).unwrap();
+                }
+                let mut partition = Partition::new(String::from("tmp"), 0).unwrap();
+                partition.open_active().expect("reopen active segment");
+                assert_eq!(partition.append_batch(batch(1, 0, 0, 3)).unwrap(), 0, "resent batches are spotted");
+                assert_eq!(partition.append_batch(batch(1, 0, 3, 1)).unwrap(), 7);
+                assert_eq!(partition.append_batch(batch(1, 0, 4, 1)).unwrap(), 8);
+                assert_eq!(partition.ongoing_transactions(), vec![(3, 0)]);
+                assert_eq!(partition.last_stable_offset(), 6);
+                let aborted = AbortedTransaction { producer_id: 2, first_offset: 3, last_offset: 5 };
+                assert_eq!(partition.aborted_transactions(0, 9), vec![aborted]);
+            }
+
+            test "a damaged snapshot is rebuilt from the log" {
+                {
+                    let mut partition = Partition::new(String::from("tmp"), 0).unwrap();
+                    partition.open_active().expect("open active segment");
+                    partition.append_batch(transaction(1, 0, 2)).unwrap();
+                    partition.roll().expect("roll");
+                    partition.append_batch(batch(2, 0, 0, 1)).unwrap();
+                }
+                fs::write("tmp/0/00000000000000000002.snapshot", b"WOMBIEST").unwrap();
+                let mut partition = Partition::new(String::from("tmp"), 0).unwrap();
+                partition.open_active().expect("reopen active segment");
+                assert_eq!(partition.ongoing_transactions(), vec![(1, 0)]);
+                assert_eq!(partition.last_stable_offset(), 0);
+                assert_eq!(partition.append_batch(batch(2, 0, 0, 1)).unwrap(), 2);
+            }
+
+            test "batches aren't split across segments" {
+                let record_len = Record::new(b"WOMBIEST".to_vec()).encoded_len() as u64;
+                let config = Config { segment_bytes: record_len * 4, ..Config::default() };
+                let mut partition = Partition::with_config(String::from("tmp"), 0, config).unwrap();
+                partition.open_active().expect("open active segment");
+                partition.append_batch(batch(1, 0, 0, 3)).unwrap();
+                partition.append_batch(batch(1, 0, 3, 3)).unwrap();
+                assert_eq!(partition.active.as_ref().unwrap().base_offset(), 3);
+                assert_eq!(partition.snapshots().unwrap(), vec![3]);
+            }
+        }
+
+        describe "read" {
+            test "read spans segments up to the end offset" {
+                let record_len = Record::new(b"WOMBIEST".to_vec()).encoded_len();
+                let config = Config { segment_bytes: record_len as u64 * 3, ..Config::default() };
+                let mut partition = Partition::with_config(String::from("tmp"), 0, config).unwrap();
+                partition.open_active().expect("open active segment");
+                for _ in 0..10 {
+                    partition.append(&mut Record::new(b"WOMBIEST".to_vec())).unwrap();
+                }
+
+                assert_eq!(offsets(partition.read(2, 8, usize::MAX).unwrap()), vec![2, 3, 4, 5, 6, 7]);
+                assert_eq!(offsets(partition.read(4, 10, record_len * 2).unwrap()), vec![4, 5]);
+                assert_eq!(offsets(partition.read(4, 10, 1).unwrap()), vec![4], "always one record");
+                assert!(partition.read(10, 10, usize::MAX).unwrap().is_empty());
+            }
+
+            test "read skips compacted offsets" {
+                let config = Config { cleanup_policy: CleanupPolicy::Compact, ..Config::default() };
+                let mut partition = Partition::with_config(String::from("tmp"), 0, config).unwrap();
+                partition.open_active().expect("open active segment");
+                for key in [b"a", b"b", b"a", b"a"].iter() {
+                    partition.append(&mut Record::with_key(key.to_vec(), Some(b"WOMBIEST".to_vec()))).unwrap();
+                }
+                partition.roll().expect("roll");
+                partition.append(&mut Record::new(b"WOMBIEST".to_vec())).unwrap();
+                partition.compact().expect("compact");
+
+                assert_eq!(offsets(partition.read(0, 5, usize::MAX).unwrap()), vec![1, 3, 4]);
+            }
+
+            test "slices hold the bytes of the records read" {
+                let record_len = Record::new(b"WOMBIEST".to_vec()).encoded_len();
+                let config = Config { segment_bytes: record_len as u64 * 3, ..Config::default() };
+                let mut partition = Partition::with_config(String::from("tmp"), 0, config).unwrap();
+                partition.open_active().expect("open active segment");
+                for _ in 0..10 {
+                    partition.append(&mut Record::new(b"WOMBIEST".to_vec())).unwrap();
+                }
+
+                for &(offset, end, max_bytes) in &[(2, 8, usize::MAX), (4, 10, record_len * 2), (4, 10, 1), (10, 10, usize::MAX)] {
+                    let mut expected = vec![];
+                    for record in partition.read(offset, end, max_bytes).unwrap() {
+                        record.write_to(&mut expected).unwrap();
+                    }
+                    let slices = partition.slices(offset, end, max_bytes).unwrap();
+                    let mut bytes = vec![];
+                    crate::transfer::copy_to(&slices, &mut bytes).unwrap();
+                    assert_eq!(bytes, expected, "{}..{} in {} bytes", offset, end, max_bytes);
+                }
+                assert_eq!(partition.slices(2, 8, usize::MAX).unwrap().len(), 3, "one slice per segment");
+            }
+        }
+
+        describe "compression" {
+            // A batch as a producer compresses it.
+            fn compressed(records: Vec<Record>, compression: Compression) -> Vec<Record> {
+                let records: Vec<Record> = records.into_iter().zip(0..).map(|(record, offset)| Record { offset, ..record }).collect();
+                vec![Record::compress(&records, compression).unwrap()]
+            }
+
+            fn wombats(n: usize) -> Vec<Record> {
+                (0..n).map(|_| Record::new(b"WOMBIEST".to_vec())).collect()
+            }
+
+            // (offset, codec) of every frame in the log, oldest first.
+            fn frames(partition: &Partition) -> Vec<(Offset, Compression)> {
+                let mut frames = vec![];
+                for base in partition.sorted_bases().unwrap() {
+                    let mut segment = Segment::new(partition.path.clone(), base).unwrap();
+                    segment.open(Client::Consumer).expect("open segment");
+                    while let Some(frame) = segment.read_record().unwrap() {
+                        frames.push((frame.offset, Compression::from_attributes(frame.attributes).unwrap()));
+                    }
+                }
+                frames
+            }
+
+            test "compressed batches are stored as they came" {
+                let mut partition = Partition::new(String::from("tmp"), 0).unwrap();
+                partition.open_active().expect("open active segment");
+                assert_eq!(partition.append_batch(compressed(wombats(3), Compression::Gzip)).unwrap(), 0);
+                partition.append(&mut Record::new(b"WOMBIEST".to_vec())).unwrap();
+                assert_eq!(partition.append_batch(compressed(wombats(2), Compression::Lz4)).unwrap(), 4);
+                assert_eq!(partition.next_offset(), 6);
+                assert_eq!(frames(&partition), vec![(2, Compression::Gzip), (3, Compression::None), (5, Compression::Lz4)]);
+
+                assert_eq!(offsets(partition.read(1, 5, usize::MAX).unwrap()), vec![1, 2, 3, 4], "batches are cut to the offsets asked for");
+            }
+
+            test "batches decompressing beyond the limit are refused" {
+                let mut partition = Partition::new(String::from("tmp"), 0).unwrap();
+                partition.open_active().expect("open active segment");
+                let bomb = Compression::Zstd.compress(&vec![0; crate::compression::MAX_DECOMPRESSED_SIZE + 1]).unwrap();
+                let mut wrapper = Record::new(bomb);
+                wrapper.attributes |= Compression::Zstd.attributes();
+                let error = partition.append_batch(vec![wrapper]).unwrap_err();
+                assert_eq!(ErrorCode::for_error(&error), ErrorCode::CorruptMessage);
+                assert_eq!(partition.next_offset(), 0);
+            }
+
+            test "topics compress batches with their own codec" {
+                let config = Config { compression: Some(Compression::Zstd), ..Config::default() };
+                let mut partition = Partition::with_config(String::from("tmp"), 0, config).unwrap();
+                partition.open_active().expect("open active segment");
+                partition.append_batch(wombats(3)).unwrap();
+                partition.append_batch(compressed(wombats(2), Compression::Snappy)).unwrap();
+                assert_eq!(frames(&partition), vec![(2, Compression::Zstd), (4, Compression::Zstd)]);
+
+                partition.config.compression = Some(Compression::None);
+                partition.append_batch(compressed(wombats(2), Compression::Gzip)).unwrap();
+                assert_eq!(frames(&partition)[2..], [(5, Compression::None), (6, Compression::None)]);
+                assert_eq!(partition.read(0, 7, usize::MAX).unwrap().len(), 7);
+            }
+
+            test "producers are followed into compressed batches" {
+                let sent = |first_sequence: i32| compressed(batch(1, 0, first_sequence, 2), Compression::Gzip);
+                {
+                    let mut partition = Partition::new(String::from("tmp"), 0).unwrap();
+                    partition.open_active().expect("open active segment");
+                    partition.append_batch(sent(0)).unwrap();
+                    let err = partition.append_batch(sent(3)).unwrap_err();
+                    assert_eq!(ErrorCode::for_error(&err), ErrorCode::OutOfOrderSequenceNumber);
+                }
+                let mut partition = Partition::new(String::from("tmp"), 0).unwrap();
+                partition.open_active().expect("reopen active segment");
+                assert_eq!(partition.append_batch(sent(0)).unwrap(), 0, "resent batches are spotted");
+                assert_eq!(partition.append_batch(sent(2)).unwrap(), 2);
+            }
+
+            test "compaction compresses what's left of a batch" {
+                let config = Config { cleanup_policy: CleanupPolicy::Compact, ..Config::default() };
+                let mut partition = Partition::with_config(String::from("tmp"), 0, config).unwrap();
+                partition.open_active().expect("open active segment");
+                let keyed = |keys: &[&str]| keys.iter()
+                    .map(|key| Record::with_key(key.as_bytes().to_vec(), Some(b"WOMBIEST".to_vec())))
+                    .collect::<Vec<Record>>();
+                partition.append_batch(compressed(keyed(&["a", "b", "a", "c"]), Compression::Snappy)).unwrap();
+                partition.append_batch(compressed(keyed(&["b", "c"]), Compression::Snappy)).unwrap();
+                partition.roll().expect("roll");
+
+                assert_eq!(partition.compact().expect("compact"), 3);
+                assert_eq!(frames(&partition), vec![(2, Compression::Snappy), (5, Compression::Snappy)]);
+                assert_eq!(offsets(partition.read(0, 6, usize::MAX).unwrap()), vec![2, 4, 5]);
+            }
+
+            test "timestamps are found inside compressed batches" {
+                let mut partition = Partition::new(String::from("tmp"), 0).unwrap();
+                partition.open_active().expect("open active segment");
+                let stamped: Vec<Record> = (0..5).map(|i| Record { timestamp: i * 100, ..Record::new(b"WOMBIEST".to_vec()) }).collect();
+                partition.append_batch(compressed(stamped, Compression::Gzip)).unwrap();
+                assert_eq!(partition.offset_for_timestamp(250).unwrap(), Some(3));
+                assert_eq!(partition.offset_for_timestamp(500).unwrap(), None);
+            }
+        }
+
         describe "fill segments" {
+            #[allow(clippy::assertions_on_constants)]
             test "fill segments" {
                 let mut partition = Partition::new(String::from("tmp"), 0).unwrap();
                 {
                     Segment::new(partition.path.clone(), 0).expect("new segment")
-                        .open(Client::Consumer).expect("open segment");
+                        .open(Client::Producer).expect("open segment");
                     Segment::new(partition.path.clone(), 1).expect("new segment")
-                        .open(Client::Consumer).expect("open segment");
+                        .open(Client::Producer).expect("open segment");
                 }
 
                 partition.fill_segments().expect("fill segments");
 
                 assert_eq!(partition.segments.len(), 2);
-                if let Some(segment) = partition.segments.pop() {
+                if let Some((_, segment)) = partition.segments.pop_first() {
                     assert_eq!(
                         Segment::new(String::from("tmp/0000000000000000000000.log"), 0).unwrap(), segment)
                 } else {
                     assert!(false)
                 }
             }
+
+            test "stray log files are left out" {
+                let mut partition = Partition::new(String::from("tmp"), 0).unwrap();
+                Segment::new(partition.path.clone(), 0).expect("new segment")
+                    .open(Client::Producer).expect("open segment");
+                fs::write(format!("{}/broker.log", partition.path), b"WOMBIEST").unwrap();
+
+                partition.fill_segments().expect("fill segments");
+                assert_eq!(partition.segments.keys().copied().collect::<Vec<_>>(), vec![0]);
+            }
+
+            test "offset for timestamp spans segments" {
+                let mut partition = Partition::new(String::from("tmp"), 0).unwrap();
+                for base_offset in [0, 10] {
+                    let mut segment = Segment::new(partition.path.clone(), base_offset).expect("new segment");
+                    segment.open(Client::Producer).expect("open segment");
+                    for offset in base_offset..base_offset + 10 {
+                        let mut record = Record::new(b"WOMBIEST".to_vec());
+                        record.offset = offset;
+                        record.timestamp = offset as i64 * 100;
+                        segment.append(&record).expect("append record");
+                    }
+                }
+                partition.fill_segments().expect("fill segments");
+
+                assert_eq!(partition.offset_for_timestamp(250).unwrap(), Some(3));
+                assert_eq!(partition.offset_for_timestamp(1_500).unwrap(), Some(15));
+                assert_eq!(partition.offset_for_timestamp(2_000).unwrap(), None);
+            }
+
+            test "fill segments skips indexes" {
+                let mut partition = Partition::new(String::from("tmp"), 0).unwrap();
+                Segment::new(partition.path.clone(), 0).expect("new segment")
+                    .open(Client::Producer).expect("open segment");
+
+                partition.fill_segments().expect("fill segments");
+
+                assert_eq!(partition.segments.len(), 1);
+            }
         }
     }
 }
@@ -0,0 +1,162 @@
+// Partitioners pick the partition of a topic a record is produced to.
+// Keyed records are placed by a murmur2 hash of their key, compatible
+// with the Java client, so every producer sends a key to the same
+// partition as long as the partition count doesn't change.
+
+
+pub trait Partitioner {
+    /// Partition in `0..partitions` the record with `key` goes to.
+    fn partition(&mut self, key: Option<&[u8]>, partitions: u32) -> u32;
+
+    /// Called by producers once they've sent off what they had buffered.
+    fn on_new_batch(&mut self) {}
+}
+
+
+/// Hashes keys and sticks keyless records to one partition until the
+/// producer starts a new batch, so batches fill up before moving on.
+#[derive(Debug, Default)]
+pub struct DefaultPartitioner {
+    next: u32,
+    sticky: Option<u32>,
+}
+
+impl DefaultPartitioner {
+    pub fn new() -> DefaultPartitioner {
+        DefaultPartitioner::default()
+    }
+}
+
+impl Partitioner for DefaultPartitioner {
+    fn partition(&mut self, key: Option<&[u8]>, partitions: u32) -> u32 {
+        if let Some(key) = key {
+            return partition_for_key(key, partitions);
+        }
+        match self.sticky {
+            Some(partition) if partition < partitions => partition,
+            _ => {
+                let partition = self.next % partitions;
+                self.sticky = Some(partition);
+                partition
+            },
+        }
+    }
+
+    fn on_new_batch(&mut self) {
+        if self.sticky.take().is_some() {
+            self.next = self.next.wrapping_add(1);
+        }
+    }
+}
+
+
+/// Hashes keys and spreads keyless records over the partitions in turn.
+#[derive(Debug, Default)]
+pub struct RoundRobinPartitioner {
+    next: u32,
+}
+
+impl RoundRobinPartitioner {
+    pub fn new() -> RoundRobinPartitioner {
+        RoundRobinPartitioner::default()
+    }
+}
+
+impl Partitioner for RoundRobinPartitioner {
+    fn partition(&mut self, key: Option<&[u8]>, partitions: u32) -> u32 {
+        if let Some(key) = key {
+            return partition_for_key(key, partitions);
+        }
+        let partition = self.next % partitions;
+        self.next = self.next.wrapping_add(1);
+        partition
+    }
+}
+
+
+pub fn partition_for_key(key: &[u8], partitions: u32) -> u32 {
+    // same as the Java client's toPositive, which masks rather than abs()
+    (murmur2(key) & 0x7fff_ffff) as u32 % partitions
+}
+
+/// The 32 bit murmur2 hash, seeded like the Java client's `Utils.murmur2`.
+pub fn murmur2(data: &[u8]) -> i32 {
+    const SEED: u32 = 0x9747_b28c;
+    const M: u32 = 0x5bd1_e995;
+    const R: u32 = 24;
+
+    let length = data.len();
+    let mut h = SEED ^ length as u32;
+    let mut chunks = data.chunks_exact(4);
+    for chunk in &mut chunks {
+        let mut k = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+        k = k.wrapping_mul(M);
+        k ^= k >> R;
+        k = k.wrapping_mul(M);
+        h = h.wrapping_mul(M);
+        h ^= k;
+    }
+    let tail = chunks.remainder();
+    if tail.len() >= 3 {
+        h ^= (tail[2] as u32) << 16;
+    }
+    if tail.len() >= 2 {
+        h ^= (tail[1] as u32) << 8;
+    }
+    if !tail.is_empty() {
+        h ^= tail[0] as u32;
+        h = h.wrapping_mul(M);
+    }
+    h ^= h >> 13;
+    h = h.wrapping_mul(M);
+    h ^= h >> 15;
+    h as i32
+}
+
+
+#[cfg(test)]
+extern crate speculate;
+
+#[cfg(test)]
+mod tests {
+    use speculate::speculate;
+    use super::*;
+
+    speculate! {
+        test "murmur2 matches the java client" {
+            assert_eq!(murmur2(b"21"), -973_932_308);
+            assert_eq!(murmur2(b"foobar"), -790_332_482);
+            assert_eq!(murmur2(b"a-little-bit-long-string"), -985_981_536);
+            assert_eq!(murmur2(b"a-little-bit-longer-string"), -1_486_304_829);
+            assert_eq!(murmur2(b"lkjh234lh9fiuh90y23oiuhsafujhadof229phr9h19h89h8"), -58_897_971);
+            assert_eq!(murmur2(b"abc"), 479_470_107);
+        }
+
+        test "keys always land on the same partition" {
+            let mut partitioner = DefaultPartitioner::new();
+            let first = partitioner.partition(Some(b"WOMBAT"), 7);
+            for _ in 0..10 {
+                partitioner.on_new_batch();
+                assert_eq!(partitioner.partition(Some(b"WOMBAT"), 7), first);
+            }
+            assert_eq!(RoundRobinPartitioner::new().partition(Some(b"WOMBAT"), 7), first);
+        }
+
+        test "keyless records stick until a new batch" {
+            let mut partitioner = DefaultPartitioner::new();
+            assert_eq!(partitioner.partition(None, 3), 0);
+            assert_eq!(partitioner.partition(None, 3), 0);
+            partitioner.on_new_batch();
+            assert_eq!(partitioner.partition(None, 3), 1);
+            partitioner.on_new_batch();
+            partitioner.on_new_batch();
+            assert_eq!(partitioner.partition(None, 3), 2);
+        }
+
+        test "keyless records go round robin" {
+            let mut partitioner = RoundRobinPartitioner::new();
+            let partitions: Vec<u32> = (0..5).map(|_| partitioner.partition(None, 3)).collect();
+            assert_eq!(partitions, vec![0, 1, 2, 0, 1]);
+        }
+    }
+}
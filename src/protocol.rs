@@ -0,0 +1,1294 @@
+// Clients talk to the broker in size prefixed frames. A request is
+//
+//   size: i32 | api_key: i16 | api_version: i16 | correlation_id: i32 |
+//   client_id: string | body
+//
+// and the broker answers every request but a produce with acks 0, in the
+// order they arrived, with
+//
+//   size: i32 | correlation_id: i32 | error_code: i16 | error_message: string | body
+//
+// where the body is left out unless the error code is NONE. `size` counts
+// the bytes following it, strings are an i16 length and UTF-8 (-1 is null)
+// and record sets are an i32 length followed by framed records. Clients may
+// send several requests before reading the responses and match them up by
+// correlation id.
+//
+// Each request type has its own version, so it can change without breaking
+// clients built against an older broker. A client starts by asking the
+// broker which versions it supports with an ApiVersions request, which is
+// always understood at version 0, and then uses the newest version both
+// sides know of.
+//
+// A transactional producer registers its transactional id with
+// InitProducerId, which fences off older producers with the same id, names
+// every partition it is about to write to with AddPartitionsToTxn and ends
+// the transaction with EndTxn, which writes a commit or abort marker to all
+// of them. Fetches at the read committed isolation level only go up to the
+// last stable offset and list the aborted transactions the consumer has to
+// drop records of.
+//
+// The consumers of a group join it with JoinGroup, which only returns once
+// the group rebalanced, fetch the partitions the broker assigned them with
+// SyncGroup, keep their membership alive with Heartbeat and go with
+// LeaveGroup. A heartbeat failing with REBALANCE_IN_PROGRESS means the
+// member has to join again.
+//
+// OffsetCommit stores how far a group got in some partitions and
+// OffsetFetch reads it back, -1 meaning nothing was committed. A commit
+// from a member must name the current generation of its group, one with
+// generation -1 and an empty member id comes from outside the group.
+use std::{fmt, io};
+use std::io::{Read, Write, Error, ErrorKind};
+
+use byteorder::{ReadBytesExt, WriteBytesExt, NetworkEndian};
+
+use crate::partition::AbortedTransaction;
+use crate::record::Record;
+use crate::segment::Offset;
+
+
+pub const MAX_FRAME_SIZE: usize = 64 << 20;
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[repr(i16)]
+pub enum ApiKey {
+    Produce = 0,
+    Fetch = 1,
+    ListOffsets = 2,
+    Metadata = 3,
+    OffsetCommit = 8,
+    OffsetFetch = 9,
+    JoinGroup = 11,
+    Heartbeat = 12,
+    LeaveGroup = 13,
+    SyncGroup = 14,
+    ApiVersions = 18,
+    InitProducerId = 22,
+    AddPartitionsToTxn = 24,
+    EndTxn = 26,
+}
+
+impl ApiKey {
+    pub const ALL: [ApiKey; 14] = [
+        ApiKey::Produce, ApiKey::Fetch, ApiKey::ListOffsets, ApiKey::Metadata, ApiKey::OffsetCommit,
+        ApiKey::OffsetFetch, ApiKey::JoinGroup, ApiKey::Heartbeat, ApiKey::LeaveGroup,
+        ApiKey::SyncGroup, ApiKey::ApiVersions, ApiKey::InitProducerId, ApiKey::AddPartitionsToTxn,
+        ApiKey::EndTxn,
+    ];
+
+    pub fn from_i16(key: i16) -> Option<ApiKey> {
+        ApiKey::ALL.iter().copied().find(|&api_key| api_key as i16 == key)
+    }
+
+    /// Oldest and newest version of the request this build understands.
+    pub fn versions(self) -> (i16, i16) {
+        match self {
+            // v1 adds acks to the request and the base offset to the response
+            ApiKey::Produce => (0, 1),
+            // v1 adds the isolation level, the last stable offset and aborted transactions,
+            // v2 lets the broker wait for records
+            ApiKey::Fetch => (0, 2),
+            ApiKey::ListOffsets => (0, 0),
+            ApiKey::Metadata => (0, 0),
+            ApiKey::OffsetCommit => (0, 0),
+            ApiKey::OffsetFetch => (0, 0),
+            ApiKey::JoinGroup => (0, 0),
+            ApiKey::Heartbeat => (0, 0),
+            ApiKey::LeaveGroup => (0, 0),
+            ApiKey::SyncGroup => (0, 0),
+            ApiKey::ApiVersions => (0, 0),
+            // v1 adds the transactional id
+            ApiKey::InitProducerId => (0, 1),
+            ApiKey::AddPartitionsToTxn => (0, 0),
+            ApiKey::EndTxn => (0, 0),
+        }
+    }
+}
+
+
+/// A request type the broker supports and the range of its versions.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ApiVersion {
+    pub api_key: i16,
+    pub min_version: i16,
+    pub max_version: i16,
+}
+
+impl ApiVersion {
+    /// Every request type this build supports.
+    pub fn supported() -> Vec<ApiVersion> {
+        ApiKey::ALL.iter().map(|&api_key| {
+            let (min_version, max_version) = api_key.versions();
+            ApiVersion { api_key: api_key as i16, min_version, max_version }
+        }).collect()
+    }
+}
+
+/// The newest version of `api_key` both this build and a broker that
+/// answered ApiVersions with `broker_versions` support.
+pub fn negotiate_version(broker_versions: &[ApiVersion], api_key: ApiKey) -> io::Result<i16> {
+    let (min_version, max_version) = api_key.versions();
+    let unsupported = || Error::from(ResponseError::new(
+        ErrorCode::UnsupportedVersion,
+        format!("broker supports no version of {:?} between {} and {}", api_key, min_version, max_version)
+    ));
+    let broker = broker_versions.iter().find(|v| v.api_key == api_key as i16).ok_or_else(unsupported)?;
+    let version = max_version.min(broker.max_version);
+    if version < min_version.max(broker.min_version) {
+        return Err(unsupported());
+    }
+    Ok(version)
+}
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(i16)]
+pub enum ErrorCode {
+    None = 0,
+    Unknown = -1,
+    OffsetOutOfRange = 1,
+    CorruptMessage = 2,
+    UnknownTopicOrPartition = 3,
+    IllegalGeneration = 22,
+    InconsistentGroupProtocol = 23,
+    UnknownMemberId = 25,
+    RebalanceInProgress = 27,
+    UnsupportedVersion = 35,
+    InvalidRequest = 42,
+    OutOfOrderSequenceNumber = 45,
+    InvalidProducerEpoch = 47,
+    InvalidTxnState = 48,
+    InvalidProducerIdMapping = 49,
+}
+
+impl ErrorCode {
+    pub fn from_i16(code: i16) -> ErrorCode {
+        match code {
+            0 => ErrorCode::None,
+            1 => ErrorCode::OffsetOutOfRange,
+            2 => ErrorCode::CorruptMessage,
+            3 => ErrorCode::UnknownTopicOrPartition,
+            22 => ErrorCode::IllegalGeneration,
+            23 => ErrorCode::InconsistentGroupProtocol,
+            25 => ErrorCode::UnknownMemberId,
+            27 => ErrorCode::RebalanceInProgress,
+            35 => ErrorCode::UnsupportedVersion,
+            42 => ErrorCode::InvalidRequest,
+            45 => ErrorCode::OutOfOrderSequenceNumber,
+            47 => ErrorCode::InvalidProducerEpoch,
+            48 => ErrorCode::InvalidTxnState,
+            49 => ErrorCode::InvalidProducerIdMapping,
+            _ => ErrorCode::Unknown,
+        }
+    }
+
+    /// The code telling a client about `error`, which keeps its own code
+    /// if it came from a `ResponseError`.
+    pub fn for_error(error: &Error) -> ErrorCode {
+        if let Some(response_error) = error.get_ref().and_then(|e| e.downcast_ref::<ResponseError>()) {
+            return response_error.code;
+        }
+        match error.kind() {
+            ErrorKind::NotFound => ErrorCode::UnknownTopicOrPartition,
+            ErrorKind::InvalidData => ErrorCode::CorruptMessage,
+            ErrorKind::InvalidInput => ErrorCode::InvalidRequest,
+            _ => ErrorCode::Unknown,
+        }
+    }
+
+    fn kind(self) -> ErrorKind {
+        match self {
+            ErrorCode::UnknownTopicOrPartition => ErrorKind::NotFound,
+            ErrorCode::CorruptMessage => ErrorKind::InvalidData,
+            ErrorCode::OffsetOutOfRange | ErrorCode::InvalidRequest
+                | ErrorCode::OutOfOrderSequenceNumber | ErrorCode::InvalidTxnState
+                | ErrorCode::IllegalGeneration | ErrorCode::InconsistentGroupProtocol
+                | ErrorCode::UnknownMemberId => ErrorKind::InvalidInput,
+            ErrorCode::InvalidProducerEpoch | ErrorCode::InvalidProducerIdMapping => ErrorKind::PermissionDenied,
+            ErrorCode::UnsupportedVersion => ErrorKind::Unsupported,
+            _ => ErrorKind::Other,
+        }
+    }
+}
+
+
+/// A request the broker turned down. Converts into an `io::Error`
+/// the code can be recovered from with `ErrorCode::for_error`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ResponseError {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl ResponseError {
+    pub fn new<S: Into<String>>(code: ErrorCode, message: S) -> ResponseError {
+        ResponseError { code, message: message.into() }
+    }
+
+    /// What to tell a client about `error`.
+    pub fn for_error(error: &Error) -> ResponseError {
+        match error.get_ref().and_then(|e| e.downcast_ref::<ResponseError>()) {
+            Some(response_error) => response_error.clone(),
+            None => ResponseError::new(ErrorCode::for_error(error), error.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for ResponseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}: {}", self.code, self.message)
+    }
+}
+
+impl std::error::Error for ResponseError {}
+
+impl From<ResponseError> for Error {
+    fn from(error: ResponseError) -> Error {
+        Error::new(error.code.kind(), error)
+    }
+}
+
+
+/// How much of a produce the broker must have done before answering.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Acks {
+    // don't answer at all
+    None,
+    // answer once the records are written to the segment
+    Leader,
+    // answer once they are synced to disk
+    All,
+}
+
+impl Acks {
+    fn from_i16(acks: i16) -> io::Result<Acks> {
+        match acks {
+            0 => Ok(Acks::None),
+            1 => Ok(Acks::Leader),
+            -1 => Ok(Acks::All),
+            _ => Err(ResponseError::new(ErrorCode::InvalidRequest, format!("invalid acks {}", acks)).into()),
+        }
+    }
+
+    fn as_i16(self) -> i16 {
+        match self {
+            Acks::None => 0,
+            Acks::Leader => 1,
+            Acks::All => -1,
+        }
+    }
+}
+
+
+/// Which records a fetch returns.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IsolationLevel {
+    // everything that is flushed
+    ReadUncommitted,
+    // only up to the last stable offset
+    ReadCommitted,
+}
+
+impl IsolationLevel {
+    fn from_i8(level: i8) -> io::Result<IsolationLevel> {
+        match level {
+            0 => Ok(IsolationLevel::ReadUncommitted),
+            1 => Ok(IsolationLevel::ReadCommitted),
+            _ => Err(ResponseError::new(
+                ErrorCode::InvalidRequest, format!("invalid isolation level {}", level)
+            ).into()),
+        }
+    }
+
+    fn as_i8(self) -> i8 {
+        match self {
+            IsolationLevel::ReadUncommitted => 0,
+            IsolationLevel::ReadCommitted => 1,
+        }
+    }
+}
+
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RequestHeader {
+    pub api_key: i16,
+    pub api_version: i16,
+    pub correlation_id: i32,
+    pub client_id: Option<String>,
+}
+
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Request {
+    // append records, the broker assigns their offsets
+    Produce { topic: String, partition: u32, acks: Acks, records: Vec<Record> },
+    // flushed records from `offset` on, about `max_bytes` of them, waiting
+    // up to `max_wait_ms` for at least `min_bytes` of them
+    Fetch {
+        topic: String,
+        partition: u32,
+        offset: Offset,
+        max_bytes: u32,
+        isolation_level: IsolationLevel,
+        max_wait_ms: u32,
+        min_bytes: u32,
+    },
+    // first offset stamped at or after `timestamp`
+    ListOffsets { topic: String, partition: u32, timestamp: i64 },
+    // how many partitions a topic has
+    Metadata { topic: String },
+    // join a consumer group, `member_id` is empty the first time
+    JoinGroup {
+        group_id: String,
+        member_id: String,
+        session_timeout_ms: u32,
+        topics: Vec<String>,
+        assignors: Vec<String>,
+    },
+    // the partitions assigned to the member in the generation
+    SyncGroup { group_id: String, generation_id: i32, member_id: String },
+    Heartbeat { group_id: String, generation_id: i32, member_id: String },
+    LeaveGroup { group_id: String, member_id: String },
+    // (topic, partition, offset) to resume each partition at
+    OffsetCommit { group_id: String, generation_id: i32, member_id: String, offsets: Vec<(String, u32, Offset)> },
+    OffsetFetch { group_id: String, partitions: Vec<(String, u32)> },
+    // which request types and versions the broker supports
+    ApiVersions,
+    // a producer id for an idempotent producer to stamp its records with,
+    // and a new epoch of the one registered for a transactional id
+    InitProducerId { transactional_id: Option<String> },
+    // partitions the producer's ongoing transaction is about to write to
+    AddPartitionsToTxn { transactional_id: String, producer_id: i64, producer_epoch: i16, partitions: Vec<(String, u32)> },
+    // commit or abort the producer's ongoing transaction
+    EndTxn { transactional_id: String, producer_id: i64, producer_epoch: i16, commit: bool },
+}
+
+impl Request {
+    pub fn api_key(&self) -> ApiKey {
+        match self {
+            Request::Produce { .. } => ApiKey::Produce,
+            Request::Fetch { .. } => ApiKey::Fetch,
+            Request::ListOffsets { .. } => ApiKey::ListOffsets,
+            Request::Metadata { .. } => ApiKey::Metadata,
+            Request::JoinGroup { .. } => ApiKey::JoinGroup,
+            Request::SyncGroup { .. } => ApiKey::SyncGroup,
+            Request::Heartbeat { .. } => ApiKey::Heartbeat,
+            Request::LeaveGroup { .. } => ApiKey::LeaveGroup,
+            Request::OffsetCommit { .. } => ApiKey::OffsetCommit,
+            Request::OffsetFetch { .. } => ApiKey::OffsetFetch,
+            Request::ApiVersions => ApiKey::ApiVersions,
+            Request::InitProducerId { .. } => ApiKey::InitProducerId,
+            Request::AddPartitionsToTxn { .. } => ApiKey::AddPartitionsToTxn,
+            Request::EndTxn { .. } => ApiKey::EndTxn,
+        }
+    }
+
+    /// Whether the broker answers this request.
+    pub fn expects_response(&self) -> bool {
+        !matches!(self, Request::Produce { acks: Acks::None, .. })
+    }
+
+    fn encode(&self, api_version: i16, buf: &mut Vec<u8>) -> io::Result<()> {
+        match self {
+            Request::Produce { topic, partition, acks, records } => {
+                write_string(buf, Some(topic))?;
+                buf.write_u32::<NetworkEndian>(*partition)?;
+                if api_version >= 1 {
+                    buf.write_i16::<NetworkEndian>(acks.as_i16())?;
+                } else if *acks != Acks::Leader {
+                    return Err(Error::from(ResponseError::new(
+                        ErrorCode::UnsupportedVersion, "the broker is too old to choose acks"
+                    )));
+                }
+                write_records(buf, records)
+            },
+            Request::Fetch { topic, partition, offset, max_bytes, isolation_level, max_wait_ms, min_bytes } => {
+                write_string(buf, Some(topic))?;
+                buf.write_u32::<NetworkEndian>(*partition)?;
+                buf.write_u64::<NetworkEndian>(*offset)?;
+                buf.write_u32::<NetworkEndian>(*max_bytes)?;
+                if api_version >= 1 {
+                    buf.write_i8(isolation_level.as_i8())?;
+                } else if *isolation_level != IsolationLevel::ReadUncommitted {
+                    return Err(Error::from(ResponseError::new(
+                        ErrorCode::UnsupportedVersion, "the broker is too old to read committed"
+                    )));
+                }
+                // older brokers answer right away, which is only sooner
+                if api_version >= 2 {
+                    buf.write_u32::<NetworkEndian>(*max_wait_ms)?;
+                    buf.write_u32::<NetworkEndian>(*min_bytes)?;
+                }
+                Ok(())
+            },
+            Request::ListOffsets { topic, partition, timestamp } => {
+                write_string(buf, Some(topic))?;
+                buf.write_u32::<NetworkEndian>(*partition)?;
+                buf.write_i64::<NetworkEndian>(*timestamp)
+            },
+            Request::Metadata { topic } => write_string(buf, Some(topic)),
+            Request::JoinGroup { group_id, member_id, session_timeout_ms, topics, assignors } => {
+                write_string(buf, Some(group_id))?;
+                write_string(buf, Some(member_id))?;
+                buf.write_u32::<NetworkEndian>(*session_timeout_ms)?;
+                write_strings(buf, topics)?;
+                write_strings(buf, assignors)
+            },
+            Request::SyncGroup { group_id, generation_id, member_id }
+                | Request::Heartbeat { group_id, generation_id, member_id } => {
+                write_string(buf, Some(group_id))?;
+                buf.write_i32::<NetworkEndian>(*generation_id)?;
+                write_string(buf, Some(member_id))
+            },
+            Request::LeaveGroup { group_id, member_id } => {
+                write_string(buf, Some(group_id))?;
+                write_string(buf, Some(member_id))
+            },
+            Request::OffsetCommit { group_id, generation_id, member_id, offsets } => {
+                write_string(buf, Some(group_id))?;
+                buf.write_i32::<NetworkEndian>(*generation_id)?;
+                write_string(buf, Some(member_id))?;
+                buf.write_i32::<NetworkEndian>(offsets.len() as i32)?;
+                for (topic, partition, offset) in offsets {
+                    write_string(buf, Some(topic))?;
+                    buf.write_u32::<NetworkEndian>(*partition)?;
+                    buf.write_u64::<NetworkEndian>(*offset)?;
+                }
+                Ok(())
+            },
+            Request::OffsetFetch { group_id, partitions } => {
+                write_string(buf, Some(group_id))?;
+                write_partitions(buf, partitions)
+            },
+            Request::ApiVersions => Ok(()),
+            Request::InitProducerId { transactional_id } => match (api_version, transactional_id) {
+                (0, None) => Ok(()),
+                (0, Some(_)) => Err(Error::from(ResponseError::new(
+                    ErrorCode::UnsupportedVersion, "the broker is too old for transactions"
+                ))),
+                (_, transactional_id) => write_string(buf, transactional_id.as_deref()),
+            },
+            Request::AddPartitionsToTxn { transactional_id, producer_id, producer_epoch, partitions } => {
+                write_string(buf, Some(transactional_id))?;
+                buf.write_i64::<NetworkEndian>(*producer_id)?;
+                buf.write_i16::<NetworkEndian>(*producer_epoch)?;
+                write_partitions(buf, partitions)
+            },
+            Request::EndTxn { transactional_id, producer_id, producer_epoch, commit } => {
+                write_string(buf, Some(transactional_id))?;
+                buf.write_i64::<NetworkEndian>(*producer_id)?;
+                buf.write_i16::<NetworkEndian>(*producer_epoch)?;
+                buf.write_u8(*commit as u8)
+            },
+        }
+    }
+
+    fn decode(header: &RequestHeader, cursor: &mut &[u8]) -> io::Result<Request> {
+        let api_key = ApiKey::from_i16(header.api_key).ok_or_else(|| Error::from(ResponseError::new(
+            ErrorCode::UnsupportedVersion, format!("unknown api key {}", header.api_key)
+        )))?;
+        let (min_version, max_version) = api_key.versions();
+        if header.api_version < min_version || header.api_version > max_version {
+            return Err(ResponseError::new(
+                ErrorCode::UnsupportedVersion,
+                format!(
+                    "{:?} version {} is not between {} and {}",
+                    api_key, header.api_version, min_version, max_version
+                )
+            ).into());
+        }
+        let request = match api_key {
+            ApiKey::Produce => Request::Produce {
+                topic: read_topic(cursor)?,
+                partition: cursor.read_u32::<NetworkEndian>()?,
+                // version 0 producers were always answered once written
+                acks: match header.api_version {
+                    0 => Acks::Leader,
+                    _ => Acks::from_i16(cursor.read_i16::<NetworkEndian>()?)?,
+                },
+                records: read_records(cursor)?,
+            },
+            ApiKey::Fetch => Request::Fetch {
+                topic: read_topic(cursor)?,
+                partition: cursor.read_u32::<NetworkEndian>()?,
+                offset: cursor.read_u64::<NetworkEndian>()?,
+                max_bytes: cursor.read_u32::<NetworkEndian>()?,
+                isolation_level: match header.api_version {
+                    0 => IsolationLevel::ReadUncommitted,
+                    _ => IsolationLevel::from_i8(cursor.read_i8()?)?,
+                },
+                max_wait_ms: match header.api_version {
+                    0 | 1 => 0,
+                    _ => cursor.read_u32::<NetworkEndian>()?,
+                },
+                min_bytes: match header.api_version {
+                    0 | 1 => 0,
+                    _ => cursor.read_u32::<NetworkEndian>()?,
+                },
+            },
+            ApiKey::ListOffsets => Request::ListOffsets {
+                topic: read_topic(cursor)?,
+                partition: cursor.read_u32::<NetworkEndian>()?,
+                timestamp: cursor.read_i64::<NetworkEndian>()?,
+            },
+            ApiKey::Metadata => Request::Metadata { topic: read_topic(cursor)? },
+            ApiKey::JoinGroup => Request::JoinGroup {
+                group_id: read_group_id(cursor)?,
+                member_id: read_string(cursor)?.unwrap_or_default(),
+                session_timeout_ms: cursor.read_u32::<NetworkEndian>()?,
+                topics: read_strings(cursor)?,
+                assignors: read_strings(cursor)?,
+            },
+            ApiKey::SyncGroup => Request::SyncGroup {
+                group_id: read_group_id(cursor)?,
+                generation_id: cursor.read_i32::<NetworkEndian>()?,
+                member_id: read_string(cursor)?.unwrap_or_default(),
+            },
+            ApiKey::Heartbeat => Request::Heartbeat {
+                group_id: read_group_id(cursor)?,
+                generation_id: cursor.read_i32::<NetworkEndian>()?,
+                member_id: read_string(cursor)?.unwrap_or_default(),
+            },
+            ApiKey::LeaveGroup => Request::LeaveGroup {
+                group_id: read_group_id(cursor)?,
+                member_id: read_string(cursor)?.unwrap_or_default(),
+            },
+            ApiKey::OffsetCommit => {
+                let group_id = read_group_id(cursor)?;
+                let generation_id = cursor.read_i32::<NetworkEndian>()?;
+                let member_id = read_string(cursor)?.unwrap_or_default();
+                let count = cursor.read_i32::<NetworkEndian>()?;
+                let mut offsets = vec![];
+                for _ in 0..count {
+                    offsets.push((
+                        read_topic(cursor)?,
+                        cursor.read_u32::<NetworkEndian>()?,
+                        cursor.read_u64::<NetworkEndian>()?,
+                    ));
+                }
+                Request::OffsetCommit { group_id, generation_id, member_id, offsets }
+            },
+            ApiKey::OffsetFetch => Request::OffsetFetch {
+                group_id: read_group_id(cursor)?,
+                partitions: read_partitions(cursor)?,
+            },
+            ApiKey::ApiVersions => Request::ApiVersions,
+            ApiKey::InitProducerId => Request::InitProducerId {
+                transactional_id: match header.api_version {
+                    0 => None,
+                    _ => read_string(cursor)?,
+                },
+            },
+            ApiKey::AddPartitionsToTxn => {
+                let transactional_id = read_transactional_id(cursor)?;
+                let producer_id = cursor.read_i64::<NetworkEndian>()?;
+                let producer_epoch = cursor.read_i16::<NetworkEndian>()?;
+                let partitions = read_partitions(cursor)?;
+                Request::AddPartitionsToTxn { transactional_id, producer_id, producer_epoch, partitions }
+            },
+            ApiKey::EndTxn => Request::EndTxn {
+                transactional_id: read_transactional_id(cursor)?,
+                producer_id: cursor.read_i64::<NetworkEndian>()?,
+                producer_epoch: cursor.read_i16::<NetworkEndian>()?,
+                commit: cursor.read_u8()? != 0,
+            },
+        };
+        expect_end(cursor)?;
+        Ok(request)
+    }
+}
+
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Response {
+    // `None` when the broker is too old to say, or wasn't asked to answer
+    Produce { base_offset: Option<Offset> },
+    // a version 0 response has the high watermark as last stable offset
+    Fetch {
+        high_watermark: Offset,
+        last_stable_offset: Offset,
+        log_start_offset: Offset,
+        aborted: Vec<AbortedTransaction>,
+        records: Vec<Record>,
+    },
+    ListOffsets { offset: Offset },
+    Metadata { partitions: u32 },
+    JoinGroup { generation_id: i32, member_id: String, leader_id: String, assignor: String, members: Vec<String> },
+    SyncGroup { partitions: Vec<(String, u32)> },
+    Heartbeat,
+    LeaveGroup,
+    OffsetCommit,
+    // `None` for partitions the group never committed
+    OffsetFetch { offsets: Vec<(String, u32, Option<Offset>)> },
+    ApiVersions { versions: Vec<ApiVersion> },
+    InitProducerId { producer_id: i64, producer_epoch: i16 },
+    AddPartitionsToTxn,
+    EndTxn,
+}
+
+impl Response {
+    // everything of a fetch response before its records
+    fn encode_fetch_position(&self, api_version: i16, buf: &mut Vec<u8>) -> io::Result<()> {
+        let (high_watermark, last_stable_offset, log_start_offset, aborted) = match self {
+            Response::Fetch { high_watermark, last_stable_offset, log_start_offset, aborted, .. } => {
+                (high_watermark, last_stable_offset, log_start_offset, aborted)
+            },
+            _ => return Err(Error::new(ErrorKind::InvalidInput, "not a fetch response")),
+        };
+        buf.write_u64::<NetworkEndian>(*high_watermark)?;
+        if api_version >= 1 {
+            buf.write_u64::<NetworkEndian>(*last_stable_offset)?;
+        }
+        buf.write_u64::<NetworkEndian>(*log_start_offset)?;
+        if api_version >= 1 {
+            buf.write_i32::<NetworkEndian>(aborted.len() as i32)?;
+            for transaction in aborted {
+                buf.write_i64::<NetworkEndian>(transaction.producer_id)?;
+                buf.write_u64::<NetworkEndian>(transaction.first_offset)?;
+                buf.write_u64::<NetworkEndian>(transaction.last_offset)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn encode(&self, api_version: i16, buf: &mut Vec<u8>) -> io::Result<()> {
+        match self {
+            Response::Produce { base_offset } => match (api_version, base_offset) {
+                (0, _) => Ok(()),
+                (_, Some(base_offset)) => buf.write_u64::<NetworkEndian>(*base_offset),
+                (_, None) => Err(Error::new(ErrorKind::InvalidInput, "produce response without offset")),
+            },
+            Response::Fetch { records, .. } => {
+                self.encode_fetch_position(api_version, buf)?;
+                write_records(buf, records)
+            },
+            Response::ListOffsets { offset } => buf.write_u64::<NetworkEndian>(*offset),
+            Response::Metadata { partitions } => buf.write_u32::<NetworkEndian>(*partitions),
+            Response::JoinGroup { generation_id, member_id, leader_id, assignor, members } => {
+                buf.write_i32::<NetworkEndian>(*generation_id)?;
+                write_string(buf, Some(member_id))?;
+                write_string(buf, Some(leader_id))?;
+                write_string(buf, Some(assignor))?;
+                write_strings(buf, members)
+            },
+            Response::SyncGroup { partitions } => write_partitions(buf, partitions),
+            Response::Heartbeat | Response::LeaveGroup | Response::OffsetCommit => Ok(()),
+            Response::OffsetFetch { offsets } => {
+                buf.write_i32::<NetworkEndian>(offsets.len() as i32)?;
+                for (topic, partition, offset) in offsets {
+                    write_string(buf, Some(topic))?;
+                    buf.write_u32::<NetworkEndian>(*partition)?;
+                    buf.write_i64::<NetworkEndian>(offset.map_or(-1, |offset| offset as i64))?;
+                }
+                Ok(())
+            },
+            Response::ApiVersions { versions } => {
+                buf.write_i32::<NetworkEndian>(versions.len() as i32)?;
+                for version in versions {
+                    buf.write_i16::<NetworkEndian>(version.api_key)?;
+                    buf.write_i16::<NetworkEndian>(version.min_version)?;
+                    buf.write_i16::<NetworkEndian>(version.max_version)?;
+                }
+                Ok(())
+            },
+            Response::InitProducerId { producer_id, producer_epoch } => {
+                buf.write_i64::<NetworkEndian>(*producer_id)?;
+                buf.write_i16::<NetworkEndian>(*producer_epoch)
+            },
+            Response::AddPartitionsToTxn | Response::EndTxn => Ok(()),
+        }
+    }
+
+    fn decode(api_key: ApiKey, api_version: i16, cursor: &mut &[u8]) -> io::Result<Response> {
+        let response = match api_key {
+            ApiKey::Produce => Response::Produce {
+                base_offset: match api_version {
+                    0 => None,
+                    _ => Some(cursor.read_u64::<NetworkEndian>()?),
+                },
+            },
+            ApiKey::Fetch => {
+                let high_watermark = cursor.read_u64::<NetworkEndian>()?;
+                let last_stable_offset = match api_version {
+                    0 => high_watermark,
+                    _ => cursor.read_u64::<NetworkEndian>()?,
+                };
+                let log_start_offset = cursor.read_u64::<NetworkEndian>()?;
+                let mut aborted = vec![];
+                if api_version >= 1 {
+                    let count = cursor.read_i32::<NetworkEndian>()?;
+                    for _ in 0..count {
+                        aborted.push(AbortedTransaction {
+                            producer_id: cursor.read_i64::<NetworkEndian>()?,
+                            first_offset: cursor.read_u64::<NetworkEndian>()?,
+                            last_offset: cursor.read_u64::<NetworkEndian>()?,
+                        });
+                    }
+                }
+                let records = read_records(cursor)?;
+                Response::Fetch { high_watermark, last_stable_offset, log_start_offset, aborted, records }
+            },
+            ApiKey::ListOffsets => Response::ListOffsets {
+                offset: cursor.read_u64::<NetworkEndian>()?,
+            },
+            ApiKey::Metadata => Response::Metadata {
+                partitions: cursor.read_u32::<NetworkEndian>()?,
+            },
+            ApiKey::JoinGroup => Response::JoinGroup {
+                generation_id: cursor.read_i32::<NetworkEndian>()?,
+                member_id: read_string(cursor)?.unwrap_or_default(),
+                leader_id: read_string(cursor)?.unwrap_or_default(),
+                assignor: read_string(cursor)?.unwrap_or_default(),
+                members: read_strings(cursor)?,
+            },
+            ApiKey::SyncGroup => Response::SyncGroup { partitions: read_partitions(cursor)? },
+            ApiKey::Heartbeat => Response::Heartbeat,
+            ApiKey::LeaveGroup => Response::LeaveGroup,
+            ApiKey::OffsetCommit => Response::OffsetCommit,
+            ApiKey::OffsetFetch => {
+                let count = cursor.read_i32::<NetworkEndian>()?;
+                let mut offsets = vec![];
+                for _ in 0..count {
+                    let topic = read_topic(cursor)?;
+                    let partition = cursor.read_u32::<NetworkEndian>()?;
+                    let offset = cursor.read_i64::<NetworkEndian>()?;
+                    offsets.push((topic, partition, if offset < 0 { None } else { Some(offset as Offset) }));
+                }
+                Response::OffsetFetch { offsets }
+            },
+            ApiKey::ApiVersions => {
+                let count = cursor.read_i32::<NetworkEndian>()?;
+                let mut versions = vec![];
+                for _ in 0..count {
+                    versions.push(ApiVersion {
+                        api_key: cursor.read_i16::<NetworkEndian>()?,
+                        min_version: cursor.read_i16::<NetworkEndian>()?,
+                        max_version: cursor.read_i16::<NetworkEndian>()?,
+                    });
+                }
+                Response::ApiVersions { versions }
+            },
+            ApiKey::InitProducerId => Response::InitProducerId {
+                producer_id: cursor.read_i64::<NetworkEndian>()?,
+                producer_epoch: cursor.read_i16::<NetworkEndian>()?,
+            },
+            ApiKey::AddPartitionsToTxn => Response::AddPartitionsToTxn,
+            ApiKey::EndTxn => Response::EndTxn,
+        };
+        expect_end(cursor)?;
+        Ok(response)
+    }
+}
+
+
+pub fn write_request<W: Write>(
+    writer: &mut W, api_version: i16, correlation_id: i32, client_id: &str, request: &Request
+) -> io::Result<()> {
+    let mut buf = vec![];
+    buf.write_i16::<NetworkEndian>(request.api_key() as i16)?;
+    buf.write_i16::<NetworkEndian>(api_version)?;
+    buf.write_i32::<NetworkEndian>(correlation_id)?;
+    write_string(&mut buf, Some(client_id))?;
+    request.encode(api_version, &mut buf)?;
+    write_frame(writer, &buf)
+}
+
+/// Read the next request, `None` once the client closed the connection.
+/// A request whose header made it but whose body can't be made sense of,
+/// or whose type or version isn't supported, is returned as an error next
+/// to the header, so it can still be answered.
+pub fn read_request<R: Read>(reader: &mut R) -> io::Result<Option<(RequestHeader, io::Result<Request>)>> {
+    let frame = match read_frame(reader)? {
+        Some(frame) => frame,
+        None => return Ok(None),
+    };
+    let mut cursor = &frame[..];
+    let header = RequestHeader {
+        api_key: cursor.read_i16::<NetworkEndian>()?,
+        api_version: cursor.read_i16::<NetworkEndian>()?,
+        correlation_id: cursor.read_i32::<NetworkEndian>()?,
+        client_id: read_string(&mut cursor)?,
+    };
+    let request = Request::decode(&header, &mut cursor);
+    Ok(Some((header, request)))
+}
+
+/// Answer a request made at `api_version`.
+pub fn write_response<W: Write>(
+    writer: &mut W, api_version: i16, correlation_id: i32, response: &Result<Response, ResponseError>
+) -> io::Result<()> {
+    let mut buf = vec![];
+    buf.write_i32::<NetworkEndian>(correlation_id)?;
+    match response {
+        Ok(response) => {
+            buf.write_i16::<NetworkEndian>(ErrorCode::None as i16)?;
+            write_string(&mut buf, None)?;
+            response.encode(api_version, &mut buf)?;
+        },
+        Err(error) => {
+            buf.write_i16::<NetworkEndian>(error.code as i16)?;
+            write_string(&mut buf, Some(&error.message))?;
+        },
+    }
+    write_frame(writer, &buf)
+}
+
+/// Answer a fetch with all of `response` but its records, which are
+/// `records_len` bytes of encoded records the caller writes right after,
+/// so they can come straight from the log.
+pub fn write_fetch_response_header<W: Write>(
+    writer: &mut W, api_version: i16, correlation_id: i32, response: &Response, records_len: u64
+) -> io::Result<()> {
+    let mut buf = vec![];
+    buf.write_i32::<NetworkEndian>(correlation_id)?;
+    buf.write_i16::<NetworkEndian>(ErrorCode::None as i16)?;
+    write_string(&mut buf, None)?;
+    response.encode_fetch_position(api_version, &mut buf)?;
+    if records_len > (MAX_FRAME_SIZE - buf.len() - 4) as u64 {
+        return Err(Error::new(ErrorKind::InvalidInput, "records too large for a frame"));
+    }
+    buf.write_i32::<NetworkEndian>(records_len as i32)?;
+    writer.write_i32::<NetworkEndian>((buf.len() as u64 + records_len) as i32)?;
+    writer.write_all(&buf)
+}
+
+/// Read the response to a request of type `api_key` made at `api_version`,
+/// returning its correlation id and either the response or what the broker
+/// objected to.
+pub fn read_response<R: Read>(
+    reader: &mut R, api_key: ApiKey, api_version: i16
+) -> io::Result<(i32, Result<Response, ResponseError>)> {
+    let frame = read_frame(reader)?.ok_or_else(|| Error::new(
+        ErrorKind::UnexpectedEof, "broker closed the connection"
+    ))?;
+    let mut cursor = &frame[..];
+    let correlation_id = cursor.read_i32::<NetworkEndian>()?;
+    let code = ErrorCode::from_i16(cursor.read_i16::<NetworkEndian>()?);
+    let message = read_string(&mut cursor)?;
+    if code != ErrorCode::None {
+        let message = message.unwrap_or_else(|| format!("{:?}", code));
+        return Ok((correlation_id, Err(ResponseError::new(code, message))));
+    }
+    Ok((correlation_id, Ok(Response::decode(api_key, api_version, &mut cursor)?)))
+}
+
+
+fn write_frame<W: Write>(writer: &mut W, buf: &[u8]) -> io::Result<()> {
+    writer.write_i32::<NetworkEndian>(buf.len() as i32)?;
+    writer.write_all(buf)
+}
+
+// `None` if the stream ends before the frame starts.
+fn read_frame<R: Read>(reader: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let size = match reader.read_i32::<NetworkEndian>() {
+        Ok(size) => size,
+        Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    if size < 0 || size as usize > MAX_FRAME_SIZE {
+        return Err(invalid(&format!("frame size {} out of bounds", size)));
+    }
+    let mut frame = vec![0; size as usize];
+    reader.read_exact(&mut frame)?;
+    Ok(Some(frame))
+}
+
+fn write_string(buf: &mut Vec<u8>, string: Option<&str>) -> io::Result<()> {
+    match string {
+        Some(string) if string.len() > i16::MAX as usize => Err(Error::new(
+            ErrorKind::InvalidInput, "string too long for the protocol"
+        )),
+        Some(string) => {
+            buf.write_i16::<NetworkEndian>(string.len() as i16)?;
+            buf.write_all(string.as_bytes())
+        },
+        None => buf.write_i16::<NetworkEndian>(-1),
+    }
+}
+
+fn read_string(cursor: &mut &[u8]) -> io::Result<Option<String>> {
+    let len = cursor.read_i16::<NetworkEndian>()?;
+    if len < 0 {
+        return Ok(None);
+    }
+    let mut bytes = vec![0; len as usize];
+    cursor.read_exact(&mut bytes)?;
+    String::from_utf8(bytes).map(Some).map_err(|e| invalid(&e.to_string()))
+}
+
+fn read_topic(cursor: &mut &[u8]) -> io::Result<String> {
+    read_string(cursor)?.ok_or_else(|| invalid("null topic"))
+}
+
+fn read_transactional_id(cursor: &mut &[u8]) -> io::Result<String> {
+    read_string(cursor)?.ok_or_else(|| invalid("null transactional id"))
+}
+
+fn read_group_id(cursor: &mut &[u8]) -> io::Result<String> {
+    read_string(cursor)?.ok_or_else(|| invalid("null group id"))
+}
+
+fn write_strings(buf: &mut Vec<u8>, strings: &[String]) -> io::Result<()> {
+    buf.write_i32::<NetworkEndian>(strings.len() as i32)?;
+    for string in strings {
+        write_string(buf, Some(string))?;
+    }
+    Ok(())
+}
+
+fn read_strings(cursor: &mut &[u8]) -> io::Result<Vec<String>> {
+    let count = cursor.read_i32::<NetworkEndian>()?;
+    let mut strings = vec![];
+    for _ in 0..count {
+        strings.push(read_string(cursor)?.ok_or_else(|| invalid("null string in array"))?);
+    }
+    Ok(strings)
+}
+
+// (topic, partition) pairs
+fn write_partitions(buf: &mut Vec<u8>, partitions: &[(String, u32)]) -> io::Result<()> {
+    buf.write_i32::<NetworkEndian>(partitions.len() as i32)?;
+    for (topic, partition) in partitions {
+        write_string(buf, Some(topic))?;
+        buf.write_u32::<NetworkEndian>(*partition)?;
+    }
+    Ok(())
+}
+
+fn read_partitions(cursor: &mut &[u8]) -> io::Result<Vec<(String, u32)>> {
+    let count = cursor.read_i32::<NetworkEndian>()?;
+    let mut partitions = vec![];
+    for _ in 0..count {
+        partitions.push((read_topic(cursor)?, cursor.read_u32::<NetworkEndian>()?));
+    }
+    Ok(partitions)
+}
+
+fn write_records(buf: &mut Vec<u8>, records: &[Record]) -> io::Result<()> {
+    let len: usize = records.iter().map(|r| r.encoded_len()).sum();
+    buf.write_i32::<NetworkEndian>(len as i32)?;
+    for record in records {
+        record.write_to(buf)?;
+    }
+    Ok(())
+}
+
+fn read_records(cursor: &mut &[u8]) -> io::Result<Vec<Record>> {
+    let len = cursor.read_i32::<NetworkEndian>()?;
+    if len < 0 || len as usize > cursor.len() {
+        return Err(invalid("record set longer than its frame"));
+    }
+    let (mut set, rest) = cursor.split_at(len as usize);
+    *cursor = rest;
+    let mut records = vec![];
+    while let Some(record) = Record::read_from(&mut set)? {
+        records.push(record);
+    }
+    Ok(records)
+}
+
+fn expect_end(cursor: &[u8]) -> io::Result<()> {
+    match cursor.is_empty() {
+        true => Ok(()),
+        false => Err(invalid("trailing bytes after the message")),
+    }
+}
+
+fn invalid(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, msg)
+}
+
+
+#[cfg(test)]
+extern crate speculate;
+
+#[cfg(test)]
+mod tests {
+    use speculate::speculate;
+    use std::io::{Cursor, ErrorKind};
+    use super::*;
+
+    speculate! {
+        test "request round trip" {
+            let request = Request::Produce {
+                topic: String::from("wombats"),
+                partition: 3,
+                acks: Acks::All,
+                records: vec![Record::new(b"WOMBIEST".to_vec()), Record::with_key(b"key".to_vec(), None)],
+            };
+            let mut buf = vec![];
+            write_request(&mut buf, 1, 7, "test", &request).unwrap();
+
+            let mut cursor = Cursor::new(buf);
+            let (header, decoded) = read_request(&mut cursor).unwrap().unwrap();
+            assert_eq!(header.api_key, ApiKey::Produce as i16);
+            assert_eq!(header.correlation_id, 7);
+            assert_eq!(header.client_id.as_deref(), Some("test"));
+            assert_eq!(decoded.unwrap(), request);
+            assert!(read_request(&mut cursor).unwrap().is_none(), "clean end of stream");
+        }
+
+        test "pipelined responses keep their correlation ids" {
+            let mut buf = vec![];
+            write_response(&mut buf, 0, 1, &Ok(Response::Metadata { partitions: 4 })).unwrap();
+            write_response(&mut buf, 0, 2, &Ok(Response::ListOffsets { offset: 42 })).unwrap();
+
+            let mut cursor = Cursor::new(buf);
+            let (id, response) = read_response(&mut cursor, ApiKey::Metadata, 0).unwrap();
+            assert_eq!((id, response), (1, Ok(Response::Metadata { partitions: 4 })));
+            let (id, response) = read_response(&mut cursor, ApiKey::ListOffsets, 0).unwrap();
+            assert_eq!((id, response), (2, Ok(Response::ListOffsets { offset: 42 })));
+        }
+
+        test "error responses carry a code and message" {
+            let error = ResponseError::new(ErrorCode::OffsetOutOfRange, "log starts at 5");
+            let mut buf = vec![];
+            write_response(&mut buf, 0, 9, &Err(error.clone())).unwrap();
+
+            let (id, response) = read_response(&mut Cursor::new(buf), ApiKey::Fetch, 0).unwrap();
+            assert_eq!(id, 9);
+            assert_eq!(response, Err(error.clone()));
+
+            let io_error = Error::from(error.clone());
+            assert_eq!(io_error.kind(), ErrorKind::InvalidInput);
+            assert_eq!(ErrorCode::for_error(&io_error), ErrorCode::OffsetOutOfRange);
+            assert_eq!(ResponseError::for_error(&io_error), error);
+        }
+
+        test "unknown api keys are reported with their header" {
+            let mut buf = vec![];
+            buf.write_i32::<NetworkEndian>(10).unwrap();
+            buf.write_i16::<NetworkEndian>(1234).unwrap();
+            buf.write_i16::<NetworkEndian>(0).unwrap();
+            buf.write_i32::<NetworkEndian>(5).unwrap();
+            buf.write_i16::<NetworkEndian>(-1).unwrap();
+
+            let (header, request) = read_request(&mut Cursor::new(buf)).unwrap().unwrap();
+            assert_eq!(header.correlation_id, 5);
+            assert_eq!(ErrorCode::for_error(&request.unwrap_err()), ErrorCode::UnsupportedVersion);
+        }
+
+        test "unsupported versions are rejected" {
+            let mut buf = vec![];
+            write_request(&mut buf, 99, 1, "test", &Request::Metadata { topic: String::from("t") }).unwrap();
+            let (header, request) = read_request(&mut Cursor::new(buf)).unwrap().unwrap();
+            assert_eq!(header.api_version, 99);
+            let err = request.unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::Unsupported);
+            assert_eq!(ErrorCode::for_error(&err), ErrorCode::UnsupportedVersion);
+        }
+
+        test "api versions round trip" {
+            let mut buf = vec![];
+            write_response(&mut buf, 0, 1, &Ok(Response::ApiVersions { versions: ApiVersion::supported() })).unwrap();
+            let (_, response) = read_response(&mut Cursor::new(buf), ApiKey::ApiVersions, 0).unwrap();
+            assert_eq!(response, Ok(Response::ApiVersions { versions: ApiVersion::supported() }));
+        }
+
+        test "produce version 0 has no acks or offset" {
+            let request = Request::Produce {
+                topic: String::from("wombats"),
+                partition: 0,
+                acks: Acks::Leader,
+                records: vec![Record::new(b"WOMBIEST".to_vec())],
+            };
+            let mut buf = vec![];
+            write_request(&mut buf, 0, 1, "test", &request).unwrap();
+            let (_, decoded) = read_request(&mut Cursor::new(buf)).unwrap().unwrap();
+            assert_eq!(decoded.unwrap(), request);
+
+            let mut buf = vec![];
+            write_response(&mut buf, 0, 1, &Ok(Response::Produce { base_offset: Some(5) })).unwrap();
+            let (_, response) = read_response(&mut Cursor::new(buf), ApiKey::Produce, 0).unwrap();
+            assert_eq!(response, Ok(Response::Produce { base_offset: None }));
+
+            let request = Request::Produce {
+                topic: String::from("wombats"), partition: 0, acks: Acks::All, records: vec![]
+            };
+            assert!(write_request(&mut vec![], 0, 1, "test", &request).is_err(), "acks need v1");
+        }
+
+        test "negotiate the newest common version" {
+            let broker = vec![
+                ApiVersion { api_key: ApiKey::Fetch as i16, min_version: 0, max_version: 7 },
+                ApiVersion { api_key: ApiKey::Produce as i16, min_version: 3, max_version: 5 },
+            ];
+            assert_eq!(negotiate_version(&broker, ApiKey::Fetch).unwrap(), ApiKey::Fetch.versions().1);
+            let err = negotiate_version(&broker, ApiKey::Produce).unwrap_err();
+            assert_eq!(ErrorCode::for_error(&err), ErrorCode::UnsupportedVersion);
+            assert!(negotiate_version(&broker, ApiKey::Metadata).is_err(), "unknown to the broker");
+        }
+
+        test "transaction requests round trip" {
+            let requests = vec![
+                Request::InitProducerId { transactional_id: Some(String::from("wombat-txn")) },
+                Request::AddPartitionsToTxn {
+                    transactional_id: String::from("wombat-txn"),
+                    producer_id: 4,
+                    producer_epoch: 2,
+                    partitions: vec![(String::from("wombats"), 0), (String::from("wombats"), 3)],
+                },
+                Request::EndTxn {
+                    transactional_id: String::from("wombat-txn"), producer_id: 4, producer_epoch: 2, commit: true
+                },
+            ];
+            for request in requests {
+                let mut buf = vec![];
+                write_request(&mut buf, request.api_key().versions().1, 1, "test", &request).unwrap();
+                let (_, decoded) = read_request(&mut Cursor::new(buf)).unwrap().unwrap();
+                assert_eq!(decoded.unwrap(), request);
+            }
+
+            let request = Request::InitProducerId { transactional_id: Some(String::from("wombat-txn")) };
+            assert!(write_request(&mut vec![], 0, 1, "test", &request).is_err(), "transactions need v1");
+        }
+
+        test "fetch version 2 waits for records" {
+            let request = Request::Fetch {
+                topic: String::from("wombats"),
+                partition: 1,
+                offset: 12,
+                max_bytes: 1024,
+                isolation_level: IsolationLevel::ReadCommitted,
+                max_wait_ms: 500,
+                min_bytes: 1,
+            };
+            let mut buf = vec![];
+            write_request(&mut buf, 2, 1, "test", &request).unwrap();
+            let (_, decoded) = read_request(&mut Cursor::new(buf)).unwrap().unwrap();
+            assert_eq!(decoded.unwrap(), request);
+
+            let mut buf = vec![];
+            write_request(&mut buf, 1, 1, "test", &request).unwrap();
+            let (_, decoded) = read_request(&mut Cursor::new(buf)).unwrap().unwrap();
+            match decoded.unwrap() {
+                Request::Fetch { max_wait_ms, min_bytes, .. } => assert_eq!((max_wait_ms, min_bytes), (0, 0)),
+                request => panic!("unexpected {:?}", request),
+            }
+        }
+
+        test "fetch version 1 has the last stable offset and aborted transactions" {
+            let response = Response::Fetch {
+                high_watermark: 10,
+                last_stable_offset: 7,
+                log_start_offset: 0,
+                aborted: vec![AbortedTransaction { producer_id: 4, first_offset: 2, last_offset: 5 }],
+                records: vec![Record::new(b"WOMBIEST".to_vec())],
+            };
+            let mut buf = vec![];
+            write_response(&mut buf, 1, 1, &Ok(response.clone())).unwrap();
+            let (_, decoded) = read_response(&mut Cursor::new(buf), ApiKey::Fetch, 1).unwrap();
+            assert_eq!(decoded, Ok(response.clone()));
+
+            let mut buf = vec![];
+            write_response(&mut buf, 0, 1, &Ok(response)).unwrap();
+            let (_, decoded) = read_response(&mut Cursor::new(buf), ApiKey::Fetch, 0).unwrap();
+            match decoded.unwrap() {
+                Response::Fetch { last_stable_offset, aborted, .. } => {
+                    assert_eq!(last_stable_offset, 10);
+                    assert!(aborted.is_empty());
+                },
+                response => panic!("unexpected {:?}", response),
+            }
+        }
+
+        test "a fetch header followed by raw records is a whole response" {
+            let records = vec![Record::new(b"WOMBIEST".to_vec()), Record::new(b"NUMBAT".to_vec())];
+            let response = Response::Fetch {
+                high_watermark: 10,
+                last_stable_offset: 10,
+                log_start_offset: 0,
+                aborted: vec![],
+                records: records.clone(),
+            };
+            let mut expected = vec![];
+            write_response(&mut expected, 1, 1, &Ok(response.clone())).unwrap();
+
+            let mut raw = vec![];
+            for record in &records {
+                record.write_to(&mut raw).unwrap();
+            }
+            let mut buf = vec![];
+            write_fetch_response_header(&mut buf, 1, 1, &response, raw.len() as u64).unwrap();
+            buf.extend(raw);
+            assert_eq!(buf, expected);
+        }
+
+        test "group requests round trip" {
+            let requests = vec![
+                Request::JoinGroup {
+                    group_id: String::from("wombats"),
+                    member_id: String::new(),
+                    session_timeout_ms: 10_000,
+                    topics: vec![String::from("burrows"), String::from("grass")],
+                    assignors: vec![String::from("range"), String::from("roundrobin")],
+                },
+                Request::SyncGroup { group_id: String::from("wombats"), generation_id: 3, member_id: String::from("wombats-1") },
+                Request::Heartbeat { group_id: String::from("wombats"), generation_id: 3, member_id: String::from("wombats-1") },
+                Request::LeaveGroup { group_id: String::from("wombats"), member_id: String::from("wombats-1") },
+            ];
+            for request in requests {
+                let mut buf = vec![];
+                write_request(&mut buf, 0, 1, "test", &request).unwrap();
+                let (_, decoded) = read_request(&mut Cursor::new(buf)).unwrap().unwrap();
+                assert_eq!(decoded.unwrap(), request);
+            }
+
+            let responses = vec![
+                (ApiKey::JoinGroup, Response::JoinGroup {
+                    generation_id: 3,
+                    member_id: String::from("wombats-1"),
+                    leader_id: String::from("wombats-0"),
+                    assignor: String::from("range"),
+                    members: vec![String::from("wombats-0"), String::from("wombats-1")],
+                }),
+                (ApiKey::SyncGroup, Response::SyncGroup { partitions: vec![(String::from("burrows"), 2)] }),
+                (ApiKey::Heartbeat, Response::Heartbeat),
+            ];
+            for (api_key, response) in responses {
+                let mut buf = vec![];
+                write_response(&mut buf, 0, 1, &Ok(response.clone())).unwrap();
+                let (_, decoded) = read_response(&mut Cursor::new(buf), api_key, 0).unwrap();
+                assert_eq!(decoded, Ok(response));
+            }
+        }
+
+        test "offset requests round trip" {
+            let requests = vec![
+                Request::OffsetCommit {
+                    group_id: String::from("wombats"),
+                    generation_id: 3,
+                    member_id: String::from("wombats-1"),
+                    offsets: vec![(String::from("burrows"), 0, 17), (String::from("burrows"), 2, 4)],
+                },
+                Request::OffsetFetch {
+                    group_id: String::from("wombats"),
+                    partitions: vec![(String::from("burrows"), 0), (String::from("burrows"), 1)],
+                },
+            ];
+            for request in requests {
+                let mut buf = vec![];
+                write_request(&mut buf, 0, 1, "test", &request).unwrap();
+                let (_, decoded) = read_request(&mut Cursor::new(buf)).unwrap().unwrap();
+                assert_eq!(decoded.unwrap(), request);
+            }
+
+            let response = Response::OffsetFetch {
+                offsets: vec![(String::from("burrows"), 0, Some(17)), (String::from("burrows"), 1, None)],
+            };
+            let mut buf = vec![];
+            write_response(&mut buf, 0, 1, &Ok(response.clone())).unwrap();
+            let (_, decoded) = read_response(&mut Cursor::new(buf), ApiKey::OffsetFetch, 0).unwrap();
+            assert_eq!(decoded, Ok(response));
+        }
+
+        test "oversized frames are rejected" {
+            let mut buf = vec![];
+            buf.write_i32::<NetworkEndian>(MAX_FRAME_SIZE as i32 + 1).unwrap();
+            let err = read_request(&mut Cursor::new(buf)).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidData);
+        }
+    }
+}
@@ -0,0 +1,462 @@
+// Records are framed on disk and in record sets on the wire as
+//
+//   offset: u64 | size: u32 | crc: u32 | magic: u8 | attributes: u8 |
+//   timestamp: i64 | producer_id: i64 | producer_epoch: i16 | sequence: i32 |
+//   key_length: i32 | key | value_length: i32 | value
+//
+// `size` counts the bytes following it and `crc` is the CRC32 of
+// everything after the crc field. A key or value length of -1 means null.
+// Records written before magic 2 have no producer fields and are read as
+// coming from no producer.
+//
+// Records a producer writes inside a transaction have the TRANSACTIONAL
+// attribute. A transaction ends with a CONTROL record from the same producer
+// in every partition it wrote to, whose key says if it committed or aborted.
+//
+// A batch of records can be compressed into the value of a wrapper record
+// whose COMPRESSION attribute bits name the codec. The records inside carry
+// offsets relative to the first of them, the wrapper the offset of the last,
+// so it is found like any of them and the next offset follows it. The
+// wrapper takes the producer, sequence and transactional attribute of the
+// first record and the newest timestamp.
+use std::io;
+use std::io::{Read, Write, Error, ErrorKind};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use byteorder::{ReadBytesExt, WriteBytesExt, NetworkEndian};
+use crc32fast::Hasher;
+
+use crate::compression::Compression;
+use crate::protocol;
+use crate::segment::Offset;
+
+
+pub const MAGIC: u8 = 2;
+pub const HEADER_SIZE: usize = 12; // offset + size
+pub const NO_PRODUCER_ID: i64 = -1;
+// attribute bits
+pub const TRANSACTIONAL: u8 = 0x10;
+pub const CONTROL: u8 = 0x20;
+pub const COMPRESSION: u8 = 0x07;
+// no record is bigger than the request that brought it
+pub const MAX_RECORD_SIZE: usize = protocol::MAX_FRAME_SIZE;
+const MIN_BODY_SIZE_V1: usize = 4 + 1 + 1 + 8 + 4 + 4;
+const MIN_BODY_SIZE: usize = MIN_BODY_SIZE_V1 + 8 + 2 + 4;
+
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Record {
+    pub offset: Offset,
+    pub attributes: u8,
+    pub timestamp: i64,
+    // set by idempotent producers, so the broker can drop resent records
+    pub producer_id: i64,
+    pub producer_epoch: i16,
+    pub sequence: i32,
+    pub key: Option<Vec<u8>>,
+    pub value: Option<Vec<u8>>,
+}
+
+impl Record {
+    pub fn new(value: Vec<u8>) -> Record {
+        Record {
+            offset: 0,
+            attributes: 0,
+            timestamp: now_ms(),
+            producer_id: NO_PRODUCER_ID,
+            producer_epoch: -1,
+            sequence: -1,
+            key: None,
+            value: Some(value),
+        }
+    }
+
+    pub fn with_key(key: Vec<u8>, value: Option<Vec<u8>>) -> Record {
+        Record {
+            offset: 0,
+            attributes: 0,
+            timestamp: now_ms(),
+            producer_id: NO_PRODUCER_ID,
+            producer_epoch: -1,
+            sequence: -1,
+            key: Some(key),
+            value,
+        }
+    }
+
+    /// The marker ending a producer's transaction in a partition.
+    pub fn control(producer_id: i64, producer_epoch: i16, commit: bool) -> Record {
+        Record {
+            offset: 0,
+            attributes: TRANSACTIONAL | CONTROL,
+            timestamp: now_ms(),
+            producer_id,
+            producer_epoch,
+            sequence: -1,
+            // a version then the type, as the Java client has it
+            key: Some(vec![0, 0, 0, commit as u8]),
+            value: None,
+        }
+    }
+
+    pub fn is_transactional(&self) -> bool {
+        self.attributes & TRANSACTIONAL != 0
+    }
+
+    pub fn is_control(&self) -> bool {
+        self.attributes & CONTROL != 0
+    }
+
+    pub fn is_compressed(&self) -> bool {
+        self.attributes & COMPRESSION != 0
+    }
+
+    /// The wrapper holding `records`, which must be in offset order,
+    /// compressed with `compression`.
+    pub fn compress(records: &[Record], compression: Compression) -> io::Result<Record> {
+        let first = match records.first() {
+            Some(first) => first.offset,
+            None => return Err(invalid("no records to compress")),
+        };
+        let mut framed = Vec::with_capacity(records.iter().map(Record::encoded_len).sum());
+        for record in records {
+            let start = framed.len();
+            record.write_to(&mut framed)?;
+            (&mut framed[start..start + 8]).write_u64::<NetworkEndian>(record.offset - first)?;
+        }
+        Ok(Record::wrap(records, compression, compression.compress(&framed)?))
+    }
+
+    /// The wrapper of `records` given them compressed already, as `value`.
+    pub fn wrap(records: &[Record], compression: Compression, value: Vec<u8>) -> Record {
+        let (first, last) = (&records[0], &records[records.len() - 1]);
+        Record {
+            offset: last.offset,
+            attributes: (first.attributes & TRANSACTIONAL) | compression.attributes(),
+            timestamp: records.iter().map(|record| record.timestamp).max().unwrap(),
+            producer_id: first.producer_id,
+            producer_epoch: first.producer_epoch,
+            sequence: first.sequence,
+            key: None,
+            value: Some(value),
+        }
+    }
+
+    /// The records a wrapper holds, at their offsets in the log, or the
+    /// record itself if it isn't compressed.
+    pub fn decompress(self) -> io::Result<Vec<Record>> {
+        if !self.is_compressed() {
+            return Ok(vec![self]);
+        }
+        let compression = Compression::from_attributes(self.attributes)?;
+        let framed = compression.decompress(self.value.as_deref().unwrap_or_default())?;
+        let mut cursor = &framed[..];
+        let mut records = vec![];
+        while let Some(record) = Record::read_from(&mut cursor)? {
+            if record.is_compressed() {
+                return Err(invalid("compressed record inside a compressed record"));
+            }
+            records.push(record);
+        }
+        let base_offset = match records.last() {
+            Some(last) if last.offset <= self.offset => self.offset - last.offset,
+            Some(_) => return Err(invalid("compressed records past their wrapper's offset")),
+            None => return Err(invalid("compressed record holding no records")),
+        };
+        for record in records.iter_mut() {
+            record.offset += base_offset;
+        }
+        Ok(records)
+    }
+
+    /// Whether a control record commits its transaction, `None` for others.
+    pub fn commits(&self) -> Option<bool> {
+        match (self.is_control(), self.key.as_deref()) {
+            (true, Some([0, 0, 0, commit])) => Some(*commit == 1),
+            _ => None,
+        }
+    }
+
+    fn body_size(&self) -> usize {
+        let field_len = |f: &Option<Vec<u8>>| f.as_ref().map_or(0, |b| b.len());
+        MIN_BODY_SIZE + field_len(&self.key) + field_len(&self.value)
+    }
+
+    /// Number of bytes the record occupies once framed.
+    pub fn encoded_len(&self) -> usize {
+        HEADER_SIZE + self.body_size()
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(self.encoded_len());
+        buf.write_u64::<NetworkEndian>(self.offset).unwrap();
+        buf.write_u32::<NetworkEndian>(self.body_size() as u32).unwrap();
+        buf.write_u32::<NetworkEndian>(0).unwrap(); // crc placeholder
+        buf.write_u8(MAGIC).unwrap();
+        buf.write_u8(self.attributes).unwrap();
+        buf.write_i64::<NetworkEndian>(self.timestamp).unwrap();
+        buf.write_i64::<NetworkEndian>(self.producer_id).unwrap();
+        buf.write_i16::<NetworkEndian>(self.producer_epoch).unwrap();
+        buf.write_i32::<NetworkEndian>(self.sequence).unwrap();
+        write_bytes(&mut buf, &self.key);
+        write_bytes(&mut buf, &self.value);
+
+        let crc = checksum(&buf[HEADER_SIZE + 4..]);
+        (&mut buf[HEADER_SIZE..HEADER_SIZE + 4]).write_u32::<NetworkEndian>(crc).unwrap();
+        buf
+    }
+
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<usize> {
+        let buf = self.encode();
+        writer.write_all(&buf)?;
+        Ok(buf.len())
+    }
+
+    /// Decode the part of a frame following the header, verifying its checksum.
+    pub fn decode(offset: Offset, body: &[u8]) -> io::Result<Record> {
+        if body.len() < MIN_BODY_SIZE_V1 {
+            return Err(invalid("record shorter than its fixed fields"));
+        }
+        let mut cursor = body;
+        let crc = cursor.read_u32::<NetworkEndian>()?;
+        if crc != checksum(cursor) {
+            return Err(invalid("record checksum mismatch"));
+        }
+        let magic = cursor.read_u8()?;
+        if magic != 1 && magic != MAGIC {
+            return Err(invalid("unknown record magic byte"));
+        }
+        let attributes = cursor.read_u8()?;
+        let timestamp = cursor.read_i64::<NetworkEndian>()?;
+        let (producer_id, producer_epoch, sequence) = match magic {
+            1 => (NO_PRODUCER_ID, -1, -1),
+            _ => (
+                cursor.read_i64::<NetworkEndian>()?,
+                cursor.read_i16::<NetworkEndian>()?,
+                cursor.read_i32::<NetworkEndian>()?,
+            ),
+        };
+        let key = read_bytes(&mut cursor)?;
+        let value = read_bytes(&mut cursor)?;
+        if !cursor.is_empty() {
+            return Err(invalid("trailing bytes after record value"));
+        }
+        Ok(Record { offset, attributes, timestamp, producer_id, producer_epoch, sequence, key, value })
+    }
+
+    /// Read one record, returning `None` on a clean end of stream.
+    pub fn read_from<R: Read>(reader: &mut R) -> io::Result<Option<Record>> {
+        let (offset, size) = match read_header(reader)? {
+            Some(header) => header,
+            None => return Ok(None),
+        };
+        if size == 0 {
+            return Err(invalid("empty record frame"));
+        }
+        // a corrupt size mustn't be trusted with an allocation
+        if size as usize > MAX_RECORD_SIZE {
+            return Err(invalid("record frame too large"));
+        }
+        let mut body = vec![0; size as usize];
+        reader.read_exact(&mut body)?;
+        Ok(Some(Record::decode(offset, &body)?))
+    }
+}
+
+
+/// Read the offset and size of the next frame, `None` if the stream is
+/// exhausted before any byte of it. A partial header is `UnexpectedEof`.
+pub fn read_header<R: Read>(reader: &mut R) -> io::Result<Option<(Offset, u32)>> {
+    let mut header = [0; HEADER_SIZE];
+    let mut filled = 0;
+    while filled < HEADER_SIZE {
+        match reader.read(&mut header[filled..]) {
+            Ok(0) if filled == 0 => return Ok(None),
+            Ok(0) => return Err(Error::new(ErrorKind::UnexpectedEof, "torn record header")),
+            Ok(n) => filled += n,
+            Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    let mut cursor = &header[..];
+    let offset = cursor.read_u64::<NetworkEndian>()?;
+    let size = cursor.read_u32::<NetworkEndian>()?;
+    Ok(Some((offset, size)))
+}
+
+/// The records of `records`, with those compressed taken out of their
+/// wrappers.
+pub fn decompress_all(records: Vec<Record>) -> io::Result<Vec<Record>> {
+    let mut decompressed = Vec::with_capacity(records.len());
+    for record in records {
+        match record.is_compressed() {
+            true => decompressed.extend(record.decompress()?),
+            false => decompressed.push(record),
+        }
+    }
+    Ok(decompressed)
+}
+
+pub fn now_ms() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as i64).unwrap_or(0)
+}
+
+fn checksum(bytes: &[u8]) -> u32 {
+    let mut hasher = Hasher::new();
+    hasher.update(bytes);
+    hasher.finalize()
+}
+
+fn write_bytes(buf: &mut Vec<u8>, field: &Option<Vec<u8>>) {
+    match field {
+        Some(bytes) => {
+            buf.write_i32::<NetworkEndian>(bytes.len() as i32).unwrap();
+            buf.extend_from_slice(bytes);
+        },
+        None => buf.write_i32::<NetworkEndian>(-1).unwrap(),
+    }
+}
+
+fn read_bytes(cursor: &mut &[u8]) -> io::Result<Option<Vec<u8>>> {
+    let len = cursor.read_i32::<NetworkEndian>()?;
+    if len < 0 {
+        return Ok(None);
+    }
+    let len = len as usize;
+    if cursor.len() < len {
+        return Err(invalid("field length exceeds record size"));
+    }
+    let (bytes, rest) = cursor.split_at(len);
+    *cursor = rest;
+    Ok(Some(bytes.to_vec()))
+}
+
+fn invalid(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, msg)
+}
+
+
+#[cfg(test)]
+extern crate speculate;
+
+#[cfg(test)]
+mod tests {
+    use speculate::speculate;
+    use std::io::{Cursor, ErrorKind};
+    use super::*;
+
+    speculate! {
+        test "round trip" {
+            let mut record = Record::with_key(b"key".to_vec(), Some(b"value\nwith newline".to_vec()));
+            record.offset = 42;
+            record.producer_id = 7;
+            record.producer_epoch = 1;
+            record.sequence = 99;
+            let bytes = record.encode();
+            assert_eq!(bytes.len(), record.encoded_len());
+
+            let decoded = Record::read_from(&mut Cursor::new(bytes)).unwrap().unwrap();
+            assert_eq!(decoded, record);
+        }
+
+        test "null value" {
+            let record = Record::with_key(b"key".to_vec(), None);
+            let decoded = Record::read_from(&mut Cursor::new(record.encode())).unwrap().unwrap();
+            assert_eq!(decoded.value, None);
+        }
+
+        test "empty stream" {
+            let result = Record::read_from(&mut Cursor::new(vec![])).unwrap();
+            assert!(result.is_none());
+        }
+
+        test "torn record" {
+            let mut bytes = Record::new(b"WOMBIESTWOODBINE".to_vec()).encode();
+            bytes.truncate(bytes.len() - 3);
+            let err = Record::read_from(&mut Cursor::new(bytes)).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+        }
+
+        test "corrupt record" {
+            let mut bytes = Record::new(b"WOMBIESTWOODBINE".to_vec()).encode();
+            let last = bytes.len() - 1;
+            bytes[last] ^= 0xff;
+            let err = Record::read_from(&mut Cursor::new(bytes)).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidData);
+        }
+
+        test "oversized frames are refused before reading them" {
+            let mut bytes = vec![];
+            bytes.write_u64::<NetworkEndian>(0).unwrap();
+            bytes.write_u32::<NetworkEndian>(u32::MAX).unwrap();
+            let err = Record::read_from(&mut Cursor::new(bytes)).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidData);
+        }
+
+        test "magic 1 records have no producer" {
+            let mut body = vec![];
+            body.write_u8(1).unwrap();
+            body.write_u8(0).unwrap();
+            body.write_i64::<NetworkEndian>(1_000).unwrap();
+            body.write_i32::<NetworkEndian>(-1).unwrap();
+            body.write_i32::<NetworkEndian>(8).unwrap();
+            body.extend_from_slice(b"WOMBIEST");
+            let mut framed = vec![];
+            framed.write_u32::<NetworkEndian>(checksum(&body)).unwrap();
+            framed.extend_from_slice(&body);
+
+            let record = Record::decode(3, &framed).unwrap();
+            assert_eq!(record.producer_id, NO_PRODUCER_ID);
+            assert_eq!(record.timestamp, 1_000);
+            assert_eq!(record.value, Some(b"WOMBIEST".to_vec()));
+        }
+
+        test "control records" {
+            let commit = Record::decode(0, &Record::control(3, 1, true).encode()[HEADER_SIZE..]).unwrap();
+            assert!(commit.is_control() && commit.is_transactional());
+            assert_eq!(commit.commits(), Some(true));
+            assert_eq!(Record::control(3, 1, false).commits(), Some(false));
+            assert_eq!(Record::new(b"WOMBIEST".to_vec()).commits(), None);
+        }
+
+        test "compressed records keep their offsets and producer" {
+            let records: Vec<Record> = (0..4).map(|i| Record {
+                offset: 10 + i,
+                attributes: TRANSACTIONAL,
+                producer_id: 3,
+                producer_epoch: 1,
+                sequence: i as i32,
+                timestamp: 1_000 - i as i64,
+                ..Record::new(b"WOMBIESTWOODBINE".to_vec())
+            }).collect();
+            let wrapper = Record::compress(&records, Compression::Gzip).unwrap();
+            assert_eq!(wrapper.offset, 13, "wrappers carry the last offset");
+            assert!(wrapper.is_compressed() && wrapper.is_transactional());
+            assert_eq!((wrapper.producer_id, wrapper.sequence, wrapper.timestamp), (3, 0, 1_000));
+
+            let framed = wrapper.encode();
+            let read = Record::read_from(&mut Cursor::new(framed)).unwrap().unwrap();
+            assert_eq!(read.clone().decompress().unwrap(), records);
+
+            // the broker moves wrappers to their offsets in the log
+            let moved = Record { offset: 103, ..read };
+            let offsets: Vec<Offset> = moved.decompress().unwrap().iter().map(|record| record.offset).collect();
+            assert_eq!(offsets, vec![100, 101, 102, 103]);
+        }
+
+        test "records that aren't compressed decompress to themselves" {
+            let record = Record::new(b"WOMBIEST".to_vec());
+            assert_eq!(record.clone().decompress().unwrap(), vec![record.clone()]);
+            let wrapper = Record::compress(&[Record { offset: 1, ..record.clone() }], Compression::Lz4).unwrap();
+            let all = decompress_all(vec![record.clone(), wrapper]).unwrap();
+            assert_eq!(all, vec![record.clone(), Record { offset: 1, ..record }]);
+        }
+
+        test "read header" {
+            let mut record = Record::new(b"WOMBIESTWOODBINE".to_vec());
+            record.offset = 7;
+            let header = read_header(&mut Cursor::new(record.encode())).unwrap();
+            assert_eq!(header, Some((7, record.encoded_len() as u32 - HEADER_SIZE as u32)));
+        }
+    }
+}
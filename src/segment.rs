@@ -8,11 +8,15 @@ use std::{io, fs, thread, env};
 use std::cmp::{Ord, Ordering, PartialOrd, PartialEq};
 use std::fs::{OpenOptions, File};
 use std::io::{Seek, SeekFrom, BufReader, BufWriter,Write, Read, BufRead, Error};
+use std::io::ErrorKind;
 use std::io::ErrorKind::ConnectionReset;
 use std::net::{TcpListener, TcpStream};
 use std::sync::{Arc, Mutex};
 use std::collections::BinaryHeap;
 
+use byteorder::{ReadBytesExt, WriteBytesExt, NetworkEndian};
+
+use crate::record::{self, Record, HEADER_SIZE};
 
 
 pub type Offset = u64;
@@ -21,59 +25,408 @@ pub type Offset = u64;
 pub enum Client { // enum for opening files
     Consumer,
     Producer,
+    // both, for the segment a partition appends to
+    Active,
 }
 
 
 
+// Every INDEX_INTERVAL_BYTES of log an entry mapping the offset of the
+// next record (relative to the base offset) to its byte position in the
+// .log is appended to the .index, both stored as big endian u32.
+// If the largest timestamp seen so far has grown since the last entry,
+// the .timeindex gets an entry pairing that timestamp (i64) with the same
+// relative offset, so every record before it is no newer than it.
+pub const INDEX_INTERVAL_BYTES: u64 = 4096;
+const INDEX_ENTRY_SIZE: usize = 8;
+const TIME_INDEX_ENTRY_SIZE: usize = 12;
+
+
+/// The tail a recovery cut off a segment, and why.
+#[derive(Debug)]
+pub struct Truncation {
+    pub position: u64,
+    pub bytes: u64,
+    pub reason: Error,
+}
+
+
 #[derive(Debug)]
 pub struct Segment {
     base_offset: Offset,
     filename: String,
+    index_filename: String,
+    time_index_filename: String,
     file: Option<File>,
+    index_file: Option<File>,
+    time_index_file: Option<File>,
+    index: Vec<(u32, u32)>,
+    time_index: Vec<(i64, u32)>,
+    max_timestamp: i64,
+    first_timestamp: Option<i64>,
+    next_offset: Offset,
+    position: u64,
+    bytes_since_index: u64,
 }
 
 impl Segment {
     pub fn new(partition_path: String, offset: u64) -> io::Result<Segment> {
         let filename = format!("{}/{:0>20}.log", partition_path, offset);
+        let index_filename = format!("{}/{:0>20}.index", partition_path, offset);
+        let time_index_filename = format!("{}/{:0>20}.timeindex", partition_path, offset);
         Ok(Segment {
             base_offset: offset,
-            filename: filename,
+            filename,
+            index_filename,
+            time_index_filename,
             file: None,
+            index_file: None,
+            time_index_file: None,
+            index: Vec::new(),
+            time_index: Vec::new(),
+            max_timestamp: i64::MIN,
+            first_timestamp: None,
+            next_offset: offset,
+            position: 0,
+            bytes_since_index: 0,
         })
     }
     pub fn open(&mut self, client: Client) ->  io::Result<()> {
         match client {
             Client::Consumer => {
-                drop(OpenOptions::new().create(true).write(true).open(&self.filename)?); // touch
+                // reading never creates a segment, one that went is gone
                 let reader = OpenOptions::new().read(true).open(&self.filename)?;
                 self.file = Some(reader);
-                return Ok(());
+                self.index = read_index(&self.index_filename)?;
+                self.time_index = read_time_index(&self.time_index_filename)?;
             },
-            Client::Producer => {
-                let writer = OpenOptions::new().create(true).append(true).open(&self.filename)?;
+            Client::Producer | Client::Active => {
+                // appends always go to the end, wherever reading seeked to
+                let writer = OpenOptions::new().create(true).append(true)
+                    .read(matches!(client, Client::Active))
+                    .open(&self.filename)?;
                 self.file = Some(writer);
-                return Ok(());
+                let index_writer = OpenOptions::new().create(true).append(true).open(&self.index_filename)?;
+                self.index_file = Some(index_writer);
+                let time_index_writer = OpenOptions::new().create(true).append(true).open(&self.time_index_filename)?;
+                self.time_index_file = Some(time_index_writer);
+                self.index = read_index(&self.index_filename)?;
+                self.time_index = read_time_index(&self.time_index_filename)?;
+                self.scan_tail()?;
             }
         }
+        Ok(())
     }
 
     pub fn close(&mut self) {
         let file = self.file.take();
-        drop(file)
+        drop(file);
+        let index_file = self.index_file.take();
+        drop(index_file);
+        let time_index_file = self.time_index_file.take();
+        drop(time_index_file)
+    }
+
+    /// A handle of its own on the log file, which reading doesn't move
+    /// the position of this one.
+    pub fn open_file(&self) -> io::Result<File> {
+        File::open(&self.filename)
     }
 
     pub fn len(&self) -> u64 {
         if let Ok(attr) = fs::metadata(&self.filename) {
             return attr.len();
         }
-        return 0
+        0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn base_offset(&self) -> Offset {
+        self.base_offset
+    }
+
+    /// Bytes appended so far, without asking the filesystem.
+    pub fn size(&self) -> u64 {
+        self.position
+    }
+
+    /// Timestamp of the segment's first record, which dates the segment.
+    pub fn first_timestamp(&self) -> Option<i64> {
+        self.first_timestamp
+    }
+
+    /// The offset the next appended record is expected to carry.
+    pub fn next_offset(&self) -> Offset {
+        self.next_offset
+    }
+
+    /// Frame and append a record, the whole frame in a single write
+    /// so a reader never observes a record spliced with another.
+    /// The record's offset must already be assigned.
+    pub fn append(&mut self, record: &Record) -> io::Result<usize> {
+        let buf = record.encode();
+        match &mut self.file {
+            Some(w) => w.write_all(&buf)?,
+            None => return Err(Error::new(ErrorKind::NotConnected, "segment is not open")),
+        }
+        self.track(record, buf.len() as u64)?;
+        Ok(buf.len())
+    }
+
+    /// Newest timestamp in the segment: the last time index entry,
+    /// refined by the records after the last offset index entry.
+    /// The segment must be open for consuming.
+    pub fn largest_timestamp(&mut self) -> io::Result<i64> {
+        let mut largest = self.time_index.last().map_or(i64::MIN, |&(ts, _)| ts);
+        let position = self.index.last().map_or(0, |&(_, p)| p as u64);
+        self.seek(SeekFrom::Start(position))?;
+        loop {
+            match self.read_record() {
+                Ok(Some(record)) => largest = largest.max(record.timestamp),
+                Ok(None) => break,
+                Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(largest)
+    }
+
+    /// Remove the log and its indexes from disk.
+    pub fn delete(mut self) -> io::Result<()> {
+        self.close();
+        remove_if_exists(&self.filename)?;
+        remove_if_exists(&self.index_filename)?;
+        remove_if_exists(&self.time_index_filename)
+    }
+
+    /// Move this segment's files over those of `other`. The indexes of
+    /// `other` go first: a segment without indexes is read by scanning,
+    /// while a log paired with the wrong indexes would be misread.
+    pub fn replace(mut self, other: &Segment) -> io::Result<()> {
+        self.close();
+        remove_if_exists(&other.index_filename)?;
+        remove_if_exists(&other.time_index_filename)?;
+        fs::rename(&self.filename, &other.filename)?;
+        fs::rename(&self.index_filename, &other.index_filename)?;
+        fs::rename(&self.time_index_filename, &other.time_index_filename)
+    }
+
+    /// Force the log and its indexes down to disk.
+    pub fn sync(&mut self) -> io::Result<()> {
+        for file in [&self.file, &self.index_file, &self.time_index_file].iter().filter_map(|f| f.as_ref()) {
+            file.sync_data()?;
+        }
+        Ok(())
+    }
+
+    /// Validate every record of the segment, truncate the log at the first
+    /// one that is torn, corrupt or out of order, and rebuild both indexes
+    /// from what is left. Leaves the segment closed.
+    pub fn recover(&mut self) -> io::Result<Option<Truncation>> {
+        self.close();
+        drop(OpenOptions::new().create(true).append(true).open(&self.filename)?); // touch
+        self.index_file = Some(File::create(&self.index_filename)?);
+        self.time_index_file = Some(File::create(&self.time_index_filename)?);
+        self.index.clear();
+        self.time_index.clear();
+        self.max_timestamp = i64::MIN;
+        self.first_timestamp = None;
+        self.next_offset = self.base_offset;
+        self.position = 0;
+        self.bytes_since_index = 0;
+
+        let mut reader = BufReader::new(File::open(&self.filename)?);
+        let reason = loop {
+            match Record::read_from(&mut reader) {
+                Ok(Some(record)) if record.offset < self.next_offset => {
+                    break Error::new(ErrorKind::InvalidData, "record offset out of order");
+                },
+                Ok(Some(record)) => self.track(&record, record.encoded_len() as u64)?,
+                Ok(None) => break Error::new(ErrorKind::UnexpectedEof, "end of segment"),
+                Err(e) => break e,
+            }
+        };
+        self.close();
+
+        let len = self.len();
+        if len == self.position {
+            return Ok(None);
+        }
+        OpenOptions::new().write(true).open(&self.filename)?.set_len(self.position)?;
+        Ok(Some(Truncation {
+            position: self.position,
+            bytes: len - self.position,
+            reason,
+        }))
+    }
+
+    /// Read the record at the current position, `None` at the end of the segment.
+    pub fn read_record(&mut self) -> io::Result<Option<Record>> {
+        Record::read_from(self)
+    }
+
+    /// Position the segment at the first record whose offset is at least
+    /// `offset`: binary search the index, then skip forward header by header.
+    pub fn seek_offset(&mut self, offset: Offset) -> io::Result<u64> {
+        let mut position = self.lookup(offset);
+        loop {
+            self.seek(SeekFrom::Start(position))?;
+            match record::read_header(self) {
+                Ok(Some((record_offset, size))) if record_offset < offset => {
+                    position += (HEADER_SIZE as u64) + size as u64;
+                },
+                _ => break,
+            }
+        }
+        self.seek(SeekFrom::Start(position))
+    }
+
+    /// Offset of the first record stamped at or after `timestamp`,
+    /// `None` if every record in the segment is older.
+    pub fn offset_for_timestamp(&mut self, timestamp: i64) -> io::Result<Option<Offset>> {
+        let i = self.time_index.partition_point(|&(entry, _)| entry < timestamp);
+        let start = match i {
+            0 => self.base_offset,
+            _ => self.base_offset + self.time_index[i - 1].1 as u64,
+        };
+        self.seek_offset(start)?;
+        loop {
+            match self.read_record() {
+                // a compressed batch carries its newest timestamp
+                Ok(Some(frame)) if frame.timestamp >= timestamp && frame.is_compressed() => {
+                    let first = frame.decompress()?.into_iter().find(|record| record.timestamp >= timestamp);
+                    return Ok(first.map(|record| record.offset));
+                },
+                Ok(Some(record)) if record.timestamp >= timestamp => return Ok(Some(record.offset)),
+                Ok(Some(_)) => continue,
+                Ok(None) => return Ok(None),
+                Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Byte position of the closest indexed record at or before `offset`.
+    fn lookup(&self, offset: Offset) -> u64 {
+        if offset <= self.base_offset {
+            return 0;
+        }
+        let relative = (offset - self.base_offset).min(u32::MAX as u64) as u32;
+        let i = self.index.partition_point(|&(entry, _)| entry <= relative);
+        if i == 0 {
+            return 0;
+        }
+        self.index[i - 1].1 as u64
+    }
+
+    // Bookkeeping for a record just written at the current position.
+    fn track(&mut self, record: &Record, len: u64) -> io::Result<()> {
+        if self.bytes_since_index >= INDEX_INTERVAL_BYTES {
+            self.append_index_entry(record.offset)?;
+        }
+        self.position += len;
+        self.bytes_since_index += len;
+        self.next_offset = record.offset + 1;
+        self.max_timestamp = self.max_timestamp.max(record.timestamp);
+        self.first_timestamp.get_or_insert(record.timestamp);
+        Ok(())
+    }
+
+    fn append_index_entry(&mut self, offset: Offset) -> io::Result<()> {
+        let entry = ((offset - self.base_offset) as u32, self.position as u32);
+        let mut buf = Vec::with_capacity(INDEX_ENTRY_SIZE);
+        buf.write_u32::<NetworkEndian>(entry.0)?;
+        buf.write_u32::<NetworkEndian>(entry.1)?;
+        if let Some(w) = &mut self.index_file {
+            w.write_all(&buf)?;
+        }
+        self.index.push(entry);
+        self.bytes_since_index = 0;
+
+        if self.time_index.last().is_none_or(|&(ts, _)| ts < self.max_timestamp) {
+            let time_entry = (self.max_timestamp, entry.0);
+            let mut buf = Vec::with_capacity(TIME_INDEX_ENTRY_SIZE);
+            buf.write_i64::<NetworkEndian>(time_entry.0)?;
+            buf.write_u32::<NetworkEndian>(time_entry.1)?;
+            if let Some(w) = &mut self.time_index_file {
+                w.write_all(&buf)?;
+            }
+            self.time_index.push(time_entry);
+        }
+        Ok(())
+    }
+
+    // Find where appending resumes: walk the records after the last
+    // index entry to learn the next offset and the current byte position.
+    fn scan_tail(&mut self) -> io::Result<()> {
+        let mut position = self.index.last().map_or(0, |&(_, p)| p as u64);
+        let mut next_offset = self.index.last().map_or(self.base_offset, |&(r, _)| self.base_offset + r as u64);
+        let mut max_timestamp = self.time_index.last().map_or(i64::MIN, |&(ts, _)| ts);
+        let mut reader = BufReader::new(File::open(&self.filename)?);
+        self.first_timestamp = Record::read_from(&mut reader)?.map(|record| record.timestamp);
+        reader.seek(SeekFrom::Start(position))?;
+        while let Some(record) = Record::read_from(&mut reader)? {
+            position += record.encoded_len() as u64;
+            next_offset = record.offset + 1;
+            max_timestamp = max_timestamp.max(record.timestamp);
+        }
+        let indexed = self.index.last().map_or(0, |&(_, p)| p as u64);
+        self.position = position;
+        self.bytes_since_index = position - indexed;
+        self.next_offset = next_offset;
+        self.max_timestamp = max_timestamp;
+        Ok(())
+    }
+}
+
+fn remove_if_exists(filename: &str) -> io::Result<()> {
+    match fs::remove_file(filename) {
+        Err(ref e) if e.kind() == ErrorKind::NotFound => Ok(()),
+        result => result,
     }
 }
 
+fn read_index(index_filename: &str) -> io::Result<Vec<(u32, u32)>> {
+    let bytes = match fs::read(index_filename) {
+        Ok(bytes) => bytes,
+        Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let mut cursor = &bytes[..bytes.len() - bytes.len() % INDEX_ENTRY_SIZE];
+    let mut index = Vec::with_capacity(cursor.len() / INDEX_ENTRY_SIZE);
+    while !cursor.is_empty() {
+        let relative_offset = cursor.read_u32::<NetworkEndian>()?;
+        let position = cursor.read_u32::<NetworkEndian>()?;
+        index.push((relative_offset, position));
+    }
+    Ok(index)
+}
+
+fn read_time_index(time_index_filename: &str) -> io::Result<Vec<(i64, u32)>> {
+    let bytes = match fs::read(time_index_filename) {
+        Ok(bytes) => bytes,
+        Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let mut cursor = &bytes[..bytes.len() - bytes.len() % TIME_INDEX_ENTRY_SIZE];
+    let mut time_index = Vec::with_capacity(cursor.len() / TIME_INDEX_ENTRY_SIZE);
+    while !cursor.is_empty() {
+        let timestamp = cursor.read_i64::<NetworkEndian>()?;
+        let relative_offset = cursor.read_u32::<NetworkEndian>()?;
+        time_index.push((timestamp, relative_offset));
+    }
+    Ok(time_index)
+}
+
 impl Write for Segment {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
         if let Some(w) = &mut self.file {
-            return w.write(buf)
+            let n = w.write(buf)?;
+            self.position += n as u64;
+            return Ok(n)
         }
         Ok(0)
     }
@@ -114,7 +467,7 @@ impl PartialEq for Segment {
 
 impl Ord for Segment {
     fn cmp(&self, other: &Self) -> Ordering {
-        return self.base_offset.cmp(&other.base_offset).reverse()
+        self.base_offset.cmp(&other.base_offset).reverse()
     }
 }
 
@@ -138,6 +491,8 @@ mod tests {
     use std::fs::{create_dir, remove_dir_all, remove_file};
     use std::io::{BufReader, BufWriter, Write, Read, BufRead, SeekFrom, Seek};
     use super::{Segment, Client};
+    use byteorder::{ReadBytesExt, WriteBytesExt, NetworkEndian};
+    use crate::record::{self, Record, HEADER_SIZE};
 
     speculate! {
         const DATA: &[u8] = b"WOMBIESTWOODBINE";
@@ -187,11 +542,20 @@ mod tests {
 
             test "consumer can't write" {
                 let mut segment = Segment::new(String::from(SEGMENTPATH), 0).expect("Cant open segment");
+                segment.open(Client::Producer).expect(" open write file");
+                segment.close();
                 segment.open(Client::Consumer).expect("open write file");
                 let result = segment.write(DATA);
                 assert!(result.is_err(), "consumer shouldn't write");
             }
 
+            test "consumer doesn't create a segment" {
+                let mut segment = Segment::new(String::from(SEGMENTPATH), 0).expect("Cant open segment");
+                let err = segment.open(Client::Consumer).unwrap_err();
+                assert_eq!(err.kind(), io::ErrorKind::NotFound);
+                assert_eq!(fs::read_dir(SEGMENTPATH).unwrap().count(), 0);
+            }
+
         }
         describe "producer" {
             test "producer writes" {
@@ -204,12 +568,189 @@ mod tests {
             test "producer can't read" {
                 let mut segment = Segment::new(String::from(SEGMENTPATH), 0).expect("Cant open segment");
                 segment.open(Client::Producer).expect("open write file");
-                segment.write(DATA).expect("write to file");
+                segment.write_all(DATA).expect("write to file");
 
                 let mut buf = [0; 8];
                 let result = segment.read(&mut buf);
                 assert!(result.is_err(), "producer shouldn't read");
             }
         }
+
+        describe "records" {
+            test "append and read records" {
+                let mut segment = Segment::new(String::from(SEGMENTPATH), 0).expect("Cant open segment");
+                segment.open(Client::Producer).expect("open write file");
+                let first = Record::new(b"binary\n\0payload".to_vec());
+                let second = Record::with_key(b"key".to_vec(), None);
+                let n = segment.append(&first).expect("append record");
+                assert_eq!(n, first.encoded_len());
+                segment.append(&second).expect("append record");
+                segment.close();
+
+                segment.open(Client::Consumer).expect("open read file");
+                assert_eq!(segment.read_record().unwrap(), Some(first));
+                assert_eq!(segment.read_record().unwrap(), Some(second));
+                assert_eq!(segment.read_record().unwrap(), None);
+            }
+
+            test "seek to offset through sparse index" {
+                let mut segment = Segment::new(String::from(SEGMENTPATH), 100).expect("Cant open segment");
+                segment.open(Client::Producer).expect("open write file");
+                for offset in 100..1100 {
+                    let mut record = Record::new(DATA.to_vec());
+                    record.offset = offset;
+                    segment.append(&record).expect("append record");
+                }
+                assert_eq!(segment.next_offset(), 1100);
+                segment.close();
+
+                segment.open(Client::Consumer).expect("open read file");
+                assert!(!segment.index.is_empty());
+                assert!(segment.index.len() < 1000 / 10, "index should be sparse");
+
+                segment.seek_offset(777).expect("seek to offset");
+                assert_eq!(segment.read_record().unwrap().unwrap().offset, 777);
+                segment.seek_offset(0).expect("seek before base offset");
+                assert_eq!(segment.read_record().unwrap().unwrap().offset, 100);
+                segment.seek_offset(5000).expect("seek past the end");
+                assert_eq!(segment.read_record().unwrap(), None);
+            }
+
+            test "offset for timestamp" {
+                let mut segment = Segment::new(String::from(SEGMENTPATH), 0).expect("Cant open segment");
+                segment.open(Client::Producer).expect("open write file");
+                for offset in 0..1000 {
+                    let mut record = Record::new(DATA.to_vec());
+                    record.offset = offset;
+                    record.timestamp = 1_000 + offset as i64 * 10;
+                    segment.append(&record).expect("append record");
+                }
+                segment.close();
+
+                segment.open(Client::Consumer).expect("open read file");
+                assert!(!segment.time_index.is_empty());
+                assert_eq!(segment.offset_for_timestamp(0).unwrap(), Some(0));
+                assert_eq!(segment.offset_for_timestamp(5_000).unwrap(), Some(400));
+                assert_eq!(segment.offset_for_timestamp(5_001).unwrap(), Some(401));
+                assert_eq!(segment.offset_for_timestamp(20_000).unwrap(), None);
+            }
+
+            test "largest timestamp" {
+                let mut segment = Segment::new(String::from(SEGMENTPATH), 0).expect("Cant open segment");
+                segment.open(Client::Producer).expect("open write file");
+                for offset in 0..300 {
+                    let mut record = Record::new(DATA.to_vec());
+                    record.offset = offset;
+                    record.timestamp = if offset == 150 { 9_000 } else { offset as i64 };
+                    segment.append(&record).expect("append record");
+                }
+                segment.close();
+
+                segment.open(Client::Consumer).expect("open read file");
+                assert_eq!(segment.largest_timestamp().unwrap(), 9_000);
+            }
+
+            test "delete removes the log and indexes" {
+                let mut segment = Segment::new(String::from(SEGMENTPATH), 0).expect("Cant open segment");
+                segment.open(Client::Producer).expect("open write file");
+                segment.append(&Record::new(DATA.to_vec())).expect("append record");
+                segment.delete().expect("delete segment");
+                assert_eq!(fs::read_dir(SEGMENTPATH).unwrap().count(), 0);
+            }
+
+            test "reopening resumes offsets" {
+                let mut segment = Segment::new(String::from(SEGMENTPATH), 10).expect("Cant open segment");
+                segment.open(Client::Producer).expect("open write file");
+                for offset in 10..500 {
+                    let mut record = Record::new(DATA.to_vec());
+                    record.offset = offset;
+                    segment.append(&record).expect("append record");
+                }
+                let position = segment.position;
+                segment.close();
+
+                let mut segment = Segment::new(String::from(SEGMENTPATH), 10).expect("Cant open segment");
+                segment.open(Client::Producer).expect("open write file");
+                assert_eq!(segment.next_offset(), 500);
+                assert_eq!(segment.position, position);
+            }
+
+            test "recover truncates a torn tail" {
+                let mut segment = Segment::new(String::from(SEGMENTPATH), 0).expect("Cant open segment");
+                segment.open(Client::Producer).expect("open write file");
+                for offset in 0..300 {
+                    let mut record = Record::new(DATA.to_vec());
+                    record.offset = offset;
+                    segment.append(&record).expect("append record");
+                }
+                let position = segment.position;
+                let index = segment.index.clone();
+                let bytes = Record::new(DATA.to_vec()).encode();
+                segment.write_all(&bytes[..bytes.len() / 2]).expect("write half a record");
+                segment.close();
+
+                let truncation = segment.recover().expect("recover").expect("a truncation");
+                assert_eq!(truncation.position, position);
+                assert_eq!(truncation.bytes, bytes.len() as u64 / 2);
+                assert_eq!(segment.len(), position);
+                assert_eq!(segment.index, index);
+
+                segment.open(Client::Producer).expect("open write file");
+                assert_eq!(segment.next_offset(), 300);
+                assert!(segment.recover().expect("recover").is_none());
+            }
+
+            test "recover drops corrupt records" {
+                let mut segment = Segment::new(String::from(SEGMENTPATH), 0).expect("Cant open segment");
+                segment.open(Client::Producer).expect("open write file");
+                let mut bytes = vec![];
+                for offset in 0..3 {
+                    let mut record = Record::new(DATA.to_vec());
+                    record.offset = offset;
+                    bytes.extend(record.encode());
+                }
+                let last = bytes.len() - 1;
+                bytes[last] ^= 0xff;
+                segment.write_all(&bytes).expect("write records");
+                segment.close();
+
+                let truncation = segment.recover().expect("recover").expect("a truncation");
+                assert_eq!(truncation.reason.kind(), io::ErrorKind::InvalidData);
+                segment.open(Client::Consumer).expect("open read file");
+                assert_eq!(segment.read_record().unwrap().unwrap().offset, 0);
+                assert_eq!(segment.read_record().unwrap().unwrap().offset, 1);
+                assert_eq!(segment.read_record().unwrap(), None);
+            }
+
+            test "recover rebuilds a stale index" {
+                let mut segment = Segment::new(String::from(SEGMENTPATH), 0).expect("Cant open segment");
+                segment.open(Client::Producer).expect("open write file");
+                for offset in 0..300 {
+                    let mut record = Record::new(DATA.to_vec());
+                    record.offset = offset;
+                    segment.append(&record).expect("append record");
+                }
+                let index = segment.index.clone();
+                segment.close();
+                fs::write(&segment.index_filename, b"garbage!").expect("clobber index");
+
+                assert!(segment.recover().expect("recover").is_none());
+                segment.open(Client::Consumer).expect("open read file");
+                assert_eq!(segment.index, index);
+                segment.seek_offset(250).expect("seek to offset");
+                assert_eq!(segment.read_record().unwrap().unwrap().offset, 250);
+            }
+
+            test "torn tail is an error" {
+                let mut segment = Segment::new(String::from(SEGMENTPATH), 0).expect("Cant open segment");
+                segment.open(Client::Producer).expect("open write file");
+                let bytes = Record::new(DATA.to_vec()).encode();
+                segment.write_all(&bytes[..bytes.len() / 2]).expect("write half a record");
+                segment.close();
+
+                segment.open(Client::Consumer).expect("open read file");
+                assert!(segment.read_record().is_err());
+            }
+        }
     }
 }
@@ -0,0 +1,133 @@
+// Records are stored exactly as they go on the wire, so a fetch can answer
+// with runs of bytes straight out of the segment files. On Linux the kernel
+// copies them to the socket with sendfile(2), never passing them through the
+// broker's memory. Elsewhere, or when the bytes must go through something
+// that isn't a plain socket, they are read and written the ordinary way.
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::net::TcpStream;
+
+
+/// `len` bytes of whole records starting at `position` of a segment file.
+/// The file stays readable even if the segment is deleted or compacted away
+/// before the bytes are sent.
+#[derive(Debug)]
+pub struct FileSlice {
+    pub file: File,
+    pub position: u64,
+    pub len: u64,
+}
+
+/// Total bytes of `slices`.
+pub fn len(slices: &[FileSlice]) -> u64 {
+    slices.iter().map(|slice| slice.len).sum()
+}
+
+/// Write `slices` to `writer` through a buffer in user space.
+pub fn copy_to<W: Write>(slices: &[FileSlice], writer: &mut W) -> io::Result<()> {
+    for slice in slices {
+        let mut file = &slice.file;
+        file.seek(SeekFrom::Start(slice.position))?;
+        let copied = io::copy(&mut file.take(slice.len), writer)?;
+        if copied < slice.len {
+            return Err(truncated(slice, copied));
+        }
+    }
+    Ok(())
+}
+
+/// Send `slices` to `socket`, without copying them through user space
+/// where the platform allows. Anything buffered for the socket must be
+/// flushed first.
+#[cfg(target_os = "linux")]
+pub fn send_to(slices: &[FileSlice], socket: &TcpStream) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    for slice in slices {
+        let mut position = slice.position as libc::off_t;
+        let mut sent = 0;
+        while sent < slice.len {
+            let count = (slice.len - sent) as usize;
+            let n = unsafe { libc::sendfile(socket.as_raw_fd(), slice.file.as_raw_fd(), &mut position, count) };
+            match n {
+                0 => return Err(truncated(slice, sent)),
+                n if n > 0 => sent += n as u64,
+                _ => {
+                    let e = io::Error::last_os_error();
+                    if e.kind() != io::ErrorKind::Interrupted {
+                        return Err(e);
+                    }
+                },
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn send_to(slices: &[FileSlice], mut socket: &TcpStream) -> io::Result<()> {
+    copy_to(slices, &mut socket)
+}
+
+fn truncated(slice: &FileSlice, copied: u64) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::UnexpectedEof,
+        format!("segment ended {} bytes into a slice of {} at {}", copied, slice.len, slice.position)
+    )
+}
+
+
+#[cfg(test)]
+extern crate speculate;
+
+#[cfg(test)]
+mod tests {
+    use speculate::speculate;
+    use std::fs::{self, remove_dir_all};
+    use std::net::TcpListener;
+    use std::thread;
+    use super::*;
+
+    speculate! {
+        before {
+            fs::create_dir_all("tmp").unwrap();
+            fs::write("tmp/slices", b"0123456789").unwrap();
+            let slices = vec![
+                FileSlice { file: File::open("tmp/slices").unwrap(), position: 2, len: 3 },
+                FileSlice { file: File::open("tmp/slices").unwrap(), position: 7, len: 3 },
+            ];
+        }
+
+        after {
+            let _ = remove_dir_all("tmp/");
+        }
+
+        test "copying writes the ranges in order" {
+            let mut buf = vec![];
+            copy_to(&slices, &mut buf).unwrap();
+            assert_eq!(buf, b"234789");
+            assert_eq!(len(&slices), 6);
+        }
+
+        test "sending delivers the same bytes" {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let address = listener.local_addr().unwrap();
+            let receiver = thread::spawn(move || {
+                let mut received = vec![];
+                listener.accept().unwrap().0.read_to_end(&mut received).unwrap();
+                received
+            });
+            let socket = TcpStream::connect(address).unwrap();
+            send_to(&slices, &socket).unwrap();
+            drop(socket);
+            assert_eq!(receiver.join().unwrap(), b"234789");
+        }
+
+        test "a slice past the end of the file is an error" {
+            let mut slices = slices;
+            slices[1].len = 5;
+            let e = copy_to(&slices, &mut vec![]).unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+        }
+    }
+}